@@ -0,0 +1 @@
+DROP TABLE sync_outbox;
\ No newline at end of file
@@ -0,0 +1,11 @@
+-- Role sync requests that failed to reach the server and are waiting to be retried
+CREATE TABLE sync_outbox (
+    seq INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    account_id INTEGER UNIQUE NOT NULL, -- gd account id, one entry per account
+    keep TEXT NOT NULL, -- json array of globed role ids
+    remove TEXT NOT NULL, -- json array of globed role ids
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt INTEGER NOT NULL, -- unix timestamp
+    version INTEGER NOT NULL DEFAULT 0, -- bumped every time a newer request is merged in
+    last_error TEXT
+);
@@ -0,0 +1 @@
+DROP TABLE bot_state;
\ No newline at end of file
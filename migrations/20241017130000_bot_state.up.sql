@@ -0,0 +1,5 @@
+-- Small key-value store for state that has to survive restarts
+CREATE TABLE bot_state (
+    key TEXT NOT NULL PRIMARY KEY,
+    value TEXT NOT NULL
+);
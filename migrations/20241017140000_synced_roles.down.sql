@@ -0,0 +1 @@
+DROP TABLE synced_roles;
\ No newline at end of file
@@ -0,0 +1,6 @@
+-- Globed roles that the server last confirmed for each linked user
+CREATE TABLE synced_roles (
+    gd_account_id INTEGER NOT NULL PRIMARY KEY REFERENCES linked_users(gd_account_id) ON DELETE CASCADE,
+    roles TEXT NOT NULL, -- sorted json array of globed role ids
+    synced_at INTEGER NOT NULL -- unix timestamp
+);
@@ -0,0 +1 @@
+ALTER TABLE roles DROP COLUMN direction;
\ No newline at end of file
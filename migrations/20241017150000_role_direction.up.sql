@@ -0,0 +1,2 @@
+-- Which way a role mapping syncs, 'to_globed' (Discord role grants the Globed role) or 'to_discord' (Globed role grants the Discord role)
+ALTER TABLE roles ADD COLUMN direction TEXT NOT NULL DEFAULT 'to_globed';
@@ -0,0 +1 @@
+DROP TABLE missing_members;
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Linked users that weren't found in the guild during the last full sync, waiting for an admin to review them
+CREATE TABLE missing_members (
+    id INTEGER NOT NULL PRIMARY KEY REFERENCES linked_users(id) ON DELETE CASCADE, -- discord id
+    detected_at INTEGER NOT NULL -- unix timestamp
+);
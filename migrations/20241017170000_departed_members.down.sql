@@ -0,0 +1 @@
+DROP TABLE departed_members;
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Linked users that left the guild recently, their link is kept until the grace period runs out
+CREATE TABLE departed_members (
+    id INTEGER NOT NULL PRIMARY KEY REFERENCES linked_users(id) ON DELETE CASCADE, -- discord id
+    departed_at INTEGER NOT NULL -- unix timestamp
+);
@@ -0,0 +1 @@
+ALTER TABLE roles DROP COLUMN discord_name;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Last known name of the discord role, kept up to date from role update events
+ALTER TABLE roles ADD COLUMN discord_name TEXT;
@@ -0,0 +1 @@
+DROP TABLE role_rules;
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Globed roles granted by a boolean expression over discord roles, e.g. `<@&1> OR <@&2>`
+CREATE TABLE role_rules (
+    id TEXT NOT NULL PRIMARY KEY, -- globed role id
+    expression TEXT NOT NULL -- canonical form of the rule
+);
@@ -0,0 +1,2 @@
+DROP TABLE role_group_members;
+DROP TABLE role_groups;
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- Named groups of globed roles where a user only gets the highest-tier role they qualify for
+CREATE TABLE role_groups (
+    name TEXT NOT NULL PRIMARY KEY
+);
+
+CREATE TABLE role_group_members (
+    globed_role_id TEXT NOT NULL PRIMARY KEY, -- a globed role can only be in one group
+    group_name TEXT NOT NULL REFERENCES role_groups(name) ON DELETE CASCADE,
+    position INTEGER NOT NULL -- 1 is the highest tier
+);
@@ -0,0 +1 @@
+DROP TABLE virtual_roles;
\ No newline at end of file
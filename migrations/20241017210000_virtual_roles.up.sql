@@ -0,0 +1,6 @@
+-- Globed roles granted by member state that isn't a regular discord role, like boosting the server
+CREATE TABLE virtual_roles (
+    id TEXT NOT NULL PRIMARY KEY, -- globed role id
+    source TEXT NOT NULL, -- 'booster', 'pending' or 'timed_out'
+    min_boost_days INTEGER NOT NULL DEFAULT 0 -- only for 'booster', how long the member has to be boosting
+);
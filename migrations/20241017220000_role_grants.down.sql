@@ -0,0 +1 @@
+DROP TABLE role_grants;
\ No newline at end of file
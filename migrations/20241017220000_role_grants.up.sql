@@ -0,0 +1,8 @@
+-- Globed roles granted to a linked user for a limited time, without a discord role
+CREATE TABLE role_grants (
+    user_id INTEGER NOT NULL REFERENCES linked_users(id) ON DELETE CASCADE, -- discord id
+    globed_role_id TEXT NOT NULL,
+    expires_at INTEGER NOT NULL, -- unix timestamp
+    granted_by INTEGER NOT NULL, -- discord id of the staff member
+    PRIMARY KEY (user_id, globed_role_id)
+);
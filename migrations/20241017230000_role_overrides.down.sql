@@ -0,0 +1 @@
+DROP TABLE role_overrides;
\ No newline at end of file
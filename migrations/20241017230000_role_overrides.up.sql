@@ -0,0 +1,11 @@
+-- Per-user overrides that force a globed role to be kept or removed, no matter the user's discord roles
+CREATE TABLE role_overrides (
+    user_id INTEGER NOT NULL REFERENCES linked_users(id) ON DELETE CASCADE, -- discord id
+    globed_role_id TEXT NOT NULL,
+    keep BOOLEAN NOT NULL, -- true forces the role to be kept, false forces it to be removed
+    reason TEXT NOT NULL,
+    created_by INTEGER NOT NULL, -- discord id of the staff member
+    created_at INTEGER NOT NULL, -- unix timestamp
+    expires_at INTEGER, -- unix timestamp, null if the override is permanent
+    PRIMARY KEY (user_id, globed_role_id)
+);
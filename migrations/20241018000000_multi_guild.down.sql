@@ -0,0 +1,17 @@
+DROP TABLE linked_user_guilds;
+
+-- only one mapping per globed role can be kept
+CREATE TABLE roles_old (
+    id TEXT NOT NULL PRIMARY KEY,
+    discord_id INTEGER NOT NULL,
+    direction TEXT NOT NULL DEFAULT 'to_globed',
+    discord_name TEXT
+);
+
+INSERT OR IGNORE INTO roles_old (id, discord_id, direction, discord_name)
+SELECT id, discord_id, direction, discord_name FROM roles ORDER BY guild_id;
+
+DROP TABLE roles;
+ALTER TABLE roles_old RENAME TO roles;
+
+DROP TABLE guilds;
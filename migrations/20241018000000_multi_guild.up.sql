@@ -0,0 +1,34 @@
+-- Guilds the bot syncs roles from, the guild from BOT_SERVER_ID is registered by the bot on startup
+CREATE TABLE guilds (
+    id INTEGER NOT NULL PRIMARY KEY, -- discord id
+    name TEXT,
+    added_at INTEGER NOT NULL -- unix timestamp
+);
+
+-- Role mappings are scoped to the guild of their discord role, so every guild can map the same globed role.
+-- Existing mappings get guild 0, which the bot replaces with the guild from BOT_SERVER_ID on startup
+CREATE TABLE roles_new (
+    id TEXT NOT NULL,
+    guild_id INTEGER NOT NULL,
+    discord_id INTEGER NOT NULL,
+    direction TEXT NOT NULL DEFAULT 'to_globed',
+    discord_name TEXT,
+    PRIMARY KEY (guild_id, id)
+);
+
+INSERT INTO roles_new (id, guild_id, discord_id, direction, discord_name)
+SELECT id, 0, discord_id, direction, discord_name FROM roles;
+
+DROP TABLE roles;
+ALTER TABLE roles_new RENAME TO roles;
+
+-- Guilds each linked user is a member of, roles from all of them are combined when syncing
+CREATE TABLE linked_user_guilds (
+    user_id INTEGER NOT NULL REFERENCES linked_users(id) ON DELETE CASCADE, -- discord id
+    guild_id INTEGER NOT NULL,
+    PRIMARY KEY (user_id, guild_id)
+);
+
+-- same as above, guild 0 gets replaced on startup. members that left aren't in any guild
+INSERT INTO linked_user_guilds (user_id, guild_id)
+SELECT id, 0 FROM linked_users WHERE id NOT IN (SELECT id FROM departed_members);
@@ -0,0 +1,15 @@
+-- mappings of other servers can't be kept
+CREATE TABLE roles_old (
+    id TEXT NOT NULL,
+    guild_id INTEGER NOT NULL,
+    discord_id INTEGER NOT NULL,
+    direction TEXT NOT NULL DEFAULT 'to_globed',
+    discord_name TEXT,
+    PRIMARY KEY (guild_id, id)
+);
+
+INSERT INTO roles_old (id, guild_id, discord_id, direction, discord_name)
+SELECT id, guild_id, discord_id, direction, discord_name FROM roles WHERE target = 'main';
+
+DROP TABLE roles;
+ALTER TABLE roles_old RENAME TO roles;
@@ -0,0 +1,17 @@
+-- Role mappings are scoped to a Globed server, 'main' is the server from BOT_BASE_URL.
+-- The same globed role can be mapped for every server, so the target is a part of the key
+CREATE TABLE roles_new (
+    id TEXT NOT NULL,
+    guild_id INTEGER NOT NULL,
+    target TEXT NOT NULL DEFAULT 'main',
+    discord_id INTEGER NOT NULL,
+    direction TEXT NOT NULL DEFAULT 'to_globed',
+    discord_name TEXT,
+    PRIMARY KEY (guild_id, target, id)
+);
+
+INSERT INTO roles_new (id, guild_id, discord_id, direction, discord_name)
+SELECT id, guild_id, discord_id, direction, discord_name FROM roles;
+
+DROP TABLE roles;
+ALTER TABLE roles_new RENAME TO roles;
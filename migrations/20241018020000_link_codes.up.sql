@@ -0,0 +1,8 @@
+-- Link codes issued by the bot with `/link start`, the player enters the code in-game and the Globed server confirms it
+CREATE TABLE pending_links (
+    code TEXT NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL UNIQUE, -- discord id, every user has at most one code
+    guild_id INTEGER NOT NULL, -- guild the code was requested in
+    created_at INTEGER NOT NULL, -- unix timestamp
+    expires_at INTEGER NOT NULL -- unix timestamp
+);
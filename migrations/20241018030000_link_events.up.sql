@@ -0,0 +1,12 @@
+-- Append-only history of links, rows are never updated or deleted
+CREATE TABLE link_events (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL, -- link, unlink, admin_link, admin_unlink, leave_unlink or transfer
+    actor_id INTEGER, -- discord id of who did it, null if the bot did it on its own
+    user_id INTEGER NOT NULL, -- discord id
+    gd_account_id INTEGER NOT NULL,
+    created_at INTEGER NOT NULL -- unix timestamp
+);
+
+CREATE INDEX link_events_user ON link_events (user_id);
+CREATE INDEX link_events_account ON link_events (gd_account_id);
@@ -0,0 +1 @@
+ALTER TABLE linked_users DROP COLUMN gd_username;
@@ -0,0 +1,2 @@
+-- Last known GD username of the account, refreshed from the Globed server
+ALTER TABLE linked_users ADD COLUMN gd_username TEXT;
@@ -0,0 +1,10 @@
+-- Failed link attempts, counted per discord user and per GD username to stop link codes from being guessed
+CREATE TABLE link_attempts (
+    kind TEXT NOT NULL, -- 'user' or 'username'
+    key TEXT NOT NULL, -- discord id, or the lowercase GD username
+    failures INTEGER NOT NULL DEFAULT 0, -- failed attempts since the last lockout
+    lockouts INTEGER NOT NULL DEFAULT 0, -- every lockout lasts longer than the previous one
+    locked_until INTEGER, -- unix timestamp
+    last_attempt INTEGER NOT NULL, -- unix timestamp
+    PRIMARY KEY (kind, key)
+);
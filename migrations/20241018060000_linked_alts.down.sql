@@ -0,0 +1,16 @@
+CREATE TABLE synced_roles_old (
+    gd_account_id INTEGER NOT NULL PRIMARY KEY REFERENCES linked_users(gd_account_id) ON DELETE CASCADE,
+    roles TEXT NOT NULL,
+    synced_at INTEGER NOT NULL
+);
+
+INSERT INTO synced_roles_old (gd_account_id, roles, synced_at)
+SELECT gd_account_id, roles, synced_at FROM synced_roles
+WHERE gd_account_id IN (SELECT gd_account_id FROM linked_users);
+
+DROP TABLE synced_roles;
+ALTER TABLE synced_roles_old RENAME TO synced_roles;
+
+ALTER TABLE guilds DROP COLUMN max_accounts;
+
+DROP TABLE linked_alts;
@@ -0,0 +1,25 @@
+-- Extra GD accounts of a linked user, for alts that need roles too. The main account stays in linked_users
+CREATE TABLE linked_alts (
+    gd_account_id INTEGER NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES linked_users(id) ON DELETE CASCADE, -- discord id
+    gd_username TEXT,
+    linked_at INTEGER NOT NULL -- unix timestamp
+);
+
+CREATE INDEX linked_alts_user_id ON linked_alts (user_id);
+
+-- How many GD accounts members of the guild can link, including the main one
+ALTER TABLE guilds ADD COLUMN max_accounts INTEGER NOT NULL DEFAULT 1;
+
+-- Alts have synced roles too, so the table can't reference linked_users anymore. Rows are deleted when unlinking
+CREATE TABLE synced_roles_new (
+    gd_account_id INTEGER NOT NULL PRIMARY KEY,
+    roles TEXT NOT NULL, -- sorted json array of globed role ids
+    synced_at INTEGER NOT NULL -- unix timestamp
+);
+
+INSERT INTO synced_roles_new (gd_account_id, roles, synced_at)
+SELECT gd_account_id, roles, synced_at FROM synced_roles;
+
+DROP TABLE synced_roles;
+ALTER TABLE synced_roles_new RENAME TO synced_roles;
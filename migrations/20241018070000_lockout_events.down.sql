@@ -0,0 +1,18 @@
+CREATE TABLE link_events_old (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    actor_id INTEGER,
+    user_id INTEGER NOT NULL,
+    gd_account_id INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+INSERT INTO link_events_old (id, kind, actor_id, user_id, gd_account_id, created_at)
+SELECT id, kind, actor_id, user_id, gd_account_id, created_at FROM link_events
+WHERE user_id IS NOT NULL AND gd_account_id IS NOT NULL;
+
+DROP TABLE link_events;
+ALTER TABLE link_events_old RENAME TO link_events;
+
+CREATE INDEX link_events_user ON link_events (user_id);
+CREATE INDEX link_events_account ON link_events (gd_account_id);
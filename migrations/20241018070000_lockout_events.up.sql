@@ -0,0 +1,20 @@
+-- Lockouts are recorded in the link history too. They are about a discord user or a GD username rather than
+-- an account, so both ids become optional
+CREATE TABLE link_events_new (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL, -- link, unlink, admin_link, admin_unlink, leave_unlink, transfer, lockout or lockout_clear
+    actor_id INTEGER, -- discord id of who did it, null if the bot did it on its own
+    user_id INTEGER, -- discord id
+    gd_account_id INTEGER,
+    gd_username TEXT, -- the locked GD username, only set for lockouts
+    created_at INTEGER NOT NULL -- unix timestamp
+);
+
+INSERT INTO link_events_new (id, kind, actor_id, user_id, gd_account_id, created_at)
+SELECT id, kind, actor_id, user_id, gd_account_id, created_at FROM link_events;
+
+DROP TABLE link_events;
+ALTER TABLE link_events_new RENAME TO link_events;
+
+CREATE INDEX link_events_user ON link_events (user_id);
+CREATE INDEX link_events_account ON link_events (gd_account_id);
@@ -0,0 +1,17 @@
+-- queued syncs of other servers can't be kept
+CREATE TABLE sync_outbox_old (
+    seq INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    account_id INTEGER UNIQUE NOT NULL, -- gd account id, one entry per account
+    keep TEXT NOT NULL, -- json array of globed role ids
+    remove TEXT NOT NULL, -- json array of globed role ids
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt INTEGER NOT NULL, -- unix timestamp
+    version INTEGER NOT NULL DEFAULT 0, -- bumped every time a newer request is merged in
+    last_error TEXT
+);
+
+INSERT INTO sync_outbox_old (seq, account_id, keep, remove, attempts, next_attempt, version, last_error)
+SELECT seq, account_id, keep, remove, attempts, next_attempt, version, last_error FROM sync_outbox WHERE target = 'main';
+
+DROP TABLE sync_outbox;
+ALTER TABLE sync_outbox_old RENAME TO sync_outbox;
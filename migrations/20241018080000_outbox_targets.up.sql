@@ -0,0 +1,19 @@
+-- Failed syncs to extra servers are queued too, one entry per account and server
+CREATE TABLE sync_outbox_new (
+    seq INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    account_id INTEGER NOT NULL, -- gd account id
+    target TEXT NOT NULL DEFAULT 'main', -- name of the globed server
+    keep TEXT NOT NULL, -- json array of globed role ids
+    remove TEXT NOT NULL, -- json array of globed role ids
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt INTEGER NOT NULL, -- unix timestamp
+    version INTEGER NOT NULL DEFAULT 0, -- bumped every time a newer request is merged in
+    last_error TEXT,
+    UNIQUE (account_id, target)
+);
+
+INSERT INTO sync_outbox_new (seq, account_id, keep, remove, attempts, next_attempt, version, last_error)
+SELECT seq, account_id, keep, remove, attempts, next_attempt, version, last_error FROM sync_outbox;
+
+DROP TABLE sync_outbox;
+ALTER TABLE sync_outbox_new RENAME TO sync_outbox;
@@ -0,0 +1,37 @@
+-- rules, virtual roles and groups of other servers can't be kept
+CREATE TABLE role_rules_old (
+    id TEXT NOT NULL PRIMARY KEY, -- globed role id
+    expression TEXT NOT NULL -- canonical form of the rule
+);
+
+INSERT INTO role_rules_old (id, expression) SELECT id, expression FROM role_rules WHERE target = 'main';
+
+DROP TABLE role_rules;
+ALTER TABLE role_rules_old RENAME TO role_rules;
+
+CREATE TABLE virtual_roles_old (
+    id TEXT NOT NULL PRIMARY KEY, -- globed role id
+    source TEXT NOT NULL, -- 'booster', 'pending' or 'timed_out'
+    min_boost_days INTEGER NOT NULL DEFAULT 0 -- only for 'booster', how long the member has to be boosting
+);
+
+INSERT INTO virtual_roles_old (id, source, min_boost_days)
+SELECT id, source, min_boost_days FROM virtual_roles WHERE target = 'main';
+
+DROP TABLE virtual_roles;
+ALTER TABLE virtual_roles_old RENAME TO virtual_roles;
+
+CREATE TABLE role_group_members_old (
+    globed_role_id TEXT NOT NULL PRIMARY KEY, -- a globed role can only be in one group
+    group_name TEXT NOT NULL REFERENCES role_groups(name) ON DELETE CASCADE,
+    position INTEGER NOT NULL -- 1 is the highest tier
+);
+
+INSERT INTO role_group_members_old (globed_role_id, group_name, position)
+SELECT globed_role_id, group_name, position FROM role_group_members WHERE target = 'main';
+
+DROP TABLE role_group_members;
+ALTER TABLE role_group_members_old RENAME TO role_group_members;
+
+DELETE FROM role_groups WHERE target != 'main';
+ALTER TABLE role_groups DROP COLUMN target;
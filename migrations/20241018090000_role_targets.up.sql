@@ -0,0 +1,43 @@
+-- Rules, virtual roles and exclusive groups are scoped to a Globed server like role mappings,
+-- 'main' is the server from BOT_BASE_URL
+CREATE TABLE role_rules_new (
+    id TEXT NOT NULL, -- globed role id
+    target TEXT NOT NULL DEFAULT 'main',
+    expression TEXT NOT NULL, -- canonical form of the rule
+    PRIMARY KEY (target, id)
+);
+
+INSERT INTO role_rules_new (id, expression) SELECT id, expression FROM role_rules;
+
+DROP TABLE role_rules;
+ALTER TABLE role_rules_new RENAME TO role_rules;
+
+CREATE TABLE virtual_roles_new (
+    id TEXT NOT NULL, -- globed role id
+    target TEXT NOT NULL DEFAULT 'main',
+    source TEXT NOT NULL, -- 'booster', 'pending' or 'timed_out'
+    min_boost_days INTEGER NOT NULL DEFAULT 0, -- only for 'booster', how long the member has to be boosting
+    PRIMARY KEY (target, id)
+);
+
+INSERT INTO virtual_roles_new (id, source, min_boost_days) SELECT id, source, min_boost_days FROM virtual_roles;
+
+DROP TABLE virtual_roles;
+ALTER TABLE virtual_roles_new RENAME TO virtual_roles;
+
+ALTER TABLE role_groups ADD COLUMN target TEXT NOT NULL DEFAULT 'main';
+
+-- a globed role can only be in one group of its server, the target is always the one of the group
+CREATE TABLE role_group_members_new (
+    globed_role_id TEXT NOT NULL,
+    target TEXT NOT NULL DEFAULT 'main',
+    group_name TEXT NOT NULL REFERENCES role_groups(name) ON DELETE CASCADE,
+    position INTEGER NOT NULL, -- 1 is the highest tier
+    PRIMARY KEY (target, globed_role_id)
+);
+
+INSERT INTO role_group_members_new (globed_role_id, group_name, position)
+SELECT globed_role_id, group_name, position FROM role_group_members;
+
+DROP TABLE role_group_members;
+ALTER TABLE role_group_members_new RENAME TO role_group_members;
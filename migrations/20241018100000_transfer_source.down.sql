@@ -0,0 +1,3 @@
+DROP INDEX link_events_from_user;
+
+ALTER TABLE link_events DROP COLUMN from_user_id;
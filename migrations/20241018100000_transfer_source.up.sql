@@ -0,0 +1,4 @@
+-- Transfers remember the discord user the account was moved away from, so they show up in the history of both users
+ALTER TABLE link_events ADD COLUMN from_user_id INTEGER; -- discord id, only set for transfers
+
+CREATE INDEX link_events_from_user ON link_events (from_user_id);
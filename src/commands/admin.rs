@@ -1,8 +1,34 @@
-use crate::state::LinkError;
+use poise::{ChoiceParameter, CreateReply};
+
+use crate::{
+    db::RoleOverride,
+    state::{expiry_after, AttemptKind, DryRunReport, GuildError, LinkError, TransferError},
+};
 
 use super::prelude::*;
 
-#[poise::command(slash_command, subcommands("link", "unlink", "sync", "syncall"))]
+#[poise::command(
+    slash_command,
+    subcommands(
+        "link",
+        "unlink",
+        "transfer",
+        "sync",
+        "syncall",
+        "info",
+        "history",
+        "refreshnames",
+        "status",
+        "maintenance",
+        "drift",
+        "missing",
+        "grant",
+        "revoke",
+        "overrides",
+        "lockouts",
+        "guilds"
+    )
+)]
 pub async fn admin(_ctx: Context<'_>) -> Result<(), CommandError> {
     // unreachable
     Ok(())
@@ -13,7 +39,8 @@ pub async fn admin(_ctx: Context<'_>) -> Result<(), CommandError> {
 pub async fn link(
     ctx: Context<'_>,
     #[description = "User to link"] member: serenity::Member,
-    #[description = "GD account ID"] account_id: i32,
+    #[description = "GD username, looked up on the server"] username: Option<String>,
+    #[description = "GD account ID"] account_id: Option<i32>,
 ) -> Result<(), CommandError> {
     let state = ctx.data();
 
@@ -22,14 +49,63 @@ pub async fn link(
         return Ok(());
     }
 
-    ctx.defer().await?;
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
 
-    match state
-        .add_linked_user(&ctx, member.user.id, account_id)
-        .await
-    {
-        Ok(()) => {
-            ctx.reply("✅ Successfully linked this person.").await?;
+    let actor = LinkActor::Admin(ctx.author().id);
+
+    let result = match (username, account_id) {
+        (Some(username), None) => {
+            ctx.defer().await?;
+
+            state
+                .link_user(&ctx, &member, &username, None, actor)
+                .await
+                .map(|(user, _)| format!("{} ({})", user.name, user.account_id))
+        }
+
+        (None, Some(account_id)) => {
+            ctx.defer().await?;
+
+            // the name is only shown in listings, so the account can be linked without it
+            let name = match state.fetch_gd_usernames(&[account_id]).await {
+                Ok(mut names) => names.remove(&account_id),
+                Err(e) => {
+                    warn!("Failed to look up the name of GD account {account_id}: {e}");
+                    None
+                }
+            };
+
+            state
+                .add_linked_user(
+                    ctx,
+                    member.user.id,
+                    member.guild_id,
+                    account_id,
+                    name.as_deref(),
+                    actor,
+                )
+                .await
+                .map(|()| match name {
+                    Some(name) => format!("{name} ({account_id})"),
+                    None => account_id.to_string(),
+                })
+        }
+
+        _ => {
+            ctx.reply(":x: Pass either a GD username or an account ID.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    match result {
+        Ok(account) => {
+            ctx.reply(format!(
+                "✅ Successfully linked this person to GD account {account}."
+            ))
+            .await?;
 
             Ok(())
         }
@@ -102,6 +178,16 @@ pub async fn link(
 
             Ok(())
         }
+
+        // admins link without a code, which isn't counted towards lockouts
+        Err(LinkError::LockedOut(until)) => {
+            ctx.reply(format!(
+                ":x: Linking is locked for this user or GD username until <t:{until}:f>. Use `/admin lockout clear` to lift it."
+            ))
+            .await?;
+
+            Ok(())
+        }
     }
 }
 
@@ -110,6 +196,9 @@ pub async fn link(
 pub async fn unlink(
     ctx: Context<'_>,
     #[description = "User to unlink"] user: serenity::User,
+    #[description = "GD account ID to unlink, all of their accounts if not set"] account_id: Option<
+        i32,
+    >,
 ) -> Result<(), CommandError> {
     let state = ctx.data();
 
@@ -118,14 +207,30 @@ pub async fn unlink(
         return Ok(());
     }
 
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
     ctx.defer().await?;
 
-    match state.unlink_user(user.id).await {
+    let actor = LinkActor::Admin(ctx.author().id);
+
+    let result = match account_id {
+        Some(account_id) => state.unlink_account(user.id, account_id, actor).await,
+        None => state.unlink_user(user.id, actor).await,
+    };
+
+    match result {
         Ok(()) => {
             ctx.reply("Successfully unlinked the user's account!")
                 .await?;
         }
 
+        Err(RoleSyncError::NotLinked) if account_id.is_some() => {
+            ctx.reply(":x: User is not linked to this GD account.")
+                .await?;
+        }
+
         Err(RoleSyncError::NotLinked) => {
             ctx.reply(":x: User is not linked to a GD account.").await?;
         }
@@ -139,11 +244,84 @@ pub async fn unlink(
     Ok(())
 }
 
+/// Move a linked GD account to another discord user, for players that switched accounts
+#[poise::command(slash_command)]
+pub async fn transfer(
+    ctx: Context<'_>,
+    #[description = "User the account is linked to now"] from: serenity::User,
+    #[description = "User to move the account to"] to: serenity::Member,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer().await?;
+
+    match state
+        .transfer_link(ctx.http(), from.id, &to, LinkActor::Admin(ctx.author().id))
+        .await
+    {
+        Ok((linked_user, roles)) => {
+            info!(
+                "{} transferred GD account {} from {} to {}",
+                ctx.author().id,
+                linked_user.gd_account_id,
+                from.id,
+                to.user.id
+            );
+
+            ctx.reply(format!(
+                "✅ Transferred GD account {} from <@{}> to <@{}>.\n\n* Synced roles: {}",
+                linked_user.account_display(),
+                from.id,
+                to.user.id,
+                roles.join(", ")
+            ))
+            .await?;
+        }
+
+        Err(TransferError::RoleSync(err, linked_user)) => {
+            warn!("Failed to sync roles: {err}");
+
+            ctx.reply(format!(
+                "Transferred GD account {} to <@{}>, but role syncing failed. Try to execute the `/sync` command manually for them.",
+                linked_user.account_display(),
+                to.user.id
+            ))
+            .await?;
+        }
+
+        Err(TransferError::Database(e)) => {
+            ctx.reply(":x: Unknown database error has occurred.")
+                .await?;
+
+            bail!("Failed to transfer a link: {e}");
+        }
+
+        Err(e) => {
+            ctx.reply(format!(":x: {e}.")).await?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Sync another user's roles to their GD account on Globed
 #[poise::command(slash_command)]
 pub async fn sync(
     ctx: Context<'_>,
     #[description = "User to sync"] user: serenity::Member,
+    #[description = "Send the roles even if they didn't change since the last sync"] force: Option<
+        bool,
+    >,
+    #[description = "Only show what would be sent, without syncing anything"] dry_run: Option<bool>,
 ) -> Result<(), CommandError> {
     let state = ctx.data();
 
@@ -154,7 +332,28 @@ pub async fn sync(
 
     ctx.defer().await?;
 
-    match state.sync_roles(&user).await {
+    let options = SyncOptions {
+        force: force.unwrap_or(false),
+    };
+
+    if dry_run.unwrap_or(false) {
+        match state.preview_sync(ctx.http(), &user, options).await {
+            Ok(report) => reply_dry_run(&ctx, report).await?,
+            Err(RoleSyncError::NotLinked) => {
+                ctx.reply(":x: User is not linked to a GD account.").await?;
+            }
+            Err(e) => {
+                ctx.reply(format!(":x: Error while previewing the sync: {e}"))
+                    .await?;
+
+                bail!("Error previewing sync of user: {e}");
+            }
+        }
+
+        return Ok(());
+    }
+
+    match state.sync_roles(ctx.http(), &user, options).await {
         Ok(roles) => {
             let message =
                 format!("✅ Successfully synced @{}'s roles! If they were already online on Globed, they might need to reconnect to the server to see the changes.\n\n", user.user.name)
@@ -180,7 +379,12 @@ pub async fn sync(
 
 /// Sync roles of all linked users on this server
 #[poise::command(slash_command)]
-pub async fn syncall(ctx: Context<'_>) -> Result<(), CommandError> {
+pub async fn syncall(
+    ctx: Context<'_>,
+    #[description = "Send roles of every user, even ones that didn't change since the last sync"]
+    force: Option<bool>,
+    #[description = "Only show what would be sent, without syncing anything"] dry_run: Option<bool>,
+) -> Result<(), CommandError> {
     let state = ctx.data();
 
     if !has_manage_roles_perm(&ctx).await {
@@ -188,12 +392,84 @@ pub async fn syncall(ctx: Context<'_>) -> Result<(), CommandError> {
         return Ok(());
     }
 
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
     ctx.defer().await?;
 
-    match state.sync_all_members(ctx.http()).await {
-        Ok(count) => {
-            ctx.reply(format!("✅ Successfully synced roles of {count} people!"))
-                .await?;
+    let options = SyncOptions {
+        force: force.unwrap_or(false),
+    };
+
+    if dry_run.unwrap_or(false) {
+        match state.preview_sync_all(ctx.http(), options).await {
+            Ok(report) => reply_dry_run(&ctx, report).await?,
+            Err(e) => {
+                ctx.reply(format!(":x: Error while previewing the sync: {e}"))
+                    .await?;
+
+                bail!("Error previewing sync of all members: {e}");
+            }
+        }
+
+        return Ok(());
+    }
+
+    match state.sync_all_members(ctx.http(), options).await {
+        Ok(report) => {
+            let mut message = format!(
+                "✅ Successfully synced roles of {} people!\n\n* Unchanged users skipped: {}\n* Pages fetched: {}\n* Chunks sent: {}\n* Unlinked members skipped: {}",
+                report.users_sent, report.users_unchanged, report.pages_fetched, report.chunks_sent, report.members_skipped
+            );
+
+            if report.chunks_queued > 0 {
+                message += &format!(
+                    "\n* :warning: {} chunks failed to sync and were queued for a retry",
+                    report.chunks_queued
+                );
+            }
+
+            if report.chunks_failed > 0 {
+                message += &format!(
+                    "\n* :x: {} chunks with {} users were rejected by the server and not synced, check the logs",
+                    report.chunks_failed, report.users_failed
+                );
+            }
+
+            if report.guilds_failed > 0 {
+                message += &format!(
+                    "\n* :warning: {} guilds couldn't be fetched, {} of their linked members were skipped",
+                    report.guilds_failed, report.users_incomplete
+                );
+            }
+
+            if report.members_missing > 0 {
+                message += &format!(
+                    "\n* Linked users missing from every guild: {}",
+                    report.members_missing
+                );
+            }
+
+            if !report.reverse.is_empty() {
+                message += &format!(
+                    "\n* Discord roles synced from Globed: {} added, {} removed",
+                    report.reverse.roles_added, report.reverse.roles_removed
+                );
+
+                if report.reverse.roles_failed > 0 {
+                    message += &format!(
+                        "\n* :warning: {} discord roles failed to update",
+                        report.reverse.roles_failed
+                    );
+                }
+            }
+
+            if report.resumed {
+                message += "\n* Continued from a previously interrupted sync";
+            }
+
+            ctx.reply(message).await?;
         }
 
         Err(e) => {
@@ -206,3 +482,1177 @@ pub async fn syncall(ctx: Context<'_>) -> Result<(), CommandError> {
 
     Ok(())
 }
+
+// sends the summary of a dry run, with the full payload attached as a json file
+async fn reply_dry_run(ctx: &Context<'_>, report: DryRunReport) -> Result<(), CommandError> {
+    let mut message = format!(
+        "🔍 Dry run, nothing was sent to the server.\n\n* Users that would be synced: {}\n* Unchanged users skipped: {}\n",
+        report.payload.users.len(),
+        report.users_unchanged
+    );
+
+    if report.users_unknown > 0 {
+        message += &format!(
+            "* Users that were never synced before (only granted roles are counted): {}\n",
+            report.users_unknown
+        );
+    }
+
+    for (target, data) in &report.target_payloads {
+        message += &format!(
+            "* Users that would be synced to `{target}`: {}\n",
+            data.users.len()
+        );
+    }
+
+    if !report.roles.is_empty() {
+        message += "\n**Changes per role**\n";
+
+        for (role, count) in &report.roles {
+            message += &format!(
+                "* `{role}`: {} granted, {} revoked\n",
+                count.granted, count.revoked
+            );
+        }
+    }
+
+    let payload = report.payload_json();
+
+    ctx.send(CreateReply::default().content(message).attachment(
+        serenity::CreateAttachment::bytes(payload, "sync_payload.json"),
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Show who a GD account was linked to, or which GD accounts a user was linked to
+#[poise::command(slash_command)]
+pub async fn history(
+    ctx: Context<'_>,
+    #[description = "User to look up"] user: Option<serenity::User>,
+    #[description = "GD account ID to look up"] account_id: Option<i32>,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let (events, subject) = match (user, account_id) {
+        (Some(user), None) => (
+            state.get_user_history(user.id).await,
+            format!("<@{}>", user.id),
+        ),
+        (None, Some(account_id)) => (
+            state.get_account_history(account_id).await,
+            format!("GD account {account_id}"),
+        ),
+        _ => {
+            ctx.reply(":x: Pass either a user or a GD account ID.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let events = match events {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the link history: {e}"))
+                .await?;
+            bail!("Failed to get link history: {e}");
+        }
+    };
+
+    if events.is_empty() {
+        ctx.reply(format!("There is no link history for {subject}."))
+            .await?;
+        return Ok(());
+    }
+
+    let mut message = format!("**Link history of {subject}**\n\n");
+    for event in events {
+        message += &format!("* {event}\n");
+    }
+
+    ctx.reply(message).await?;
+
+    Ok(())
+}
+
+/// Show the linked account of a user and the roles last synced to Globed
+#[poise::command(slash_command)]
+pub async fn info(
+    ctx: Context<'_>,
+    #[description = "User to look up"] user: serenity::User,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let (linked_user, alts) = match state.get_all_accounts(user.id).await {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            ctx.reply(":x: User is not linked to a GD account.").await?;
+            return Ok(());
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to look up the user: {e}"))
+                .await?;
+            bail!("Failed to look up linked account: {e}");
+        }
+    };
+
+    let mut message = format!(
+        "<@{}> is linked to GD account {}.\n\n",
+        user.id,
+        linked_user.account_display()
+    );
+
+    if !alts.is_empty() {
+        let alts: Vec<String> = alts.iter().map(|x| x.account_display()).collect();
+        message += &format!("* Alts, synced with the same roles: {}\n", alts.join(", "));
+    }
+
+    match state
+        .get_synced_roles(linked_user.gd_account_id as i32)
+        .await
+    {
+        Ok(Some(synced)) => {
+            let roles = synced.role_list();
+
+            message += &format!("* Last synced: <t:{0}:f> (<t:{0}:R>)\n", synced.synced_at);
+            if roles.is_empty() {
+                message += "* Synced roles: none";
+            } else {
+                message += &format!("* Synced roles: {}", roles.join(", "));
+            }
+        }
+        Ok(None) => {
+            message += "* Roles have not been synced yet, or the role mappings changed since the last sync.";
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the synced roles: {e}"))
+                .await?;
+            bail!("Failed to get synced roles: {e}");
+        }
+    }
+
+    match state.get_user_grants(user.id).await {
+        Ok(grants) => {
+            for grant in grants {
+                message += &format!(
+                    "\n* Temporary role `{}` until <t:{}:f>, granted by <@{}>",
+                    grant.globed_role_id, grant.expires_at, grant.granted_by
+                );
+            }
+        }
+        Err(e) => {
+            warn!("Failed to get the grants of a user: {e}");
+        }
+    }
+
+    match state.get_overrides(Some(user.id)).await {
+        Ok(overrides) => {
+            for x in overrides {
+                message += &format!("\n* Override: {}", format_override(&x));
+            }
+        }
+        Err(e) => {
+            warn!("Failed to get the overrides of a user: {e}");
+        }
+    }
+
+    ctx.reply(message).await?;
+
+    Ok(())
+}
+
+/// Show the state of background syncing
+#[poise::command(slash_command)]
+pub async fn status(ctx: Context<'_>) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    let mut message = String::from("**Sync status**\n\n");
+
+    if state.is_paused() {
+        message += "* :pause_button: Paused for maintenance, periodic syncs are skipped\n";
+    }
+
+    if state.is_full_sync_running() {
+        message += "* A full sync is currently running\n";
+    }
+
+    match state.next_reconciliation() {
+        Some(next) => message += &format!("* Next periodic sync: <t:{next}:R>\n"),
+        None => message += "* Periodic syncing is disabled\n",
+    }
+
+    match &*state.last_full_sync.lock() {
+        Some(last) => {
+            let kind = if last.periodic { "periodic" } else { "manual" };
+
+            match &last.outcome {
+                Ok(report) => {
+                    message += &format!(
+                        "* Last full sync ({kind}) finished <t:{}:R>: {report}\n",
+                        last.finished_at
+                    );
+                }
+                Err(err) => {
+                    message += &format!(
+                        "* Last full sync ({kind}) failed <t:{}:R>: {err}\n",
+                        last.finished_at
+                    );
+                }
+            }
+        }
+        None => message += "* No full sync has finished since the bot started\n",
+    }
+
+    match state.get_outbox_size().await {
+        Ok(size) => message += &format!("* Syncs queued for a retry: {size}\n"),
+        Err(e) => {
+            warn!("Failed to get the outbox size: {e}");
+        }
+    }
+
+    if !state.extra_targets().is_empty() {
+        let errors = state.target_errors();
+
+        for target in state.extra_targets() {
+            match errors.iter().find(|(name, _, _)| *name == target.name) {
+                Some((_, at, err)) => {
+                    message += &format!(
+                        "* Server `{}`: last sync failed <t:{at}:R>: {err}\n",
+                        target.name
+                    );
+                }
+                None => message += &format!("* Server `{}`: OK\n", target.name),
+            }
+        }
+    }
+
+    ctx.reply(message).await?;
+
+    Ok(())
+}
+
+/// Fetch the current GD usernames of all linked accounts from the server
+#[poise::command(slash_command)]
+pub async fn refreshnames(ctx: Context<'_>) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer().await?;
+
+    match state.refresh_gd_usernames().await {
+        Ok(count) => {
+            ctx.reply(format!(
+                "✅ Refreshed GD usernames, {count} of them changed."
+            ))
+            .await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to refresh GD usernames: {e}"))
+                .await?;
+            bail!("Failed to refresh GD usernames: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Pause or resume periodic syncing for maintenance
+#[poise::command(slash_command)]
+pub async fn maintenance(
+    ctx: Context<'_>,
+    #[description = "Whether periodic syncing should be paused"] paused: bool,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    if let Err(e) = state.set_paused(paused).await {
+        ctx.reply(format!(":x: Failed to update the maintenance flag: {e}"))
+            .await?;
+        bail!("Failed to update the maintenance flag: {e}");
+    }
+
+    if paused {
+        ctx.reply("✅ Periodic syncing is now paused.").await?;
+    } else {
+        ctx.reply("✅ Periodic syncing has been resumed.").await?;
+    }
+
+    Ok(())
+}
+
+/// Compare roles on the Globed server with the ones linked users should have
+#[poise::command(slash_command)]
+pub async fn drift(
+    ctx: Context<'_>,
+    #[description = "Send the correct roles for every user that drifted"] fix: Option<bool>,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    // the report covers users from every guild, and fixing sends their roles
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer().await?;
+
+    let report = match state.detect_drift(ctx.http()).await {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Error while checking for drift: {e}"))
+                .await?;
+
+            bail!("Error checking for drift: {e}");
+        }
+    };
+
+    if report.drifted.is_empty() {
+        ctx.reply(format!(
+            "✅ Checked {} linked users, all roles on the server are in sync.",
+            report.users_checked
+        ))
+        .await?;
+
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "Checked {} linked users, {} of their accounts have drifted:\n\n",
+        report.users_checked,
+        report.drifted.len()
+    );
+
+    const MAX_LISTED: usize = 15;
+
+    for entry in report.drifted.iter().take(MAX_LISTED) {
+        message += &format!("* <@{}> ({}):", entry.user_id, entry.account);
+
+        if !entry.missing.is_empty() {
+            message += &format!(" missing {}", entry.missing.join(", "));
+        }
+
+        if !entry.extra.is_empty() {
+            message += &format!(" extra {}", entry.extra.join(", "));
+        }
+
+        message.push('\n');
+    }
+
+    if report.drifted.len() > MAX_LISTED {
+        message += &format!("* ..and {} more\n", report.drifted.len() - MAX_LISTED);
+    }
+
+    if fix.unwrap_or(false) {
+        match state.fix_drift(report).await {
+            Ok(count) => message += &format!("\n✅ Fixed roles of {count} accounts."),
+            Err(e) => {
+                message += &format!("\n:x: Failed to fix the roles: {e}");
+                warn!("Failed to fix drifted roles: {e}");
+            }
+        }
+    } else {
+        message += "\nRun this command with `fix: True` to send the correct roles to the server.";
+    }
+
+    ctx.reply(message).await?;
+
+    Ok(())
+}
+
+/// List linked users that weren't found in any guild during the last full sync
+#[poise::command(slash_command)]
+pub async fn missing(
+    ctx: Context<'_>,
+    #[description = "Unlink all listed users and remove their roles"] unlink: Option<bool>,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if unlink.unwrap_or(false) && !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer().await?;
+
+    if unlink.unwrap_or(false) {
+        match state
+            .unlink_missing_members(LinkActor::Admin(ctx.author().id))
+            .await
+        {
+            Ok(count) => {
+                ctx.reply(format!("✅ Unlinked {count} missing users."))
+                    .await?;
+            }
+            Err(e) => {
+                ctx.reply(format!(":x: Failed to unlink missing users: {e}"))
+                    .await?;
+                bail!("Failed to unlink missing users: {e}");
+            }
+        }
+
+        return Ok(());
+    }
+
+    let missing = match state.get_missing_members().await {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the missing users: {e}"))
+                .await?;
+            bail!("Failed to get missing users: {e}");
+        }
+    };
+
+    if missing.is_empty() {
+        ctx.reply("✅ All linked users were found during the last full sync.")
+            .await?;
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "{} linked users weren't found in any guild:\n\n",
+        missing.len()
+    );
+
+    const MAX_LISTED: usize = 25;
+
+    for member in missing.iter().take(MAX_LISTED) {
+        message += &format!(
+            "* <@{}>, missing since <t:{}:f>\n",
+            member.id, member.detected_at
+        );
+    }
+
+    if missing.len() > MAX_LISTED {
+        message += &format!("* ..and {} more\n", missing.len() - MAX_LISTED);
+    }
+
+    message += "\nRun this command with `unlink: True` to unlink them and remove their roles.";
+
+    ctx.reply(message).await?;
+
+    Ok(())
+}
+
+/// Give a user a Globed role for a limited time
+#[poise::command(slash_command)]
+pub async fn grant(
+    ctx: Context<'_>,
+    #[description = "User to give the role to"] user: serenity::Member,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "How long the role is kept, e.g. 30m, 12h, 7d or 1w2d"] duration: String,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(duration) = parse_duration(&duration) else {
+        ctx.reply(":x: Invalid duration, use something like `30m`, `12h`, `7d` or `1w2d`, up to 10 years.")
+            .await?;
+        return Ok(());
+    };
+
+    ctx.defer().await?;
+
+    let expires_at = match state
+        .grant_role(user.user.id, &globed_role_id, duration, ctx.author().id)
+        .await
+    {
+        Ok(x) => x,
+        Err(RoleSyncError::NotLinked) => {
+            ctx.reply(":x: User is not linked to a GD account.").await?;
+            return Ok(());
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to grant the role: {e}"))
+                .await?;
+            bail!("Failed to grant a role: {e}");
+        }
+    };
+
+    match state
+        .sync_roles(ctx.http(), &user, SyncOptions::default())
+        .await
+    {
+        Ok(_) => {
+            ctx.reply(format!(
+                "✅ Granted globed role `{globed_role_id}` to <@{}> until <t:{expires_at}:f> (<t:{expires_at}:R>).",
+                user.user.id
+            ))
+            .await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(
+                "Granted globed role `{globed_role_id}` to <@{}> until <t:{expires_at}:f>, but syncing their roles failed: {e}",
+                user.user.id
+            ))
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Take away a temporary role before it expires
+#[poise::command(slash_command)]
+pub async fn revoke(
+    ctx: Context<'_>,
+    #[description = "User to take the role from"] user: serenity::User,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer().await?;
+
+    match state
+        .revoke_grant(ctx.http(), user.id, &globed_role_id)
+        .await
+    {
+        Ok(true) => {
+            ctx.reply(format!(
+                "✅ Revoked temporary role `{globed_role_id}` from <@{}>.",
+                user.id
+            ))
+            .await?;
+        }
+        Ok(false) => {
+            ctx.reply(":x: User doesn't have a temporary grant of this role.")
+                .await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to revoke the role: {e}"))
+                .await?;
+            bail!("Failed to revoke a grant: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Force a Globed role to be kept or removed for a user, regardless of their discord roles
+#[poise::command(
+    slash_command,
+    rename = "override",
+    subcommands("override_set", "override_list", "override_clear")
+)]
+pub async fn overrides(_ctx: Context<'_>) -> Result<(), CommandError> {
+    // unreachable
+    Ok(())
+}
+
+fn format_override(x: &RoleOverride) -> String {
+    let mut out = format!(
+        "{} `{}` for <@{}> by <@{}>: {}",
+        OverrideMode::from_keep(x.keep).name(),
+        x.globed_role_id,
+        x.user_id,
+        x.created_by,
+        x.reason
+    );
+
+    if let Some(expires_at) = x.expires_at {
+        out += &format!(" (until <t:{expires_at}:f>)");
+    }
+
+    out
+}
+
+/// Set an override for a user, replacing an existing override of the same role
+#[poise::command(slash_command, rename = "set")]
+pub async fn override_set(
+    ctx: Context<'_>,
+    #[description = "User to override the role for"] user: serenity::Member,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Whether the role is always kept or always removed"] mode: OverrideMode,
+    #[description = "Why the override exists"] reason: String,
+    #[description = "How long the override lasts, e.g. 30m, 12h, 7d or 1w2d. Permanent if not set"]
+    duration: Option<String>,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let expires_at = match duration
+        .as_deref()
+        .map(|x| parse_duration(x).and_then(expiry_after))
+    {
+        None => None,
+        Some(Some(expires_at)) => Some(expires_at),
+        Some(None) => {
+            ctx.reply(":x: Invalid duration, use something like `30m`, `12h`, `7d` or `1w2d`, up to 10 years.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    ctx.defer().await?;
+
+    match state
+        .set_override(
+            user.user.id,
+            &globed_role_id,
+            mode == OverrideMode::Keep,
+            &reason,
+            ctx.author().id,
+            expires_at,
+        )
+        .await
+    {
+        Ok(()) => {}
+        Err(RoleSyncError::NotLinked) => {
+            ctx.reply(":x: User is not linked to a GD account.").await?;
+            return Ok(());
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to set the override: {e}"))
+                .await?;
+            bail!("Failed to set an override: {e}");
+        }
+    }
+
+    let until = expires_at
+        .map(|x| format!(" until <t:{x}:f>"))
+        .unwrap_or_default();
+
+    match state
+        .sync_roles(ctx.http(), &user, SyncOptions::default())
+        .await
+    {
+        Ok(_) => {
+            ctx.reply(format!(
+                "✅ Globed role `{globed_role_id}` is now set to \"{}\" for <@{}>{until}.",
+                mode.name(),
+                user.user.id
+            ))
+            .await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(
+                "Globed role `{globed_role_id}` is now set to \"{}\" for <@{}>{until}, but syncing their roles failed: {e}",
+                mode.name(),
+                user.user.id
+            ))
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// List overrides of a user, or of everyone
+#[poise::command(slash_command, rename = "list")]
+pub async fn override_list(
+    ctx: Context<'_>,
+    #[description = "User to list the overrides of"] user: Option<serenity::User>,
+) -> Result<(), CommandError> {
+    const MAX_LISTED: usize = 25;
+
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let overrides = match state.get_overrides(user.map(|x| x.id)).await {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the overrides: {e}"))
+                .await?;
+            bail!("Failed to get overrides: {e}");
+        }
+    };
+
+    if overrides.is_empty() {
+        ctx.reply("No overrides are set.").await?;
+        return Ok(());
+    }
+
+    let mut message = format!("{} overrides:\n", overrides.len());
+
+    for x in overrides.iter().take(MAX_LISTED) {
+        message += &format!("* {}\n", format_override(x));
+    }
+
+    if overrides.len() > MAX_LISTED {
+        message += &format!("* ..and {} more\n", overrides.len() - MAX_LISTED);
+    }
+
+    ctx.reply(message).await?;
+
+    Ok(())
+}
+
+/// Remove an override, the role goes back to following the user's discord roles
+#[poise::command(slash_command, rename = "clear")]
+pub async fn override_clear(
+    ctx: Context<'_>,
+    #[description = "User to clear the override of"] user: serenity::User,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    ctx.defer().await?;
+
+    match state
+        .clear_override(ctx.http(), user.id, &globed_role_id)
+        .await
+    {
+        Ok(true) => {
+            ctx.reply(format!(
+                "✅ Cleared the override of `{globed_role_id}` for <@{}>.",
+                user.id
+            ))
+            .await?;
+        }
+        Ok(false) => {
+            ctx.reply(":x: User doesn't have an override of this role.")
+                .await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to clear the override: {e}"))
+                .await?;
+            bail!("Failed to clear an override: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// View and lift lockouts caused by failed link attempts
+#[poise::command(
+    slash_command,
+    rename = "lockout",
+    subcommands("lockout_list", "lockout_clear")
+)]
+pub async fn lockouts(_ctx: Context<'_>) -> Result<(), CommandError> {
+    // unreachable
+    Ok(())
+}
+
+/// List users and GD usernames that currently can't link
+#[poise::command(slash_command, rename = "list")]
+pub async fn lockout_list(ctx: Context<'_>) -> Result<(), CommandError> {
+    const MAX_LISTED: usize = 25;
+
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let lockouts = match state.get_lockouts().await {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the lockouts: {e}"))
+                .await?;
+            bail!("Failed to get lockouts: {e}");
+        }
+    };
+
+    if lockouts.is_empty() {
+        ctx.reply("Nobody is locked out.").await?;
+        return Ok(());
+    }
+
+    let mut message = format!("{} lockouts:\n", lockouts.len());
+
+    for x in lockouts.iter().take(MAX_LISTED) {
+        message += &format!(
+            "* {x} until <t:{}:f> (lockout #{})\n",
+            x.locked_until.unwrap_or_default(),
+            x.lockouts
+        );
+    }
+
+    if lockouts.len() > MAX_LISTED {
+        message += &format!("* ..and {} more\n", lockouts.len() - MAX_LISTED);
+    }
+
+    ctx.reply(message).await?;
+
+    Ok(())
+}
+
+/// Lift the lockout of a user or a GD username and forget their failed attempts
+#[poise::command(slash_command, rename = "clear")]
+pub async fn lockout_clear(
+    ctx: Context<'_>,
+    #[description = "User to clear the lockout of"] user: Option<serenity::User>,
+    #[description = "GD username to clear the lockout of"] username: Option<String>,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let (kind, key, shown) = match (user, username) {
+        (Some(user), None) => (
+            AttemptKind::User,
+            user.id.to_string(),
+            format!("<@{}>", user.id),
+        ),
+        (None, Some(username)) => (
+            AttemptKind::Username,
+            username.clone(),
+            format!("GD username `{username}`"),
+        ),
+        _ => {
+            ctx.reply(":x: Specify either a user or a GD username.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    match state
+        .clear_lockout(kind, &key, LinkActor::Admin(ctx.author().id))
+        .await
+    {
+        Ok(true) => {
+            info!("{} cleared the link lockout of {shown}", ctx.author().id);
+            ctx.reply(format!("✅ Cleared the lockout of {shown}."))
+                .await?;
+        }
+        Ok(false) => {
+            ctx.reply(format!(":x: {shown} has no failed link attempts."))
+                .await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to clear the lockout: {e}"))
+                .await?;
+            bail!("Failed to clear a lockout: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Manage the guilds whose roles are synced to Globed
+#[poise::command(
+    slash_command,
+    rename = "guild",
+    subcommands("guild_add", "guild_remove", "guild_list", "guild_limit")
+)]
+pub async fn guilds(_ctx: Context<'_>) -> Result<(), CommandError> {
+    // unreachable
+    Ok(())
+}
+
+// the guild registry can only be changed by admins of the home guild
+async fn can_manage_guilds(ctx: &Context<'_>) -> Result<bool, CommandError> {
+    if !in_home_guild(ctx).await? {
+        return Ok(false);
+    }
+
+    if !has_admin_perm(ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+fn parse_guild_id(input: &str) -> Option<serenity::GuildId> {
+    input
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|x| *x != 0)
+        .map(serenity::GuildId::new)
+}
+
+/// Start syncing roles from another guild, the bot has to be in it already
+#[poise::command(slash_command, rename = "add")]
+pub async fn guild_add(
+    ctx: Context<'_>,
+    #[description = "ID of the guild"] guild_id: String,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !can_manage_guilds(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(guild_id) = parse_guild_id(&guild_id) else {
+        ctx.reply(":x: Invalid guild ID.").await?;
+        return Ok(());
+    };
+
+    ctx.defer().await?;
+
+    let guild = match ctx.http().get_guild(guild_id).await {
+        Ok(x) => x,
+        Err(_) => {
+            ctx.reply(":x: The bot is not in this guild, invite it first.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    match state.add_guild(guild.id, &guild.name).await {
+        Ok(()) => {}
+        Err(GuildError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to add the guild: {e}"))
+                .await?;
+            bail!("Adding a guild failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+            return Ok(());
+        }
+    }
+
+    if let Err(e) = poise::builtins::register_in_guild(
+        ctx.http(),
+        &ctx.framework().options().commands,
+        guild.id,
+    )
+    .await
+    {
+        ctx.reply(format!(
+            "Added guild `{}`, but registering commands there failed: {e}",
+            guild.name
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    ctx.reply(format!(
+        "✅ Added guild `{}`. Link its roles with `/role add` in that guild, roles of its members get synced from now on.",
+        guild.name
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Stop syncing roles from a guild, its role mappings are deleted
+#[poise::command(slash_command, rename = "remove")]
+pub async fn guild_remove(
+    ctx: Context<'_>,
+    #[description = "ID of the guild"] guild_id: String,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !can_manage_guilds(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(guild_id) = parse_guild_id(&guild_id) else {
+        ctx.reply(":x: Invalid guild ID.").await?;
+        return Ok(());
+    };
+
+    ctx.defer().await?;
+
+    let (mappings, users) = match state.remove_guild(guild_id).await {
+        Ok(x) => x,
+        Err(GuildError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to remove the guild: {e}"))
+                .await?;
+            bail!("Removing a guild failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+            return Ok(());
+        }
+    };
+
+    // commands of the bot shouldn't stay behind in the guild
+    if let Err(e) = guild_id.set_commands(ctx.http(), Vec::new()).await {
+        warn!("Failed to unregister commands in guild {guild_id}: {e}");
+    }
+
+    ctx.reply(format!(
+        "✅ Removed guild {guild_id} and its {mappings} role mappings. Removed its roles from {users} linked accounts."
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Set how many GD accounts members of a guild can link, for official alt accounts
+#[poise::command(slash_command, rename = "limit")]
+pub async fn guild_limit(
+    ctx: Context<'_>,
+    #[description = "ID of the guild"] guild_id: String,
+    #[description = "Max GD accounts per member, including the main one"]
+    #[min = 1]
+    #[max = 25]
+    max_accounts: u8,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !can_manage_guilds(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(guild_id) = parse_guild_id(&guild_id) else {
+        ctx.reply(":x: Invalid guild ID.").await?;
+        return Ok(());
+    };
+
+    match state
+        .set_max_accounts(guild_id, i64::from(max_accounts))
+        .await
+    {
+        Ok(()) => {
+            ctx.reply(format!(
+                "✅ Members of guild {guild_id} can now link up to {max_accounts} GD accounts. Accounts over the limit stay linked."
+            ))
+            .await?;
+        }
+        Err(GuildError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to change the limit: {e}"))
+                .await?;
+            bail!("Changing the account limit of a guild failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// List the guilds whose roles are synced to Globed
+#[poise::command(slash_command, rename = "list")]
+pub async fn guild_list(ctx: Context<'_>) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let guilds = match state.get_all_guilds().await {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the list of guilds: {e}"))
+                .await?;
+            bail!("Failed to get guilds: {e}");
+        }
+    };
+
+    let mut message = "Guilds whose roles are synced to Globed:\n\n".to_owned();
+
+    for guild in guilds {
+        let name = guild.name.as_deref().unwrap_or("unknown name");
+
+        message += &format!("* `{name}` ({})", guild.id);
+
+        if guild.id == state.home_guild_id.get() as i64 {
+            message += " - main server";
+        } else {
+            message += &format!(", added <t:{}:f>", guild.added_at);
+        }
+
+        if guild.max_accounts > 1 {
+            message += &format!(", up to {} GD accounts per member", guild.max_accounts);
+        }
+
+        message += "\n";
+    }
+
+    ctx.reply(message).await?;
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use crate::state::LinkError;
+use crate::state::{format_code, LinkCodeError, LinkError};
 
 use super::prelude::*;
 
@@ -11,12 +11,21 @@ pub async fn link(
 ) -> Result<(), CommandError> {
     let state = ctx.data();
 
+    if state.bot_link_codes_enabled() {
+        reply_ephemeral(
+            &ctx,
+            ":x: Linking with codes shown in-game is disabled. Use `/linkstart` to get a code to enter in-game instead.",
+        )
+        .await?;
+        return Ok(());
+    }
+
     let member = ctx.author_member().await.unwrap();
 
     ctx.defer().await?;
 
     match state
-        .link_user(&ctx, &member, &username, Some(link_code))
+        .link_user(&ctx, &member, &username, Some(link_code), LinkActor::User)
         .await
     {
         Ok((user, roles)) => {
@@ -108,5 +117,55 @@ pub async fn link(
 
             Ok(())
         }
+
+        Err(LinkError::LockedOut(until)) => {
+            ctx.reply(format!(":x: Too many failed attempts to link this account. Try again <t:{until}:R>, or contact the moderator team if you need help."))
+            .await?;
+
+            Ok(())
+        }
+    }
+}
+
+/// Get a code to enter in-game, for linking your account without being online right now
+#[poise::command(slash_command, guild_only = true)]
+pub async fn linkstart(ctx: Context<'_>) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    match state
+        .create_link_code(ctx.author().id, ctx.guild_id().unwrap())
+        .await
+    {
+        Ok((code, expires_at)) => {
+            reply_ephemeral(
+                &ctx,
+                format!(
+                    "Your link code is `{}`. Enter it in-game to link your GD account, it expires <t:{expires_at}:R>.",
+                    format_code(&code)
+                ),
+            )
+            .await?;
+        }
+
+        Err(LinkCodeError::AlreadyLinked) => {
+            reply_ephemeral(
+                &ctx,
+                ":x: Already linked. Use the `/unlink` command to unlink your account.",
+            )
+            .await?;
+        }
+
+        Err(LinkCodeError::Database(err)) => {
+            ctx.reply(":x: Unknown database error has occurred.")
+                .await?;
+
+            bail!("database connection error: {err}");
+        }
+
+        Err(err) => {
+            reply_ephemeral(&ctx, format!(":x: {err}")).await?;
+        }
     }
+
+    Ok(())
 }
@@ -1,5 +1,5 @@
 use crate::serenity;
-use std::{borrow::Cow, fmt::Display};
+use std::{borrow::Cow, fmt::Display, time::Duration};
 
 pub mod prelude;
 
@@ -81,6 +81,55 @@ pub async fn has_manage_roles_perm(ctx: &crate::Context<'_>) -> bool {
         .is_some_and(|p| p.manage_roles())
 }
 
+/// Whether the command is used in the home guild, replies with an error otherwise.
+/// Settings and data shared by every guild can't be changed or viewed from partner guilds.
+pub async fn in_home_guild(ctx: &crate::Context<'_>) -> Result<bool, CommandError> {
+    if ctx.guild_id() == Some(ctx.data().home_guild_id) {
+        return Ok(true);
+    }
+
+    ctx.reply(":x: This can only be used from the main server.")
+        .await?;
+
+    Ok(false)
+}
+
+// longest duration accepted for grants and overrides, 10 years
+const MAX_DURATION: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+/// Parses durations like `30m`, `12h` or `1w2d`, up to 10 years
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    let mut total: u64 = 0;
+    let mut number = String::new();
+
+    for c in input.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+
+        let value: u64 = number.parse().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        number.clear();
+    }
+
+    // trailing number without a unit
+    if !number.is_empty() || total == 0 {
+        return None;
+    }
+
+    Some(Duration::from_secs(total)).filter(|x| *x <= MAX_DURATION)
+}
+
 pub async fn reply_ephemeral<'a>(
     ctx: &'a crate::Context<'_>,
     content: impl Into<String>,
@@ -88,3 +137,40 @@ pub async fn reply_ephemeral<'a>(
     ctx.send(CreateReply::default().content(content).ephemeral(true))
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(
+            parse_duration("12h"),
+            Some(Duration::from_secs(12 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration(" 1W2d "),
+            Some(Duration::from_secs(9 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("3650d"), Some(MAX_DURATION));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("15"), None);
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(parse_duration("-5m"), None);
+    }
+
+    #[test]
+    fn rejects_too_long_durations() {
+        assert_eq!(parse_duration("3651d"), None);
+        assert_eq!(parse_duration("18446744073709551615s"), None);
+        assert_eq!(parse_duration("18446744073709551615w"), None);
+        assert_eq!(parse_duration("99999999999999999999999s"), None);
+    }
+}
@@ -1,11 +1,18 @@
 // Imports typically needed for most commands
 #[allow(unused)]
-pub use super::{bail, has_admin_perm, has_manage_roles_perm, reply_ephemeral, CommandError};
+pub use super::{
+    bail, has_admin_perm, has_manage_roles_perm, in_home_guild, parse_duration, reply_ephemeral,
+    CommandError,
+};
 
 #[allow(unused)]
 pub use crate::{
     logger::*,
     serenity,
-    state::{BotState, RoleRemoveError, RoleSyncError, RoleSyncRequest, RoleSyncRequestData},
+    state::{
+        BotState, GroupError, LinkActor, OverrideMode, RoleDirection, RoleExpr, RoleRemoveError,
+        RoleSyncError, RoleSyncRequest, RoleSyncRequestData, RuleError, SyncOptions, VirtualSource,
+        MAIN_TARGET,
+    },
     Context,
 };
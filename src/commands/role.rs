@@ -1,17 +1,52 @@
+use poise::ChoiceParameter;
+
 use super::prelude::*;
 
-#[poise::command(slash_command, subcommands("add", "remove", "removeid", "list"))]
+#[poise::command(
+    slash_command,
+    subcommands("add", "remove", "removeid", "list", "rule", "group", "virtual_role")
+)]
 pub async fn role(_ctx: Context<'_>) -> Result<(), CommandError> {
     // unreachable
     Ok(())
 }
 
+// the Globed server picked in the command, replies with an error if it isn't configured
+async fn resolve_target(
+    ctx: &Context<'_>,
+    server: Option<String>,
+) -> Result<Option<String>, CommandError> {
+    let target = server.unwrap_or_else(|| MAIN_TARGET.to_owned());
+
+    if !ctx.data().has_target(&target) {
+        ctx.reply(format!(":x: Unknown Globed server `{target}`."))
+            .await?;
+        return Ok(None);
+    }
+
+    Ok(Some(target))
+}
+
+// appended to messages about roles of extra servers
+fn server_suffix(target: &str) -> String {
+    if target == MAIN_TARGET {
+        String::new()
+    } else {
+        format!(" on server `{target}`")
+    }
+}
+
 /// Add a new linked role
 #[poise::command(slash_command)]
 pub async fn add(
     ctx: Context<'_>,
     #[description = "Role to add"] role: serenity::Role,
     #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Which way the role is synced, defaults to Discord to Globed"]
+    direction: Option<RoleDirection>,
+    #[description = "Globed server the role is on, defaults to the main server"] server: Option<
+        String,
+    >,
 ) -> Result<(), CommandError> {
     let state = ctx.data();
 
@@ -20,13 +55,46 @@ pub async fn add(
         return Ok(());
     }
 
-    match state.add_role(role.id.get() as i64, &globed_role_id).await {
+    let direction = direction.unwrap_or(RoleDirection::ToGlobed);
+    let Some(target) = resolve_target(&ctx, server).await? else {
+        return Ok(());
+    };
+    let target = target.as_str();
+
+    if target != MAIN_TARGET && direction == RoleDirection::ToDiscord {
+        ctx.reply(":x: Roles can only be synced to Discord from the main server.")
+            .await?;
+        return Ok(());
+    }
+
+    match state
+        .add_role(
+            role.guild_id,
+            role.id.get() as i64,
+            &role.name,
+            &globed_role_id,
+            direction,
+            target,
+        )
+        .await
+    {
         Ok(()) => {
-            ctx.reply(format!(
-                "✅ Successfully linked role <@&{}> to globed role `{}`.",
-                role.id, globed_role_id
-            ))
-            .await?
+            let message = match direction {
+                RoleDirection::ToGlobed if target != MAIN_TARGET => format!(
+                    "✅ Successfully linked role <@&{}> to globed role `{}` on server `{target}`.",
+                    role.id, globed_role_id
+                ),
+                RoleDirection::ToGlobed => format!(
+                    "✅ Successfully linked role <@&{}> to globed role `{}`.",
+                    role.id, globed_role_id
+                ),
+                RoleDirection::ToDiscord => format!(
+                    "✅ Successfully linked globed role `{}` to role <@&{}>. Users with the role on Globed will receive it on Discord during the next full sync.",
+                    globed_role_id, role.id
+                ),
+            };
+
+            ctx.reply(message).await?
         }
         Err(e) => {
             ctx.reply(format!(":x: Failed to add the role: {e}"))
@@ -75,6 +143,9 @@ pub async fn remove(
 pub async fn removeid(
     ctx: Context<'_>,
     #[description = "Role to remove"] globed_role_id: String,
+    #[description = "Globed server the role is on, defaults to the main server"] server: Option<
+        String,
+    >,
 ) -> Result<(), CommandError> {
     let state = ctx.data();
 
@@ -83,7 +154,13 @@ pub async fn removeid(
         return Ok(());
     }
 
-    match state.remove_role_by_globed_id(&globed_role_id).await {
+    let guild_id = ctx.guild_id().unwrap();
+    let target = server.as_deref().unwrap_or(MAIN_TARGET);
+
+    match state
+        .remove_role_by_globed_id(guild_id, target, &globed_role_id)
+        .await
+    {
         Ok(()) => {
             ctx.reply(format!(
                 "✅ Successfully removed role `{}`.",
@@ -99,7 +176,7 @@ pub async fn removeid(
         }
 
         Err(RoleRemoveError::NotFound) => {
-            ctx.reply(":x: Role is not currently linked to any role on Globed.")
+            ctx.reply(":x: Role is not currently linked to any role on this server.")
                 .await?;
         }
     };
@@ -117,11 +194,71 @@ pub async fn list(ctx: Context<'_>) -> Result<(), CommandError> {
         return Ok(());
     }
 
+    let virtual_roles = match state.get_all_virtual_roles().await {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the list of virtual roles: {e}"))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let rules = match state.get_all_rules().await {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the list of rules: {e}"))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let guild_id = ctx.guild_id().unwrap().get() as i64;
+
+    let server_tag = |target: &str| {
+        if target == MAIN_TARGET {
+            String::new()
+        } else {
+            format!(" [{target}]")
+        }
+    };
+
     match state.get_all_roles().await {
         Ok(roles) => {
             let mut msg = "List of linked roles on this server:\n\n".to_owned();
-            for role in roles {
-                msg += &format!("* <@&{}> - `{}`\n", role.discord_id, role.id);
+            for role in roles.into_iter().filter(|x| x.guild_id == guild_id) {
+                let arrow = if role.is_reverse() { "<-" } else { "->" };
+                let server = server_tag(&role.target);
+
+                match &role.discord_name {
+                    Some(name) => {
+                        msg += &format!(
+                            "* <@&{}> ({name}) {arrow} `{}`{server}\n",
+                            role.discord_id, role.id
+                        )
+                    }
+                    None => {
+                        msg += &format!("* <@&{}> {arrow} `{}`{server}\n", role.discord_id, role.id)
+                    }
+                }
+            }
+
+            if !virtual_roles.is_empty() {
+                msg += "\nVirtual roles:\n\n";
+                for role in virtual_roles {
+                    msg += &format!("* {role} -> `{}`{}\n", role.id, server_tag(&role.target));
+                }
+            }
+
+            if !rules.is_empty() {
+                msg += "\nRules:\n\n";
+                for rule in rules {
+                    msg += &format!(
+                        "* {} -> `{}`{}\n",
+                        rule.expression,
+                        rule.id,
+                        server_tag(&rule.target)
+                    );
+                }
             }
 
             ctx.reply(msg).await?;
@@ -134,3 +271,529 @@ pub async fn list(ctx: Context<'_>) -> Result<(), CommandError> {
 
     Ok(())
 }
+
+#[poise::command(
+    slash_command,
+    subcommands("rule_add", "rule_edit", "rule_remove", "rule_test")
+)]
+pub async fn rule(_ctx: Context<'_>) -> Result<(), CommandError> {
+    // unreachable
+    Ok(())
+}
+
+/// Grant a Globed role to everyone matching a rule, e.g. `@Booster OR @Patron`
+#[poise::command(slash_command, rename = "add")]
+pub async fn rule_add(
+    ctx: Context<'_>,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Roles combined with AND, OR, NOT and parentheses"] rule: String,
+    #[description = "Globed server the role is on, defaults to the main server"] server: Option<
+        String,
+    >,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(target) = resolve_target(&ctx, server).await? else {
+        return Ok(());
+    };
+
+    match state.add_rule(&target, &globed_role_id, &rule).await {
+        Ok(expr) => {
+            ctx.reply(format!(
+                "✅ Successfully added a rule for globed role `{globed_role_id}`{}: {expr}",
+                server_suffix(&target)
+            ))
+            .await?;
+        }
+        Err(RuleError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to add the rule: {e}"))
+                .await?;
+            bail!("Adding a rule failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Change the rule of a Globed role
+#[poise::command(slash_command, rename = "edit")]
+pub async fn rule_edit(
+    ctx: Context<'_>,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Roles combined with AND, OR, NOT and parentheses"] rule: String,
+    #[description = "Globed server the role is on, defaults to the main server"] server: Option<
+        String,
+    >,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(target) = resolve_target(&ctx, server).await? else {
+        return Ok(());
+    };
+
+    match state.edit_rule(&target, &globed_role_id, &rule).await {
+        Ok(expr) => {
+            ctx.reply(format!(
+                "✅ Successfully changed the rule for globed role `{globed_role_id}`{}: {expr}",
+                server_suffix(&target)
+            ))
+            .await?;
+        }
+        Err(RuleError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to change the rule: {e}"))
+                .await?;
+            bail!("Editing a rule failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the rule of a Globed role
+#[poise::command(slash_command, rename = "remove")]
+pub async fn rule_remove(
+    ctx: Context<'_>,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Globed server the role is on, defaults to the main server"] server: Option<
+        String,
+    >,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(target) = resolve_target(&ctx, server).await? else {
+        return Ok(());
+    };
+
+    match state.remove_rule(&target, &globed_role_id).await {
+        Ok(()) => {
+            ctx.reply(format!(
+                "✅ Successfully removed the rule for globed role `{globed_role_id}`{}.",
+                server_suffix(&target)
+            ))
+            .await?;
+        }
+        Err(RuleError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to remove the rule: {e}"))
+                .await?;
+            bail!("Removing a rule failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check whether a rule is valid, and whether a member matches it
+#[poise::command(slash_command, rename = "test")]
+pub async fn rule_test(
+    ctx: Context<'_>,
+    #[description = "Roles combined with AND, OR, NOT and parentheses"] rule: String,
+    #[description = "Member to test the rule against, defaults to you"] member: Option<
+        serenity::Member,
+    >,
+) -> Result<(), CommandError> {
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    let expr = match RoleExpr::parse(&rule) {
+        Ok(x) => x,
+        Err(e) => {
+            ctx.reply(format!(":x: Invalid rule: {e}")).await?;
+            return Ok(());
+        }
+    };
+
+    let member = match member {
+        Some(x) => x,
+        None => ctx.author_member().await.unwrap().into_owned(),
+    };
+
+    let result = if expr.eval(&member.roles) {
+        "matches"
+    } else {
+        "doesn't match"
+    };
+
+    ctx.reply(format!(
+        "✅ Rule is valid: {expr}\n\n<@{}> {result} this rule.",
+        member.user.id
+    ))
+    .await?;
+
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    subcommands(
+        "group_create",
+        "group_delete",
+        "group_add",
+        "group_remove",
+        "group_list"
+    )
+)]
+pub async fn group(_ctx: Context<'_>) -> Result<(), CommandError> {
+    // unreachable
+    Ok(())
+}
+
+/// Create an exclusive group, users only get the highest-tier Globed role of a group
+#[poise::command(slash_command, rename = "create")]
+pub async fn group_create(
+    ctx: Context<'_>,
+    #[description = "Name of the group"] name: String,
+    #[description = "Globed server the roles of the group are on, defaults to the main server"]
+    server: Option<String>,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(target) = resolve_target(&ctx, server).await? else {
+        return Ok(());
+    };
+
+    match state.create_group(&name, &target).await {
+        Ok(()) => {
+            ctx.reply(format!(
+                "✅ Successfully created group `{name}`{}.",
+                server_suffix(&target)
+            ))
+            .await?;
+        }
+        Err(GroupError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to create the group: {e}"))
+                .await?;
+            bail!("Creating a group failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete an exclusive group, its roles are synced independently again
+#[poise::command(slash_command, rename = "delete")]
+pub async fn group_delete(
+    ctx: Context<'_>,
+    #[description = "Name of the group"] name: String,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    match state.delete_group(&name).await {
+        Ok(()) => {
+            ctx.reply(format!("✅ Successfully deleted group `{name}`."))
+                .await?;
+        }
+        Err(GroupError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to delete the group: {e}"))
+                .await?;
+            bail!("Deleting a group failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Add a Globed role to an exclusive group
+#[poise::command(slash_command, rename = "add")]
+pub async fn group_add(
+    ctx: Context<'_>,
+    #[description = "Name of the group"] name: String,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Tier of the role, 1 is the highest. Defaults to the lowest tier"]
+    #[min = 1]
+    position: Option<i64>,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    match state
+        .add_role_to_group(&name, &globed_role_id, position)
+        .await
+    {
+        Ok(position) => {
+            ctx.reply(format!(
+                "✅ Successfully added globed role `{globed_role_id}` to group `{name}` at position {position}."
+            ))
+            .await?;
+        }
+        Err(GroupError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to add the role to the group: {e}"))
+                .await?;
+            bail!("Adding a role to a group failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove a Globed role from its exclusive group
+#[poise::command(slash_command, rename = "remove")]
+pub async fn group_remove(
+    ctx: Context<'_>,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Globed server the role is on, defaults to the main server"] server: Option<
+        String,
+    >,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(target) = resolve_target(&ctx, server).await? else {
+        return Ok(());
+    };
+
+    match state.remove_role_from_group(&target, &globed_role_id).await {
+        Ok(()) => {
+            ctx.reply(format!(
+                "✅ Successfully removed globed role `{globed_role_id}`{} from its group.",
+                server_suffix(&target)
+            ))
+            .await?;
+        }
+        Err(GroupError::Database(e)) => {
+            ctx.reply(format!(":x: Failed to remove the role from the group: {e}"))
+                .await?;
+            bail!("Removing a role from a group failed: {e}");
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: {e}")).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// List all exclusive groups and the order of their roles
+#[poise::command(slash_command, rename = "list")]
+pub async fn group_list(ctx: Context<'_>) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_manage_roles_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    match state.get_all_groups().await {
+        Ok(groups) if groups.is_empty() => {
+            ctx.reply("There are no exclusive groups on this server.")
+                .await?;
+        }
+        Ok(groups) => {
+            let mut msg = "List of exclusive groups, from the highest tier:\n".to_owned();
+            for (group, roles) in groups {
+                msg += &format!("\n**{}**{}\n", group.name, server_suffix(&group.target));
+
+                if roles.is_empty() {
+                    msg += "* no roles\n";
+                }
+
+                for role in roles {
+                    msg += &format!("{}. `{}`\n", role.position, role.globed_role_id);
+                }
+            }
+
+            ctx.reply(msg).await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to get the list of groups: {e}"))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    rename = "virtual",
+    subcommands("virtual_add", "virtual_remove")
+)]
+pub async fn virtual_role(_ctx: Context<'_>) -> Result<(), CommandError> {
+    // unreachable
+    Ok(())
+}
+
+/// Grant a Globed role based on member state instead of a role, like boosting the server
+#[poise::command(slash_command, rename = "add")]
+pub async fn virtual_add(
+    ctx: Context<'_>,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Member state that grants the role"] source: VirtualSource,
+    #[description = "For boosters, how many days they have to be boosting for"]
+    min_boost_days: Option<u32>,
+    #[description = "Globed server the role is on, defaults to the main server"] server: Option<
+        String,
+    >,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(target) = resolve_target(&ctx, server).await? else {
+        return Ok(());
+    };
+
+    match state
+        .add_virtual_role(
+            &target,
+            &globed_role_id,
+            source,
+            min_boost_days.unwrap_or(0),
+        )
+        .await
+    {
+        Ok(()) => {
+            let condition = match min_boost_days {
+                Some(days) if days > 0 && source == VirtualSource::Booster => {
+                    format!("boosting for {days}+ days")
+                }
+                _ => source.name().to_lowercase(),
+            };
+
+            ctx.reply(format!(
+                "✅ Successfully linked globed role `{globed_role_id}`{} to members that are: {condition}.",
+                server_suffix(&target)
+            ))
+            .await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to add the virtual role: {e}"))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove a virtual role
+#[poise::command(slash_command, rename = "remove")]
+pub async fn virtual_remove(
+    ctx: Context<'_>,
+    #[description = "Role ID on the Globed server"] globed_role_id: String,
+    #[description = "Globed server the role is on, defaults to the main server"] server: Option<
+        String,
+    >,
+) -> Result<(), CommandError> {
+    let state = ctx.data();
+
+    if !has_admin_perm(&ctx).await {
+        ctx.reply(":x: No permission").await?;
+        return Ok(());
+    }
+
+    if !in_home_guild(&ctx).await? {
+        return Ok(());
+    }
+
+    let Some(target) = resolve_target(&ctx, server).await? else {
+        return Ok(());
+    };
+
+    match state.remove_virtual_role(&target, &globed_role_id).await {
+        Ok(true) => {
+            ctx.reply(format!(
+                "✅ Successfully removed virtual role `{globed_role_id}`{}.",
+                server_suffix(&target)
+            ))
+            .await?;
+        }
+        Ok(false) => {
+            ctx.reply(":x: This Globed role is not linked to any virtual role.")
+                .await?;
+        }
+        Err(e) => {
+            ctx.reply(format!(":x: Failed to remove the virtual role: {e}"))
+                .await?;
+            bail!("Virtual role removal failed: {e}");
+        }
+    }
+
+    Ok(())
+}
@@ -8,7 +8,10 @@ pub async fn sync(ctx: Context<'_>) -> Result<(), CommandError> {
 
     ctx.defer().await?;
 
-    match state.sync_roles(&member).await {
+    match state
+        .sync_roles(ctx.http(), &member, SyncOptions::default())
+        .await
+    {
         Ok(roles) => {
             let message =
                 String::from("✅ Successfully synced roles! If you were already online on Globed, please reconnect to the server to see the changes.\n\n")
@@ -18,7 +21,7 @@ pub async fn sync(ctx: Context<'_>) -> Result<(), CommandError> {
         }
 
         Err(RoleSyncError::NotLinked) => {
-            ctx.reply(":x: Not currently linked to any account. Use `/link` to link a GD account.")
+            ctx.reply(":x: Not currently linked to any account. Use `/link` or `/linkstart` to link a GD account.")
                 .await?;
         }
 
@@ -2,13 +2,80 @@ use super::prelude::*;
 
 /// Unlink your Discord account from your GD account
 #[poise::command(slash_command, guild_only = true)]
-pub async fn unlink(ctx: Context<'_>) -> Result<(), CommandError> {
+pub async fn unlink(
+    ctx: Context<'_>,
+    #[description = "GD username or account ID to unlink, if you have multiple accounts linked"]
+    account: Option<String>,
+) -> Result<(), CommandError> {
     let state = ctx.data();
     let member = ctx.author_member().await.unwrap();
 
     ctx.defer().await?;
 
-    match state.unlink_user(member.user.id).await {
+    let (linked_user, alts) = match state.get_all_accounts(member.user.id).await {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            ctx.reply(":x: Not currently linked to any account.")
+                .await?;
+            return Ok(());
+        }
+        Err(e) => {
+            ctx.reply(":x: Failed to unlink your account due to an internal error.")
+                .await?;
+
+            bail!(
+                "Failed to look up accounts of user ({}): {e}",
+                ctx.author().name
+            );
+        }
+    };
+
+    // (account id, username) of every linked account, the main one first
+    let accounts: Vec<(i64, Option<&str>)> = std::iter::once(&linked_user)
+        .map(|x| (x.gd_account_id, x.gd_username.as_deref()))
+        .chain(
+            alts.iter()
+                .map(|x| (x.gd_account_id, x.gd_username.as_deref())),
+        )
+        .collect();
+
+    let result = match account {
+        None if alts.is_empty() => state.unlink_user(member.user.id, LinkActor::User).await,
+
+        None => {
+            let mut message = ":x: You have multiple GD accounts linked. Pick the one to unlink with the `account` option:\n".to_owned();
+
+            message += &format!("* {} (main account)\n", linked_user.account_display());
+            for alt in &alts {
+                message += &format!("* {}\n", alt.account_display());
+            }
+
+            ctx.reply(message).await?;
+            return Ok(());
+        }
+
+        Some(query) => {
+            let query = query.trim();
+
+            let found = accounts.iter().find(|(id, name)| {
+                id.to_string() == query || name.is_some_and(|x| x.eq_ignore_ascii_case(query))
+            });
+
+            let Some((account_id, _)) = found else {
+                ctx.reply(format!(
+                    ":x: None of your linked GD accounts match `{query}`."
+                ))
+                .await?;
+                return Ok(());
+            };
+
+            state
+                .unlink_account(member.user.id, *account_id as i32, LinkActor::User)
+                .await
+        }
+    };
+
+    match result {
         Ok(()) => {
             ctx.reply("Successfully unlinked the account! If you were connected, you might have to reconnect to Globed to link again.").await?;
         }
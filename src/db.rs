@@ -3,7 +3,11 @@
 #[derive(Clone, Debug)]
 pub struct Role {
     pub id: String,
+    pub guild_id: i64,
+    pub target: String,
     pub discord_id: i64,
+    pub direction: String,
+    pub discord_name: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -11,4 +15,120 @@ pub struct LinkedUser {
     #[allow(unused)]
     pub id: i64,
     pub gd_account_id: i64,
+    pub gd_username: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkedAlt {
+    pub gd_account_id: i64,
+    pub user_id: i64,
+    pub gd_username: Option<String>,
+    pub linked_at: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutboxEntry {
+    pub seq: i64,
+    pub account_id: i64,
+    pub target: String,
+    pub keep: String,
+    pub remove: String,
+    pub attempts: i64,
+    #[allow(unused)]
+    pub next_attempt: i64,
+    pub version: i64,
+    #[allow(unused)]
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncedRoles {
+    pub gd_account_id: i64,
+    pub roles: String,
+    pub synced_at: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct MissingMember {
+    pub id: i64,
+    pub detected_at: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoleRule {
+    pub id: String,
+    pub target: String,
+    pub expression: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoleGroup {
+    pub name: String,
+    pub target: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoleGroupMember {
+    pub globed_role_id: String,
+    pub target: String,
+    pub group_name: String,
+    pub position: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct VirtualRole {
+    pub id: String,
+    pub target: String,
+    pub source: String,
+    pub min_boost_days: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoleGrant {
+    pub user_id: i64,
+    pub globed_role_id: String,
+    pub expires_at: i64,
+    pub granted_by: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoleOverride {
+    pub user_id: i64,
+    pub globed_role_id: String,
+    pub keep: bool,
+    pub reason: String,
+    pub created_by: i64,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Guild {
+    pub id: i64,
+    pub name: Option<String>,
+    pub added_at: i64,
+    pub max_accounts: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkEvent {
+    #[allow(unused)]
+    pub id: i64,
+    pub kind: String,
+    pub actor_id: Option<i64>,
+    pub user_id: Option<i64>,
+    pub gd_account_id: Option<i64>,
+    pub gd_username: Option<String>,
+    pub created_at: i64,
+    pub from_user_id: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkAttempts {
+    pub kind: String,
+    pub key: String,
+    pub failures: i64,
+    pub lockouts: i64,
+    pub locked_until: Option<i64>,
+    pub last_attempt: i64,
 }
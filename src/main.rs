@@ -1,8 +1,8 @@
-use std::env;
+use std::{env, sync::Arc};
 
 pub use poise::serenity_prelude as serenity;
 
-use serenity::prelude::*;
+use serenity::{all::RoleId, prelude::*};
 
 mod commands;
 mod db;
@@ -11,11 +11,11 @@ mod state;
 
 use commands::CommandError;
 use logger::*;
-use state::{BotState, RoleSyncError};
+use state::{BotState, RoleSyncError, SyncOptions};
 
-pub type Context<'a> = poise::Context<'a, BotState, CommandError>;
+pub type Context<'a> = poise::Context<'a, Arc<BotState>, CommandError>;
 
-async fn on_error(error: poise::FrameworkError<'_, BotState, CommandError>) {
+async fn on_error(error: poise::FrameworkError<'_, Arc<BotState>, CommandError>) {
     match error {
         poise::FrameworkError::Setup { error, .. } => panic!("Failed to start bot: {:?}", error),
         poise::FrameworkError::Command { error, ctx, .. } => {
@@ -30,63 +30,158 @@ async fn on_error(error: poise::FrameworkError<'_, BotState, CommandError>) {
 }
 
 async fn event_handler(
-    _ctx: &serenity::Context,
+    ctx: &serenity::Context,
     event: &serenity::FullEvent,
-    _framework: poise::FrameworkContext<'_, BotState, CommandError>,
-    state: &BotState,
+    _framework: poise::FrameworkContext<'_, Arc<BotState>, CommandError>,
+    state: &Arc<BotState>,
 ) -> Result<(), CommandError> {
     match event {
         serenity::FullEvent::GuildMemberUpdate {
             old_if_available,
             new: Some(new),
             event: _event,
-        } => {
+        } if state.is_allowed_guild(new.guild_id) => {
             // check for the roles
-            let mut should_sync = false;
-            {
-                let watched = state.watched_roles.read();
-
-                if let Some(old_user) = old_if_available {
-                    // iterate over all watched roles, see if anything changed
-                    for role in &*watched {
-                        if new.roles.contains(role) != old_user.roles.contains(role) {
-                            should_sync = true;
-                            break;
-                        }
-                    }
-                } else {
-                    should_sync = true;
-                }
-            }
+            let should_sync = if let Some(old_user) = old_if_available {
+                // see which watched roles changed
+                let changed: Vec<RoleId> = state
+                    .watched_roles
+                    .read()
+                    .iter()
+                    .filter(|role| new.roles.contains(role) != old_user.roles.contains(role))
+                    .copied()
+                    .collect();
+
+                // ignore changes the bot made itself while syncing roles from Globed
+                !state
+                    .filter_own_role_changes(new.user.id, changed)
+                    .is_empty()
+                    || state.virtual_state_changed(old_user, new)
+            } else {
+                true
+            };
 
             if should_sync {
-                match state.sync_roles(new).await {
-                    Ok(_) | Err(RoleSyncError::NotLinked) => {}
-                    Err(err) => {
-                        return Err(CommandError::other(format!(
-                            "Failed to auto sync user roles: {err}"
-                        )));
-                    }
-                }
+                // batched together with other changes and sent by the sync scheduler
+                state.schedule_sync(new.clone());
             }
         }
 
         serenity::FullEvent::GuildMemberRemoval {
-            guild_id: _guild_id,
+            guild_id,
             user,
             member_data_if_available: _member,
-        } => {
-            // if a user left, unlink and remove them
-            match state.unlink_user(user.id).await {
+        } if state.is_allowed_guild(*guild_id) => {
+            // if a user left every guild, unlink and remove them, or only remove their roles if they have some time to come back
+            match state
+                .handle_member_removal(&ctx.http, *guild_id, user.id)
+                .await
+            {
                 Ok(()) | Err(RoleSyncError::NotLinked) => {}
+                Err(err) if err.is_retryable() => {
+                    warn!("Failed to remove roles of a user that left a guild, queued for a retry: {err}");
+                }
+                Err(err) => {
+                    return Err(CommandError::other(format!(
+                        "Failed to unlink user that left every guild: {err}"
+                    )));
+                }
+            }
+        }
+
+        serenity::FullEvent::GuildMemberAddition { new_member }
+            if state.is_allowed_guild(new_member.guild_id) =>
+        {
+            // give back the roles of linked users that left recently
+            match state.handle_member_rejoin(&ctx.http, new_member).await {
+                Ok(true) => info!(
+                    "Restored the link of {} after they rejoined",
+                    new_member.user.name
+                ),
+                Ok(false) => {}
+                Err(err) if err.is_retryable() => {
+                    warn!("Failed to sync roles of a member that rejoined the guild, queued for a retry: {err}");
+                }
+                Err(err) => {
+                    return Err(CommandError::other(format!(
+                        "Failed to sync roles of a member that rejoined the guild: {err}"
+                    )));
+                }
+            }
+        }
+
+        serenity::FullEvent::GuildRoleDelete {
+            guild_id,
+            removed_role_id,
+            removed_role_data_if_available: _removed,
+        } if state.is_allowed_guild(*guild_id) => {
+            // drop mappings of the deleted role, otherwise every sync keeps removing it from everyone
+            match state.handle_role_deleted(*guild_id, *removed_role_id).await {
+                Ok(Some(report)) => {
+                    let name = report.name.unwrap_or_else(|| removed_role_id.to_string());
+
+                    let mut message = format!(
+                        ":warning: Discord role `{name}` was deleted, so it was unlinked from globed roles {}. Removed them from {} linked accounts.",
+                        report
+                            .globed_roles
+                            .iter()
+                            .map(|x| format!("`{x}`"))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        report.stripped.users_stripped
+                    );
+
+                    if report.stripped.chunks_queued > 0 {
+                        message += &format!(
+                            " {} chunks failed to sync and will be retried in the background.",
+                            report.stripped.chunks_queued
+                        );
+                    }
+
+                    if report.stripped.chunks_failed > 0 {
+                        message += &format!(
+                            " {} chunks were rejected by the server, those users keep the roles until they are removed on Globed.",
+                            report.stripped.chunks_failed
+                        );
+                    }
+
+                    info!("{message}");
+                    state.notify_admins(&ctx.http, message).await;
+                }
+                Ok(None) => {}
                 Err(err) => {
+                    state
+                        .notify_admins(
+                            &ctx.http,
+                            format!(":x: A linked discord role ({removed_role_id}) was deleted, but cleaning up its mappings failed: {err}"),
+                        )
+                        .await;
+
                     return Err(CommandError::other(format!(
-                        "Failed to unlink user that left the guild: {err}"
+                        "Failed to clean up mappings of a deleted role: {err}"
                     )));
                 }
             }
         }
 
+        serenity::FullEvent::InteractionCreate {
+            interaction: serenity::Interaction::Component(interaction),
+        } => {
+            // confirm and deny buttons of links started in-game, these are sent in direct messages
+            if let Err(err) = state.handle_link_interaction(ctx, interaction).await {
+                warn!("Failed to handle a link confirmation: {err}");
+            }
+        }
+
+        serenity::FullEvent::GuildRoleUpdate {
+            old_data_if_available: _old,
+            new,
+        } if state.is_allowed_guild(new.guild_id) => {
+            if let Err(err) = state.handle_role_updated(new).await {
+                warn!("Failed to update the name of a linked role: {err}");
+            }
+        }
+
         _ => {}
     }
 
@@ -138,21 +233,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // start the discord bot
-    let state = BotState::new(db).await;
+    let state = Arc::new(BotState::new(db).await);
 
     let options = poise::FrameworkOptions {
         commands: vec![
             commands::admin(),
             commands::link(),
+            commands::linkstart(),
             commands::unlink(),
             commands::role(),
             commands::sync(),
         ],
         on_error: |error| Box::pin(on_error(error)),
         command_check: Some(|ctx| {
-            // only allow from a specific guild
+            // only allow from registered guilds
             Box::pin(async move {
-                if !ctx.guild_id().is_some_and(|g| g == ctx.data().guild_id) {
+                if !ctx
+                    .guild_id()
+                    .is_some_and(|g| ctx.data().is_allowed_guild(g))
+                {
                     return Ok(false);
                 }
 
@@ -175,10 +274,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 poise::builtins::register_in_guild(
                     ctx,
                     &framework.options().commands,
-                    state.guild_id,
+                    state.home_guild_id,
                 )
                 .await?;
 
+                // other guilds shouldn't stop the bot from starting, the bot could have been kicked from them
+                for guild_id in state.allowed_guilds() {
+                    if guild_id == state.home_guild_id {
+                        continue;
+                    }
+
+                    if let Err(err) = poise::builtins::register_in_guild(
+                        ctx,
+                        &framework.options().commands,
+                        guild_id,
+                    )
+                    .await
+                    {
+                        warn!("Failed to register commands in guild {guild_id}: {err}");
+                    }
+                }
+
+                // retry syncs that failed while the server was unreachable
+                tokio::spawn(state.clone().run_outbox_worker());
+                tokio::spawn(state.clone().run_sync_scheduler(ctx.http.clone()));
+                tokio::spawn(state.clone().run_reconciliation(ctx.http.clone()));
+                tokio::spawn(state.clone().run_departure_expiry());
+                tokio::spawn(state.clone().run_expiry_task(ctx.http.clone()));
+                tokio::spawn(state.clone().run_link_listener(ctx.http.clone()));
+
                 let skip_sync = env::var("BOT_SKIP_SYNC_ALL")
                     .ok()
                     .map(|x| x != "0")
@@ -187,9 +311,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if !skip_sync {
                     info!("Attempting to sync all members.. (this may take some time)");
 
-                    match state.sync_all_members(&ctx.http).await {
-                        Ok(count) => {
-                            info!("Sync finished! Total {} users synced.", count);
+                    match state
+                        .sync_all_members(&ctx.http, SyncOptions::default())
+                        .await
+                    {
+                        Ok(report) => {
+                            info!("Sync finished! {report}");
                         }
                         Err(e) => {
                             warn!("Failed to sync roles of members: {e}");
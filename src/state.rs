@@ -1,34 +1,175 @@
-use std::{env, fmt::Display, num::NonZeroI32};
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Display,
+    net::SocketAddr,
+    num::NonZeroI32,
+    sync::atomic::{AtomicBool, AtomicI64},
+    time::{Duration, Instant, SystemTime},
+};
 
 use crate::{db::*, serenity, Context};
 use log::{debug, error, warn};
-use parking_lot::RwLock as SyncRwLock;
+use parking_lot::{Mutex as SyncMutex, RwLock as SyncRwLock};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use serenity::all::{GuildId, Member, RoleId, UserId};
+use serenity::all::{ChannelId, GuildId, Member, RoleId, UserId};
+use tokio::sync::Notify;
+
+mod alts;
+mod departed;
+mod drift;
+mod dry_run;
+mod full_sync;
+mod grants;
+mod groups;
+mod guilds;
+mod history;
+mod link_callback;
+mod link_codes;
+mod lockouts;
+mod missing;
+mod names;
+mod outbox;
+mod overrides;
+mod reconcile;
+mod reverse;
+mod role_events;
+mod rules;
+mod scheduler;
+mod synced;
+mod targets;
+mod transfer;
+mod virtual_roles;
+
+pub use dry_run::DryRunReport;
+pub use full_sync::SyncReport;
+pub use groups::GroupError;
+pub use guilds::GuildError;
+pub use history::LinkActor;
+pub use link_codes::{format_code, LinkCodeError};
+pub use lockouts::AttemptKind;
+pub use missing::MissingMemberPolicy;
+pub use overrides::OverrideMode;
+pub use reconcile::FullSyncStatus;
+pub use reverse::ReverseSyncReport;
+pub use rules::{RoleExpr, RuleError};
+use targets::TargetRequestKind;
+pub use targets::{ServerTarget, TargetRoles, MAIN_TARGET};
+pub use transfer::TransferError;
+pub use virtual_roles::VirtualSource;
 
 pub struct BotState {
     pub http_client: reqwest::Client,
-    pub base_url: String,
-    pub server_password: String,
+    // globed servers roles get synced to, the main server always comes first
+    pub targets: Vec<ServerTarget>,
+    // last failed sync of every extra server, name -> (timestamp, error)
+    target_errors: SyncMutex<HashMap<String, (i64, String)>>,
     pub database: sqlx::SqlitePool,
-    pub guild_id: GuildId,
+    // guild from BOT_SERVER_ID, it's always registered and the guild registry is managed from there
+    pub home_guild_id: GuildId,
+    // every registered guild, including the home guild
+    allowed_guilds: SyncRwLock<Vec<GuildId>>,
+    // channel where the bot posts notices for admins, if configured
+    pub admin_channel: Option<ChannelId>,
 
     pub watched_roles: SyncRwLock<Vec<RoleId>>,
+    // parsed rules of every server, keyed by the globed role they grant
+    role_rules: SyncRwLock<HashMap<String, Vec<(String, RoleExpr)>>>,
+    // exclusive group and position of every grouped globed role, by server
+    role_groups: SyncRwLock<HashMap<String, groups::GroupTiers>>,
+    // globed roles granted by member state instead of a discord role, by server
+    virtual_roles: SyncRwLock<HashMap<String, Vec<(String, virtual_roles::VirtualCondition)>>>,
+    // temporary grants of every user, discord id -> (globed role, expiry timestamp)
+    role_grants: SyncRwLock<HashMap<i64, Vec<(String, i64)>>>,
+    // manual overrides of every user, by discord id
+    role_overrides: SyncRwLock<HashMap<i64, Vec<overrides::CachedOverride>>>,
+
+    // wakes up the outbox worker once the server is reachable again
+    outbox_wakeup: Notify,
+
+    // members whose roles changed and are waiting to be synced
+    pending_syncs: SyncMutex<HashMap<UserId, Member>>,
+    sync_wakeup: Notify,
+    sync_debounce: Duration,
+
+    // max amount of users sent in a single request during a full sync
+    sync_chunk_size: usize,
+
+    full_sync_running: AtomicBool,
+    pub last_full_sync: SyncMutex<Option<FullSyncStatus>>,
+    reconcile_interval: Duration,
+    next_reconcile_at: AtomicI64,
+    // maintenance pause, stops periodic reconciliation
+    paused: AtomicBool,
+    missing_member_policy: MissingMemberPolicy,
+    // how long the link of a member that left is kept, zero unlinks them right away
+    departure_grace: Duration,
+
+    // discord roles the bot itself just added or removed during a reverse sync,
+    // so the member update handler doesn't treat them as changes made by someone else
+    own_role_changes: SyncMutex<HashMap<(UserId, RoleId), Instant>>,
+
+    // address the Globed server reaches the bot on when players start linking in-game, if enabled
+    link_listen_addr: Option<SocketAddr>,
+    // whether users get link codes from the bot to enter in-game, instead of codes shown by the game
+    bot_link_codes: bool,
+    // links started in-game that wait for the user to confirm them
+    link_confirmations: SyncMutex<HashMap<u64, link_callback::PendingConfirmation>>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Copy, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum RoleDirection {
+    #[name = "Discord to Globed"]
+    ToGlobed,
+    #[name = "Globed to Discord"]
+    ToDiscord,
+}
+
+impl RoleDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ToGlobed => "to_globed",
+            Self::ToDiscord => "to_discord",
+        }
+    }
+}
+
+impl Role {
+    pub fn direction(&self) -> RoleDirection {
+        match self.direction.as_str() {
+            "to_discord" => RoleDirection::ToDiscord,
+            _ => RoleDirection::ToGlobed,
+        }
+    }
+
+    /// Whether the Globed role is the source of truth, and the Discord role follows it
+    pub fn is_reverse(&self) -> bool {
+        self.direction() == RoleDirection::ToDiscord
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct RoleSyncRequest {
     pub account_id: i32,
     pub keep: Vec<String>,
     pub remove: Vec<String>,
+    /// Roles on the extra servers, sent to each of them separately
+    #[serde(skip)]
+    pub targets: Vec<TargetRoles>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub struct RoleSyncRequestData {
     pub users: Vec<RoleSyncRequest>,
 }
 
+#[derive(Clone, Copy, Default)]
+pub struct SyncOptions {
+    /// Send the request even if the server already has the same roles
+    pub force: bool,
+}
+
 pub enum RoleSyncError {
     NotLinked,
     Database(sqlx::Error),
@@ -36,6 +177,24 @@ pub enum RoleSyncError {
     #[allow(unused)]
     InternalError(&'static str),
     ServerUpdate((StatusCode, String)),
+    MemberFetch(serenity::Error),
+    SyncInProgress,
+    ServerMalformedResponse(serde_json::Error, String),
+}
+
+impl RoleSyncError {
+    /// Whether the failure is likely temporary (server down, overloaded, etc.) and the request is worth retrying
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::ServerRequest(_) => true,
+            Self::ServerUpdate((code, _)) => {
+                code.is_server_error()
+                    || *code == StatusCode::TOO_MANY_REQUESTS
+                    || *code == StatusCode::REQUEST_TIMEOUT
+            }
+            _ => false,
+        }
+    }
 }
 
 impl From<sqlx::Error> for RoleSyncError {
@@ -57,6 +216,11 @@ impl Display for RoleSyncError {
             Self::ServerUpdate((code, message)) => {
                 write!(f, "Server returned error (code {code}): {message}")
             }
+            Self::MemberFetch(e) => write!(f, "Failed to fetch guild members: {e}"),
+            Self::SyncInProgress => f.write_str("Another full sync is already in progress"),
+            Self::ServerMalformedResponse(e, json) => {
+                write!(f, "Server returned unparsable data ({e}): {json}")
+            }
         }
     }
 }
@@ -85,6 +249,8 @@ pub enum LinkError {
     Database(sqlx::Error),
     RoleSync(RoleSyncError, UserLookupResponse),
     LinkedToOther(String),
+    // too many failed attempts, holds when linking gets unlocked
+    LockedOut(i64),
 }
 
 impl From<sqlx::Error> for LinkError {
@@ -99,24 +265,88 @@ pub struct UserLookupResponse {
     pub name: String,
 }
 
+// discord user the GD account is linked to, either as the main account or as an alt
+async fn find_account_owner<'e, E>(
+    executor: E,
+    account_id: i32,
+) -> Result<Option<UserId>, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    let account_id = account_id as i64;
+
+    let res = sqlx::query_scalar!(
+        r#"SELECT id AS "id!: i64" FROM linked_users WHERE gd_account_id = ?
+        UNION SELECT user_id FROM linked_alts WHERE gd_account_id = ?"#,
+        account_id,
+        account_id
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(res.map(|id| UserId::new(id as u64)))
+}
+
 impl BotState {
     pub async fn new(database: sqlx::SqlitePool) -> Self {
-        let mut base_url =
-            env::var("BOT_BASE_URL").expect("'BOT_BASE_URL' env variable not passed");
-        if base_url.ends_with('/') {
-            base_url.pop();
-        }
-
-        let server_password =
-            env::var("BOT_SERVER_PASSWORD").expect("'BOT_SERVER_PASSWORD' env variable not passed");
-
-        let guild_id = GuildId::new(
+        let home_guild_id = GuildId::new(
             env::var("BOT_SERVER_ID")
                 .expect("Expected BOT_SERVER_ID in environment")
                 .parse()
                 .expect("BOT_SERVER_ID must be an integer"),
         );
 
+        let admin_channel = env::var("BOT_ADMIN_CHANNEL")
+            .ok()
+            .map(|x| ChannelId::new(x.parse().expect("BOT_ADMIN_CHANNEL must be an integer")));
+
+        let sync_debounce = Duration::from_millis(
+            env::var("BOT_SYNC_DEBOUNCE_MS")
+                .ok()
+                .map(|x| x.parse().expect("BOT_SYNC_DEBOUNCE_MS must be an integer"))
+                .unwrap_or(3000),
+        );
+
+        let sync_chunk_size = env::var("BOT_SYNC_CHUNK_SIZE")
+            .ok()
+            .map(|x| x.parse().expect("BOT_SYNC_CHUNK_SIZE must be an integer"))
+            .unwrap_or(500usize)
+            .max(1);
+
+        // 0 disables periodic syncing
+        let reconcile_interval = Duration::from_secs(
+            env::var("BOT_SYNC_INTERVAL")
+                .ok()
+                .map(|x| x.parse().expect("BOT_SYNC_INTERVAL must be an integer"))
+                .unwrap_or(6 * 60 * 60),
+        );
+
+        // 0 unlinks members as soon as they leave
+        let departure_grace = Duration::from_secs(
+            env::var("BOT_DEPARTURE_GRACE_PERIOD")
+                .ok()
+                .map(|x| {
+                    x.parse()
+                        .expect("BOT_DEPARTURE_GRACE_PERIOD must be an integer")
+                })
+                .unwrap_or(0),
+        );
+
+        let link_listen_addr = env::var("BOT_LINK_LISTEN_ADDR").ok().map(|x| {
+            x.parse()
+                .expect("BOT_LINK_LISTEN_ADDR must be an address like 0.0.0.0:8080")
+        });
+
+        // codes from the bot are confirmed by the Globed server through the listener
+        let bot_link_codes = env::var("BOT_LINK_CODES")
+            .ok()
+            .map(|x| x.parse().expect("BOT_LINK_CODES must be true or false"))
+            .unwrap_or(false);
+
+        if bot_link_codes && link_listen_addr.is_none() {
+            panic!("BOT_LINK_CODES requires BOT_LINK_LISTEN_ADDR to be set");
+        }
+
         // fetch roles
 
         let ret = Self {
@@ -127,28 +357,71 @@ impl BotState {
                 ))
                 .build()
                 .expect("Failed to create the HTTP client"),
-            base_url,
-            server_password,
+            targets: ServerTarget::all_from_env(),
+            target_errors: SyncMutex::new(HashMap::new()),
             database,
-            guild_id,
+            home_guild_id,
+            allowed_guilds: SyncRwLock::new(Vec::new()),
+            admin_channel,
             watched_roles: SyncRwLock::new(Vec::new()),
+            role_rules: SyncRwLock::new(HashMap::new()),
+            role_groups: SyncRwLock::new(HashMap::new()),
+            virtual_roles: SyncRwLock::new(HashMap::new()),
+            role_grants: SyncRwLock::new(HashMap::new()),
+            role_overrides: SyncRwLock::new(HashMap::new()),
+            outbox_wakeup: Notify::new(),
+            pending_syncs: SyncMutex::new(HashMap::new()),
+            sync_wakeup: Notify::new(),
+            sync_debounce,
+            sync_chunk_size,
+            full_sync_running: AtomicBool::new(false),
+            last_full_sync: SyncMutex::new(None),
+            reconcile_interval,
+            next_reconcile_at: AtomicI64::new(0),
+            paused: AtomicBool::new(false),
+            missing_member_policy: MissingMemberPolicy::from_env(),
+            departure_grace,
+            own_role_changes: SyncMutex::new(HashMap::new()),
+            link_listen_addr,
+            bot_link_codes,
+            link_confirmations: SyncMutex::new(HashMap::new()),
         };
 
-        // get all roles from the database and push them to a vec
-        let roles = ret
-            .get_all_roles()
+        ret.load_maintenance_flag()
             .await
-            .expect("Failed to fetch roles from the database");
+            .expect("Failed to read the maintenance flag from the database");
 
-        let mut watched = ret.watched_roles.write();
-        for role in roles {
-            watched.push(RoleId::new(role.discord_id as u64));
-        }
+        ret.register_home_guild()
+            .await
+            .expect("Failed to register the home guild in the database");
 
-        #[cfg(debug_assertions)]
-        debug!("new watched roles: {:?}", *watched);
+        ret.reload_guilds()
+            .await
+            .expect("Failed to fetch registered guilds from the database");
+
+        ret.reload_rules()
+            .await
+            .expect("Failed to fetch role rules from the database");
+
+        ret.reload_virtual_roles()
+            .await
+            .expect("Failed to fetch virtual roles from the database");
 
-        drop(watched);
+        ret.reload_grants()
+            .await
+            .expect("Failed to fetch role grants from the database");
+
+        ret.reload_overrides()
+            .await
+            .expect("Failed to fetch role overrides from the database");
+
+        ret.reload_groups()
+            .await
+            .expect("Failed to fetch role groups from the database");
+
+        ret.reload_watched_roles()
+            .await
+            .expect("Failed to fetch roles from the database");
 
         ret
     }
@@ -159,6 +432,21 @@ impl BotState {
         Ok(self.get_linked_gd_account(user_id).await?.is_some())
     }
 
+    pub async fn get_linked_user(
+        &self,
+        user_id: UserId,
+    ) -> Result<Option<LinkedUser>, sqlx::Error> {
+        let user_id = user_id.get() as i64;
+
+        sqlx::query_as!(
+            LinkedUser,
+            "SELECT * FROM linked_users WHERE id = ?",
+            user_id
+        )
+        .fetch_optional(&self.database)
+        .await
+    }
+
     pub async fn get_linked_gd_account(
         &self,
         user_id: UserId,
@@ -184,21 +472,7 @@ impl BotState {
         &self,
         account_id: i32,
     ) -> Result<Option<UserId>, sqlx::Error> {
-        let account_id = account_id as i64;
-
-        let res = sqlx::query_as!(
-            LinkedUser,
-            "SELECT * FROM linked_users WHERE gd_account_id = ?",
-            account_id
-        )
-        .fetch_one(&self.database)
-        .await;
-
-        match res {
-            Ok(user) => Ok(Some(UserId::new(user.id as u64))),
-            Err(sqlx::Error::RowNotFound) => Ok(None),
-            Err(e) => Err(e),
-        }
+        find_account_owner(&self.database, account_id).await
     }
 
     pub async fn link_user(
@@ -207,20 +481,77 @@ impl BotState {
         member: &Member,
         gd_username: &str,
         link_code: Option<u32>, // if None, bypasses verification
+        actor: LinkActor,
     ) -> Result<(UserLookupResponse, Vec<String>), LinkError> {
         if !gd_username.is_ascii() || gd_username.len() > 16 {
             return Err(LinkError::InvalidUsername);
         }
 
-        if self.is_linked(member.user.id).await? {
+        if !self
+            .can_link_account(member.user.id, member.guild_id)
+            .await?
+        {
             return Err(LinkError::AlreadyLinked);
         }
 
         let bypass_verification = link_code.is_none();
 
+        if !bypass_verification {
+            if let Some(locked_until) = self
+                .begin_link_attempt(ctx.http(), member.user.id, gd_username)
+                .await?
+            {
+                return Err(LinkError::LockedOut(locked_until));
+            }
+        }
+
+        let linked = self
+            .lookup_and_add_user(ctx, member, gd_username, link_code, actor)
+            .await;
+
+        if !bypass_verification {
+            let counted = match &linked {
+                Ok(_) => self.clear_link_failures(member.user.id, gd_username).await,
+                // only a wrong username or link code counts as a failed attempt
+                Err(LinkError::UserNotFound) => Ok(()),
+                Err(_) => self.refund_link_attempt(member.user.id, gd_username).await,
+            };
+
+            if let Err(err) = counted {
+                warn!(
+                    "Failed to update link attempts of {}: {err}",
+                    member.user.id
+                );
+            }
+        }
+
+        let response = linked?;
+
+        // sync roles
+        match self
+            .sync_roles(ctx.http(), member, SyncOptions::default())
+            .await
+        {
+            Ok(roles) => Ok((response, roles)),
+            Err(e) => Err(LinkError::RoleSync(e, response)),
+        }
+    }
+
+    // looks the account up on the server, verifying the link code if there is one, and links it
+    async fn lookup_and_add_user(
+        &self,
+        ctx: &Context<'_>,
+        member: &Member,
+        gd_username: &str,
+        link_code: Option<u32>,
+        actor: LinkActor,
+    ) -> Result<UserLookupResponse, LinkError> {
+        let bypass_verification = link_code.is_none();
+
+        // accounts are looked up on the main server
         let mut url = format!(
             "{}/gsp/lookup?username={}&link_code={}",
-            self.base_url,
+            self.main_target().base_url,
             gd_username,
             link_code.unwrap_or(0)
         );
@@ -232,7 +563,7 @@ impl BotState {
         let response = match self
             .http_client
             .get(url)
-            .header("Authorization", &self.server_password)
+            .header("Authorization", &self.main_target().password)
             .send()
             .await
         {
@@ -265,17 +596,24 @@ impl BotState {
         };
 
         // insert into the db
-        self.add_linked_user(ctx, member.user.id, response.account_id)
-            .await?;
+        self.add_linked_user(
+            *ctx,
+            member.user.id,
+            member.guild_id,
+            response.account_id,
+            Some(&response.name),
+            actor,
+        )
+        .await?;
 
-        // sync roles
-        match self.sync_roles(member).await {
-            Ok(roles) => Ok((response, roles)),
-            Err(e) => Err(LinkError::RoleSync(e, response)),
-        }
+        Ok(response)
     }
 
-    pub async fn unlink_user(&self, user_id: UserId) -> Result<(), RoleSyncError> {
+    pub async fn unlink_user(
+        &self,
+        user_id: UserId,
+        actor: LinkActor,
+    ) -> Result<(), RoleSyncError> {
         let user_id = user_id.get() as i64;
 
         // check if the user is linked
@@ -287,95 +625,210 @@ impl BotState {
         .fetch_one(&self.database)
         .await?;
 
-        // fetch roles from the database
-        let db_roles = sqlx::query_as!(Role, "SELECT * FROM roles")
-            .fetch_all(&self.database)
-            .await?;
+        // fetch roles from the database, every account loses them
+        let req = self.make_strip_roles_request(&linked_user).await?;
+        let reqs = self.with_alts(req, user_id).await?;
+
+        // remove user from the database, alts are deleted along with it
+        let mut tx = self.database.begin().await?;
 
-        // remove user from the database
         sqlx::query!("DELETE FROM linked_users WHERE id = ?", user_id)
-            .execute(&self.database)
+            .execute(&mut *tx)
+            .await?;
+
+        for req in &reqs {
+            let account_id = req.account_id as i64;
+
+            sqlx::query!(
+                "DELETE FROM synced_roles WHERE gd_account_id = ?",
+                account_id
+            )
+            .execute(&mut *tx)
             .await?;
 
+            self.record_link_event(&mut *tx, actor.unlink_kind(), actor, user_id, account_id)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        // grants and overrides were deleted along with the link
+        self.role_grants.write().remove(&user_id);
+        self.role_overrides.write().remove(&user_id);
+
         // sync roles with the server
+        self.send_sync_roles_req(&RoleSyncRequestData { users: reqs })
+            .await
+    }
 
-        let mut removed = Vec::new();
+    // makes a request that removes every role the bot manages from the account
+    async fn make_strip_roles_request(
+        &self,
+        linked_user: &LinkedUser,
+    ) -> Result<RoleSyncRequest, sqlx::Error> {
+        let db_roles = self.get_all_roles().await?;
+
+        let mut removed = self.managed_role_ids(&db_roles, MAIN_TARGET);
 
-        for role in db_roles {
-            removed.push(role.id);
+        // temporary grants of the user too
+        for (role, _) in self.active_grants(linked_user.id) {
+            if !removed.contains(&role) {
+                removed.push(role);
+            }
         }
 
-        let req = RoleSyncRequest {
+        let targets = self
+            .extra_targets()
+            .iter()
+            .map(|target| TargetRoles {
+                target: target.name.clone(),
+                keep: Vec::new(),
+                remove: self.managed_role_ids(&db_roles, &target.name),
+            })
+            .filter(|x| !x.remove.is_empty())
+            .collect();
+
+        Ok(RoleSyncRequest {
             account_id: linked_user.gd_account_id as i32,
             keep: Vec::new(),
             remove: removed,
-        };
+            targets,
+        })
+    }
 
-        self.send_sync_roles_req(&RoleSyncRequestData { users: vec![req] })
-            .await
+    // globed roles that the bot grants on the server based on discord, from mappings, rules and virtual roles.
+    // roles granted in-game are not included, as they don't come from discord
+    fn managed_role_ids(&self, all_roles: &[Role], target: &str) -> Vec<String> {
+        let mut ids = target_role_ids(all_roles, target);
+
+        let rule_ids: Vec<String> = self
+            .role_rules
+            .read()
+            .get(target)
+            .into_iter()
+            .flatten()
+            .map(|(id, _)| id.clone())
+            .chain(
+                self.virtual_roles
+                    .read()
+                    .get(target)
+                    .into_iter()
+                    .flatten()
+                    .map(|(id, _)| id.clone()),
+            )
+            .collect();
+
+        for id in rule_ids {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        ids
     }
 
+    /// Links the GD account to the user. If the user is linked already, the account becomes an alt,
+    /// as long as the guild allows linking more accounts.
     pub async fn add_linked_user(
         &self,
-        ctx: &Context<'_>,
+        cache_http: impl serenity::CacheHttp,
         user_id: UserId,
+        guild_id: GuildId,
         account_id: i32,
+        gd_username: Option<&str>,
+        actor: LinkActor,
     ) -> Result<(), LinkError> {
         let user_id_int = user_id.get() as i64;
 
-        match sqlx::query!(
-            "INSERT INTO linked_users (id, gd_account_id) VALUES (?, ?)",
-            user_id_int,
-            account_id
-        )
-        .execute(&self.database)
-        .await
-        {
-            Ok(_) => {}
-            Err(sqlx::Error::Database(err)) => {
-                // this is pretty bad but eh
-                if !err.message().contains("UNIQUE constraint failed") {
-                    return Err(LinkError::Database(sqlx::Error::Database(err)));
-                }
-
-                // check if the someone else's discord is alreday linked to this gd account
-                let linked_disc = self.get_linked_discord_account(account_id).await?;
+        // the checks and the insert can't interleave with another link, the account could end up linked twice
+        // across both tables, or the user could go over the limit
+        let mut tx = self.database.begin_with("BEGIN IMMEDIATE").await?;
 
-                // if linked to someone else than us, tell the user
-                if linked_disc.as_ref().is_some_and(|id| *id != user_id) {
-                    let linked_id = linked_disc.unwrap();
+        // check if the someone else's discord is already linked to this gd account
+        if let Some(linked_id) = find_account_owner(&mut *tx, account_id).await? {
+            drop(tx);
 
-                    // try to fetch the member and display their username, else fall back to their user id
-                    let mut ident = String::new();
+            if linked_id == user_id {
+                return Err(LinkError::AlreadyLinked);
+            }
 
-                    // god i fucking hate async rust
-                    {
-                        if let Some(cached) = ctx.cache().user(linked_id) {
-                            ident.push('@');
-                            ident.push_str(&cached.name);
-                        }
-                    }
+            // try to fetch the member and display their username, else fall back to their user id
+            let mut ident = String::new();
 
-                    if ident.is_empty() {
-                        if let Ok(user) = ctx.http().get_user(linked_id).await {
-                            ident.push('@');
-                            ident.push_str(&user.name);
-                        } else {
-                            ident = linked_id.to_string();
-                        }
-                    };
+            // god i fucking hate async rust
+            {
+                if let Some(cached) = cache_http.cache().and_then(|x| x.user(linked_id)) {
+                    ident.push('@');
+                    ident.push_str(&cached.name);
+                }
+            }
 
-                    return Err(LinkError::LinkedToOther(ident));
+            if ident.is_empty() {
+                if let Ok(user) = cache_http.http().get_user(linked_id).await {
+                    ident.push('@');
+                    ident.push_str(&user.name);
                 } else {
-                    // otherwise most likely we are already linked
-                    return Err(LinkError::AlreadyLinked);
+                    ident = linked_id.to_string();
                 }
+            };
+
+            return Err(LinkError::LinkedToOther(ident));
+        }
+
+        let linked = alts::count_accounts(&mut *tx, user_id_int).await?;
+        let is_alt = linked > 0;
+
+        if is_alt && linked >= guilds::max_accounts(&mut *tx, guild_id).await? {
+            return Err(LinkError::AlreadyLinked);
+        }
+
+        let result = if is_alt {
+            let now = now_timestamp();
+
+            sqlx::query!(
+                "INSERT INTO linked_alts (gd_account_id, user_id, gd_username, linked_at) VALUES (?, ?, ?, ?)",
+                account_id,
+                user_id_int,
+                gd_username,
+                now
+            )
+            .execute(&mut *tx)
+            .await
+        } else {
+            sqlx::query!(
+                "INSERT INTO linked_users (id, gd_account_id, gd_username) VALUES (?, ?, ?)",
+                user_id_int,
+                account_id,
+                gd_username
+            )
+            .execute(&mut *tx)
+            .await
+        };
+
+        match result {
+            Ok(_) => {}
+            // the checks above should have caught this
+            Err(sqlx::Error::Database(err))
+                if err.message().contains("UNIQUE constraint failed") =>
+            {
+                return Err(LinkError::AlreadyLinked);
             }
             Err(err) => {
                 return Err(LinkError::Database(err));
             }
         }
 
+        self.record_link_event(
+            &mut *tx,
+            actor.link_kind(),
+            actor,
+            user_id_int,
+            account_id as i64,
+        )
+        .await?;
+
+        tx.commit().await?;
+
         Ok(())
     }
 
@@ -387,26 +840,32 @@ impl BotState {
 
     /* Methods for adding/removing/getting linked roles */
 
-    pub async fn add_role(&self, role_id: i64, globed_role_id: &str) -> Result<(), sqlx::Error> {
+    pub async fn add_role(
+        &self,
+        guild_id: GuildId,
+        role_id: i64,
+        role_name: &str,
+        globed_role_id: &str,
+        direction: RoleDirection,
+        target: &str,
+    ) -> Result<(), sqlx::Error> {
+        let guild_id = guild_id.get() as i64;
+        let direction = direction.as_str();
+
         sqlx::query!(
-            "INSERT INTO roles (id, discord_id) VALUES (?, ?)",
+            "INSERT INTO roles (id, guild_id, target, discord_id, direction, discord_name) VALUES (?, ?, ?, ?, ?, ?)",
             globed_role_id,
-            role_id
+            guild_id,
+            target,
+            role_id,
+            direction,
+            role_name
         )
         .execute(&self.database)
         .await?;
 
-        let id = RoleId::new(role_id as u64);
-
-        let mut watched = self.watched_roles.write();
-        if !watched.contains(&id) {
-            watched.push(id);
-        }
-
-        watched.sort();
-
-        #[cfg(debug_assertions)]
-        debug!("new watched roles: {:?}", *watched);
+        self.invalidate_synced_roles().await?;
+        self.reload_watched_roles().await?;
 
         Ok(())
     }
@@ -421,31 +880,62 @@ impl BotState {
             return Err(RoleRemoveError::NotFound);
         }
 
-        let id = RoleId::new(role_id as u64);
+        self.invalidate_synced_roles().await?;
+        self.reload_watched_roles().await?;
 
-        let mut watched = self.watched_roles.write();
-        if let Some(pos) = watched.iter().position(|x| *x == id) {
-            watched.remove(pos);
+        Ok(())
+    }
+
+    pub async fn remove_role_by_globed_id(
+        &self,
+        guild_id: GuildId,
+        target: &str,
+        role: &str,
+    ) -> Result<(), RoleRemoveError> {
+        let guild_id = guild_id.get() as i64;
+
+        let affected = sqlx::query!(
+            "DELETE FROM roles WHERE id = ? AND guild_id = ? AND target = ?",
+            role,
+            guild_id,
+            target
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Err(RoleRemoveError::NotFound);
         }
 
-        #[cfg(debug_assertions)]
-        debug!("new watched roles: {:?}", *watched);
+        self.invalidate_synced_roles().await?;
+        self.reload_watched_roles().await?;
 
         Ok(())
     }
 
-    pub async fn remove_role_by_globed_id(&self, role: &str) -> Result<(), RoleRemoveError> {
-        let deleted = sqlx::query_as!(Role, "DELETE FROM roles WHERE id = ? RETURNING *", role)
-            .fetch_one(&self.database)
-            .await?;
+    // rebuilds the list of discord roles whose changes have to be synced to the server.
+    // roles that are only synced from Globed to Discord aren't watched, the bot changes those itself
+    async fn reload_watched_roles(&self) -> Result<(), sqlx::Error> {
+        let roles = self.get_all_roles().await?;
 
-        let id = RoleId::new(deleted.discord_id as u64);
+        let mut new_watched: Vec<RoleId> = roles
+            .iter()
+            .filter(|role| !role.is_reverse())
+            .map(|role| RoleId::new(role.discord_id as u64))
+            .collect();
 
-        let mut watched = self.watched_roles.write();
-        if let Some(pos) = watched.iter().position(|x| *x == id) {
-            watched.remove(pos);
+        // roles used in rules matter too
+        for (_, rule) in self.role_rules.read().values().flatten() {
+            new_watched.extend(rule.roles());
         }
 
+        new_watched.sort();
+        new_watched.dedup();
+
+        let mut watched = self.watched_roles.write();
+        *watched = new_watched;
+
         #[cfg(debug_assertions)]
         debug!("new watched roles: {:?}", *watched);
 
@@ -458,88 +948,109 @@ impl BotState {
             .await
     }
 
-    /* Methods for syncing */
-
-    // syncs roles, returns ids of roles the user received
-    pub async fn sync_roles(&self, user: &Member) -> Result<Vec<String>, RoleSyncError> {
-        let req = self.make_role_sync_request(user).await?;
+    /* Methods for persistent bot state */
 
-        let retval = req.keep.clone();
+    pub async fn get_persistent_value(&self, key: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar!("SELECT value FROM bot_state WHERE key = ?", key)
+            .fetch_optional(&self.database)
+            .await
+    }
 
-        self.send_sync_roles_req(&RoleSyncRequestData { users: vec![req] })
-            .await?;
+    pub async fn set_persistent_value(&self, key: &str, value: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO bot_state (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            key,
+            value
+        )
+        .execute(&self.database)
+        .await?;
 
-        Ok(retval)
+        Ok(())
     }
 
-    pub async fn sync_all_members(&self, http: &serenity::Http) -> Result<usize, RoleSyncError> {
-        // get all linked users
-        let linked_users = self
-            .get_all_linked_users()
-            .await
-            .expect("Failed to read the database");
-
-        // get all linked roles
-        let linked_roles = self
-            .get_all_roles()
-            .await
-            .expect("Failed to read the database");
+    pub async fn remove_persistent_value(&self, key: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM bot_state WHERE key = ?", key)
+            .execute(&self.database)
+            .await?;
 
-        let mut sync_data = RoleSyncRequestData {
-            users: Vec::with_capacity(linked_users.len()),
-        };
+        Ok(())
+    }
 
-        // for fastest lookup, put all ids of linked users into a vec and sort it, so binary search can be applied later
-        let mut linked_ids: Vec<u64> = linked_users.iter().map(|x| x.id as u64).collect();
-        linked_ids.sort();
+    /* Methods for syncing */
 
-        // Perform quite a massive scan
+    // syncs roles, combined from every guild the user is in. returns ids of roles the user received
+    pub async fn sync_roles(
+        &self,
+        http: &serenity::Http,
+        user: &Member,
+        options: SyncOptions,
+    ) -> Result<Vec<String>, RoleSyncError> {
+        let (linked_user, members, db_roles) = self.load_sync_inputs(http, user).await?;
 
-        let mut after = None;
+        let req = self.make_role_sync_request_with(&members, &linked_user, &db_roles);
+        let reqs = self.with_alts(req, linked_user.id).await?;
 
-        loop {
-            let members = match http.get_guild_members(self.guild_id, None, after).await {
-                Ok(x) => x,
-                Err(err) => {
-                    warn!("Failed to fetch guild members: {err}");
-                    break;
-                }
-            };
+        // every account gets the same roles
+        let retval = reqs[0].keep.clone();
 
-            if members.is_empty() {
-                break;
+        let mut outdated = Vec::with_capacity(reqs.len());
+        for req in reqs {
+            if options.force || !self.is_up_to_date(&req).await? {
+                outdated.push(req);
             }
+        }
 
-            after = Some(members.last().unwrap().user.id.get());
-
-            // iterate over this member chunk, if any of them are linked, add them to sync list
-            for member in members {
-                let member_id = member.user.id.get();
-                if linked_ids.binary_search(&member_id).is_ok() {
-                    let req = self.make_role_sync_request_with(
-                        &member,
-                        linked_users
-                            .iter()
-                            .find(|x| x.id == member_id as i64)
-                            .unwrap(), // unwrap should be safe
-                        &linked_roles,
-                    );
-
-                    sync_data.users.push(req);
-                }
-            }
+        if outdated.is_empty() {
+            debug!("roles of {} are already up to date", user.display_name());
+        } else {
+            self.send_sync_roles_req(&RoleSyncRequestData { users: outdated })
+                .await?;
         }
 
-        // send a mass sync request!
-        self.send_sync_roles_req(&sync_data)
+        // roles granted on Globed are pulled back to discord even if nothing had to be sent
+        if let Err(err) = self
+            .sync_reverse_roles(http, &[(members, &linked_user)], &db_roles)
             .await
-            .map(|()| sync_data.users.len())
+        {
+            warn!(
+                "Failed to sync roles of {} from Globed to Discord: {err}",
+                user.display_name()
+            );
+        }
+
+        Ok(retval)
     }
 
+    /// Builds the requests `sync_roles` would send, without recording any membership changes
     pub async fn make_role_sync_request(
         &self,
+        http: &serenity::Http,
         user: &Member,
-    ) -> Result<RoleSyncRequest, RoleSyncError> {
+    ) -> Result<Vec<RoleSyncRequest>, RoleSyncError> {
+        let linked_user = self
+            .get_linked_user(user.user.id)
+            .await?
+            .ok_or(RoleSyncError::NotLinked)?;
+
+        let members = self
+            .scan_memberships(http, &linked_user, Some(user))
+            .await?
+            .members;
+
+        let db_roles = self.get_all_roles().await?;
+
+        let req = self.make_role_sync_request_with(&members, &linked_user, &db_roles);
+
+        // one request for each linked account
+        Ok(self.with_alts(req, linked_user.id).await?)
+    }
+
+    // the link of the user, their membership in every guild and all role mappings
+    async fn load_sync_inputs(
+        &self,
+        http: &serenity::Http,
+        user: &Member,
+    ) -> Result<(LinkedUser, Vec<Member>, Vec<Role>), RoleSyncError> {
         let user_id = user.user.id.get() as i64;
 
         // check if the user is linked
@@ -551,55 +1062,195 @@ impl BotState {
         .fetch_one(&self.database)
         .await?;
 
+        // the user can be in other guilds too
+        let members = self
+            .fetch_memberships(http, &linked_user, Some(user))
+            .await?;
+
         // fetch roles from the database
         let db_roles = self.get_all_roles().await?;
 
-        Ok(self.make_role_sync_request_with(user, &linked_user, &db_roles))
+        Ok((linked_user, members, db_roles))
     }
 
+    /// Builds the request from every guild membership of the user, roles from all guilds are combined
     pub fn make_role_sync_request_with(
         &self,
-        user: &Member,
+        members: &[Member],
         linked_user: &LinkedUser,
         all_roles: &[Role],
     ) -> RoleSyncRequest {
+        // discord role ids are unique across guilds, so they can all be checked together
+        let discord_roles: Vec<RoleId> = members
+            .iter()
+            .flat_map(|x| x.roles.iter().copied())
+            .collect();
+
+        let mut granted =
+            self.evaluate_target_roles(MAIN_TARGET, members, &discord_roles, all_roles);
+
+        // temporary grants are only for the main server
+        for (id, _) in self.active_grants(linked_user.id) {
+            match granted.iter_mut().find(|(x, _)| *x == id) {
+                Some((_, x)) => *x = true,
+                None => granted.push((id, true)),
+            }
+        }
+
+        self.apply_exclusive_groups(MAIN_TARGET, &mut granted);
+
+        // manual overrides always win
+        self.apply_overrides(linked_user.id, &mut granted);
+
         // depending on which roles the user has, make a vec of roles that should be kept, and roles that should be removed
         let mut kept = Vec::new();
         let mut removed = Vec::new();
 
-        for role in all_roles {
-            // check if user has that role on discord
-            if user
-                .roles
-                .iter()
-                .any(|id| id.get() as i64 == role.discord_id)
-            {
-                // add to list of roles to be kept
-                kept.push(role.id.clone());
+        for (id, has) in granted {
+            if has {
+                kept.push(id);
             } else {
-                // add to list of roles to be removed
-                removed.push(role.id.clone());
+                removed.push(id);
             }
         }
 
         #[cfg(debug_assertions)]
         debug!(
             "for {}, keep: {kept:?}, remove: {removed:?}",
-            user.display_name()
+            linked_user.id
         );
 
+        // extra servers only get their own mappings, rules and virtual roles, grants and overrides are for the main server
+        let targets = self
+            .extra_targets()
+            .iter()
+            .filter_map(|target| {
+                self.make_target_roles(&target.name, members, &discord_roles, all_roles)
+            })
+            .collect();
+
         /* make a request to update the roles on the central server */
         RoleSyncRequest {
             account_id: linked_user.gd_account_id as i32,
             keep: kept,
             remove: removed,
+            targets,
         }
     }
 
-    // internal function for making server web request to sync roles
+    // for every globed role of the server, whether the user should have it. a globed role can be granted
+    // by multiple mappings and rules, the user gets it if any of them matches
+    fn evaluate_target_roles(
+        &self,
+        target: &str,
+        members: &[Member],
+        discord_roles: &[RoleId],
+        all_roles: &[Role],
+    ) -> Vec<(String, bool)> {
+        let mut granted: Vec<(String, bool)> = Vec::new();
+        let mut grant = |id: &str, has: bool| match granted.iter_mut().find(|(x, _)| x == id) {
+            Some((_, x)) => *x |= has,
+            None => granted.push((id.to_owned(), has)),
+        };
+
+        for role in all_roles
+            .iter()
+            .filter(|role| !role.is_reverse() && role.target == target)
+        {
+            // check if user has that role on discord
+            grant(
+                &role.id,
+                discord_roles
+                    .iter()
+                    .any(|id| id.get() as i64 == role.discord_id),
+            );
+        }
+
+        for (id, rule) in self.role_rules.read().get(target).into_iter().flatten() {
+            grant(id, rule.eval(discord_roles));
+        }
+
+        for (id, condition) in self.virtual_roles.read().get(target).into_iter().flatten() {
+            grant(id, members.iter().any(|x| condition.eval(x)));
+        }
+
+        granted
+    }
+
+    // roles of the user on an extra server, `None` if nothing is mapped for the server
+    fn make_target_roles(
+        &self,
+        target: &str,
+        members: &[Member],
+        discord_roles: &[RoleId],
+        all_roles: &[Role],
+    ) -> Option<TargetRoles> {
+        let mut granted = self.evaluate_target_roles(target, members, discord_roles, all_roles);
+
+        if granted.is_empty() {
+            return None;
+        }
+
+        self.apply_exclusive_groups(target, &mut granted);
+
+        let (keep, remove): (Vec<_>, Vec<_>) = granted.into_iter().partition(|(_, has)| *has);
+
+        Some(TargetRoles {
+            target: target.to_owned(),
+            keep: keep.into_iter().map(|(id, _)| id).collect(),
+            remove: remove.into_iter().map(|(id, _)| id).collect(),
+        })
+    }
+
+    // sends a sync request to every server. if the main server fails due to a server issue, the request gets queued for a retry.
+    // extra servers are best effort, users that failed there are sent again on their next sync
     pub async fn send_sync_roles_req(
         &self,
         data: &RoleSyncRequestData,
+    ) -> Result<(), RoleSyncError> {
+        let failed_targets = self
+            .send_to_extra_targets(data, TargetRequestKind::Full)
+            .await;
+
+        match self.post_sync_roles_req(self.main_target(), data).await {
+            Ok(()) => {
+                // anything still queued for these users is outdated now
+                if let Err(err) = self.clear_outbox_for(MAIN_TARGET, data).await {
+                    warn!("Failed to clear outdated outbox entries: {err}");
+                }
+
+                if let Err(err) = self.mark_synced(data).await {
+                    warn!("Failed to store synced roles: {err}");
+                }
+
+                if !failed_targets.is_empty() {
+                    if let Err(err) = self.forget_synced(&failed_targets).await {
+                        warn!("Failed to forget synced roles: {err}");
+                    }
+                }
+
+                self.outbox_wakeup.notify_one();
+
+                Ok(())
+            }
+
+            Err(err) => {
+                if err.is_retryable() {
+                    if let Err(db_err) = self.enqueue_failed_sync(MAIN_TARGET, data, &err).await {
+                        error!("Failed to queue role sync for a retry: {db_err}");
+                    }
+                }
+
+                Err(err)
+            }
+        }
+    }
+
+    // internal function for making server web request to sync roles
+    async fn post_sync_roles_req(
+        &self,
+        target: &ServerTarget,
+        data: &RoleSyncRequestData,
     ) -> Result<(), RoleSyncError> {
         let body: String = match serde_json::to_string(data) {
             Ok(x) => x,
@@ -617,8 +1268,8 @@ impl BotState {
 
         let response = match self
             .http_client
-            .post(format!("{}/gsp/sync_roles", self.base_url))
-            .header("Authorization", &self.server_password)
+            .post(format!("{}/gsp/sync_roles", target.base_url))
+            .header("Authorization", &target.password)
             .header("Content-Type", "application/json")
             .body(body)
             .send()
@@ -638,7 +1289,8 @@ impl BotState {
                 .unwrap_or_else(|_| "<no message>".to_owned());
 
             warn!(
-                "Role update failed: code {}, message: {}",
+                "Role update on server '{}' failed: code {}, message: {}",
+                target.name,
                 status.as_u16(),
                 message
             );
@@ -650,3 +1302,31 @@ impl BotState {
         Ok(())
     }
 }
+
+// forward mapped globed roles of a server
+pub(super) fn target_role_ids(all_roles: &[Role], target: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+
+    for role in all_roles
+        .iter()
+        .filter(|role| !role.is_reverse() && role.target == target)
+    {
+        if !ids.contains(&role.id) {
+            ids.push(role.id.clone());
+        }
+    }
+
+    ids
+}
+
+pub fn now_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Timestamp once the duration passes from now, `None` if it doesn't fit
+pub fn expiry_after(duration: Duration) -> Option<i64> {
+    now_timestamp().checked_add(i64::try_from(duration.as_secs()).ok()?)
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use serenity::all::{GuildId, UserId};
+
+use super::{BotState, LinkActor, RoleSyncError, RoleSyncRequest, RoleSyncRequestData};
+use crate::{
+    db::{LinkedAlt, LinkedUser},
+    serenity,
+};
+
+impl LinkedAlt {
+    /// The GD username with the account ID, or only the account ID if the name isn't known
+    pub fn account_display(&self) -> String {
+        match &self.gd_username {
+            Some(name) => format!("{name} ({})", self.gd_account_id),
+            None => self.gd_account_id.to_string(),
+        }
+    }
+}
+
+// every account of a user gets the same roles, so the request of the main account is copied for each alt
+pub(super) fn with_alt_requests(req: RoleSyncRequest, alts: &[i64]) -> Vec<RoleSyncRequest> {
+    let mut reqs = Vec::with_capacity(alts.len() + 1);
+
+    for account_id in alts {
+        reqs.push(RoleSyncRequest {
+            account_id: *account_id as i32,
+            ..req.clone()
+        });
+    }
+
+    reqs.insert(0, req);
+    reqs
+}
+
+// how many GD accounts the user has linked, the main one included
+pub(super) async fn count_accounts<'e, E>(executor: E, user_id: i64) -> Result<i64, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    sqlx::query_scalar!(
+        r#"SELECT (SELECT COUNT(*) FROM linked_users WHERE id = ?) + (SELECT COUNT(*) FROM linked_alts WHERE user_id = ?) AS "count!: i64""#,
+        user_id,
+        user_id
+    )
+    .fetch_one(executor)
+    .await
+}
+
+impl BotState {
+    /// Extra GD accounts of the user, oldest first
+    pub async fn get_alts(&self, user_id: UserId) -> Result<Vec<LinkedAlt>, sqlx::Error> {
+        let user_id = user_id.get() as i64;
+
+        sqlx::query_as!(
+            LinkedAlt,
+            "SELECT * FROM linked_alts WHERE user_id = ? ORDER BY linked_at, gd_account_id",
+            user_id
+        )
+        .fetch_all(&self.database)
+        .await
+    }
+
+    // alts of every user that has any, oldest first
+    pub(super) async fn get_all_alts(&self) -> Result<HashMap<i64, Vec<LinkedAlt>>, sqlx::Error> {
+        let alts = sqlx::query_as!(
+            LinkedAlt,
+            "SELECT * FROM linked_alts ORDER BY linked_at, gd_account_id"
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        let mut out: HashMap<i64, Vec<LinkedAlt>> = HashMap::new();
+
+        for alt in alts {
+            out.entry(alt.user_id).or_default().push(alt);
+        }
+
+        Ok(out)
+    }
+
+    // account ids of the alts of every user that has any
+    pub(super) async fn get_all_alt_ids(&self) -> Result<HashMap<i64, Vec<i64>>, sqlx::Error> {
+        let alts = sqlx::query!("SELECT user_id, gd_account_id FROM linked_alts")
+            .fetch_all(&self.database)
+            .await?;
+
+        let mut out: HashMap<i64, Vec<i64>> = HashMap::new();
+
+        for alt in alts {
+            out.entry(alt.user_id).or_default().push(alt.gd_account_id);
+        }
+
+        Ok(out)
+    }
+
+    // the request for the main account, followed by the same one for each alt of the user
+    pub(super) async fn with_alts(
+        &self,
+        req: RoleSyncRequest,
+        user_id: i64,
+    ) -> Result<Vec<RoleSyncRequest>, sqlx::Error> {
+        let alts = sqlx::query_scalar!(
+            "SELECT gd_account_id FROM linked_alts WHERE user_id = ?",
+            user_id
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        Ok(with_alt_requests(req, &alts))
+    }
+
+    /// Whether the user can link another GD account from the guild, either their first one or an alt
+    pub async fn can_link_account(
+        &self,
+        user_id: UserId,
+        guild_id: GuildId,
+    ) -> Result<bool, sqlx::Error> {
+        let linked = count_accounts(&self.database, user_id.get() as i64).await?;
+
+        Ok(linked < self.get_max_accounts(guild_id).await?)
+    }
+
+    /// Unlinks a single GD account of the user and removes the roles from it. If the main account is unlinked,
+    /// the oldest alt becomes the main one. Unlinking the last account unlinks the user completely.
+    pub async fn unlink_account(
+        &self,
+        user_id: UserId,
+        account_id: i32,
+        actor: LinkActor,
+    ) -> Result<(), RoleSyncError> {
+        let Some(linked_user) = self.get_linked_user(user_id).await? else {
+            return Err(RoleSyncError::NotLinked);
+        };
+
+        let alts = self.get_alts(user_id).await?;
+        let account_id = account_id as i64;
+        let is_main = linked_user.gd_account_id == account_id;
+
+        if !is_main && !alts.iter().any(|x| x.gd_account_id == account_id) {
+            return Err(RoleSyncError::NotLinked);
+        }
+
+        if is_main && alts.is_empty() {
+            return self.unlink_user(user_id, actor).await;
+        }
+
+        let mut req = self.make_strip_roles_request(&linked_user).await?;
+        req.account_id = account_id as i32;
+
+        let mut tx = self.database.begin().await?;
+
+        if is_main {
+            let promoted = &alts[0];
+
+            sqlx::query!(
+                "DELETE FROM linked_alts WHERE gd_account_id = ?",
+                promoted.gd_account_id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            sqlx::query!(
+                "UPDATE linked_users SET gd_account_id = ?, gd_username = ? WHERE id = ?",
+                promoted.gd_account_id,
+                promoted.gd_username,
+                linked_user.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query!(
+                "DELETE FROM linked_alts WHERE gd_account_id = ?",
+                account_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        sqlx::query!(
+            "DELETE FROM synced_roles WHERE gd_account_id = ?",
+            account_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        self.record_link_event(
+            &mut *tx,
+            actor.unlink_kind(),
+            actor,
+            linked_user.id,
+            account_id,
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        self.send_sync_roles_req(&RoleSyncRequestData { users: vec![req] })
+            .await
+    }
+
+    /// Every GD account of the user, the main one first
+    pub async fn get_all_accounts(
+        &self,
+        user_id: UserId,
+    ) -> Result<Option<(LinkedUser, Vec<LinkedAlt>)>, sqlx::Error> {
+        let Some(linked_user) = self.get_linked_user(user_id).await? else {
+            return Ok(None);
+        };
+
+        let alts = self.get_alts(user_id).await?;
+
+        Ok(Some((linked_user, alts)))
+    }
+}
@@ -0,0 +1,164 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use log::{debug, info, warn};
+use serenity::all::{GuildId, Member, UserId};
+
+use super::{now_timestamp, BotState, LinkActor, RoleSyncError, RoleSyncRequestData, SyncOptions};
+use crate::{db::LinkedUser, serenity};
+
+// how often the expiry task checks for members whose grace period ran out
+const DEPARTURE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+impl BotState {
+    /// Called when a member leaves a guild. If they are still in another guild, only the roles from this one are gone.
+    /// Otherwise without a grace period they get unlinked right away, or their roles are removed and the link is kept in case they come back.
+    pub async fn handle_member_removal(
+        &self,
+        http: &serenity::Http,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<(), RoleSyncError> {
+        let user_id_int = user_id.get() as i64;
+
+        // check if the user is linked
+        let linked_user = sqlx::query_as!(
+            LinkedUser,
+            "SELECT * FROM linked_users WHERE id = ?",
+            user_id_int
+        )
+        .fetch_one(&self.database)
+        .await?;
+
+        self.remove_membership(user_id_int, guild_id).await?;
+
+        let members = self.fetch_memberships(http, &linked_user, None).await?;
+        if !members.is_empty() {
+            let roles = self.get_all_roles().await?;
+            let req = self.make_role_sync_request_with(&members, &linked_user, &roles);
+            let users = self.with_alts(req, user_id_int).await?;
+
+            return self
+                .send_sync_roles_req(&RoleSyncRequestData { users })
+                .await;
+        }
+
+        if self.departure_grace.is_zero() {
+            return self.unlink_user(user_id, LinkActor::System).await;
+        }
+
+        let now = now_timestamp();
+
+        sqlx::query!(
+            "INSERT INTO departed_members (id, departed_at) VALUES (?, ?) ON CONFLICT(id) DO NOTHING",
+            user_id_int,
+            now
+        )
+        .execute(&self.database)
+        .await?;
+
+        let req = self.make_strip_roles_request(&linked_user).await?;
+        let users = self.with_alts(req, user_id_int).await?;
+
+        self.send_sync_roles_req(&RoleSyncRequestData { users })
+            .await
+    }
+
+    /// Called when a member joins a guild. If they are linked, roles from the guild get added to theirs,
+    /// and if they left recently, their link is restored. Returns whether the member was restored.
+    pub async fn handle_member_rejoin(
+        &self,
+        http: &serenity::Http,
+        member: &Member,
+    ) -> Result<bool, RoleSyncError> {
+        let restored = self
+            .restore_departed_members(&[member.user.id.get()])
+            .await?;
+
+        if restored {
+            debug!(
+                "{} rejoined the guild, restoring their link",
+                member.user.name
+            );
+        }
+
+        match self.sync_roles(http, member, SyncOptions::default()).await {
+            Ok(_) => Ok(restored),
+            Err(RoleSyncError::NotLinked) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Removes the departed mark of the given members, returns whether any of them were marked
+    pub(super) async fn restore_departed_members(&self, ids: &[u64]) -> Result<bool, sqlx::Error> {
+        let ids = serde_json::to_string(ids).unwrap_or_default();
+
+        let affected = sqlx::query!(
+            "DELETE FROM departed_members WHERE id IN (SELECT value FROM json_each(?))",
+            ids
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        Ok(affected > 0)
+    }
+
+    pub(super) async fn get_departed_ids(&self) -> Result<HashSet<u64>, sqlx::Error> {
+        Ok(sqlx::query_scalar!("SELECT id FROM departed_members")
+            .fetch_all(&self.database)
+            .await?
+            .into_iter()
+            .map(|id| id as u64)
+            .collect())
+    }
+
+    /// Background task that unlinks departed members once their grace period runs out
+    pub async fn run_departure_expiry(self: Arc<Self>) {
+        if self.departure_grace.is_zero() {
+            return;
+        }
+
+        loop {
+            match self.expire_departed_members().await {
+                Ok(0) => {}
+                Ok(count) => info!("Unlinked {count} members that didn't come back to any guild"),
+                Err(err) => warn!("Failed to unlink departed members: {err}"),
+            }
+
+            tokio::time::sleep(DEPARTURE_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn expire_departed_members(&self) -> Result<usize, sqlx::Error> {
+        let cutoff = now_timestamp() - self.departure_grace.as_secs() as i64;
+
+        let expired = sqlx::query_scalar!(
+            "SELECT id FROM departed_members WHERE departed_at <= ?",
+            cutoff
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        let mut unlinked = 0;
+
+        for id in expired {
+            // the unlink removes the departed mark too
+            match self
+                .unlink_user(UserId::new(id as u64), LinkActor::System)
+                .await
+            {
+                Ok(()) => unlinked += 1,
+                Err(RoleSyncError::NotLinked) => {}
+                Err(err) if err.is_retryable() => {
+                    unlinked += 1;
+                    warn!(
+                        "Failed to remove roles of departed member {id}, queued for a retry: {err}"
+                    );
+                }
+                Err(err) => warn!("Failed to unlink departed member {id}: {err}"),
+            }
+        }
+
+        Ok(unlinked)
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use serenity::all::UserId;
+
+use super::{
+    BotState, RoleSyncError, RoleSyncRequest, RoleSyncRequestData, ServerTarget, MAIN_TARGET,
+};
+use crate::{db::LinkedUser, serenity};
+
+// max amount of account ids sent in a single role lookup
+const ROLE_LOOKUP_BATCH_SIZE: usize = 200;
+
+#[derive(Deserialize)]
+pub struct ServerRoleAssignment {
+    pub account_id: i32,
+    pub roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ServerRolesResponse {
+    users: Vec<ServerRoleAssignment>,
+}
+
+pub struct DriftEntry {
+    pub user_id: UserId,
+    pub account_id: i32,
+    /// GD username and account ID, for display
+    pub account: String,
+    /// Roles the user should have, but the server doesn't give them
+    pub missing: Vec<String>,
+    /// Roles the server gives the user, but they shouldn't have
+    pub extra: Vec<String>,
+    expected: RoleSyncRequest,
+}
+
+#[derive(Default)]
+pub struct DriftReport {
+    pub users_checked: usize,
+    pub drifted: Vec<DriftEntry>,
+}
+
+// fetches the roles the server has assigned to the accounts, in batches
+async fn request_server_roles(
+    client: &reqwest::Client,
+    target: &ServerTarget,
+    account_ids: &[i32],
+) -> Result<HashMap<i32, Vec<String>>, RoleSyncError> {
+    let mut assignments = HashMap::with_capacity(account_ids.len());
+
+    for batch in account_ids.chunks(ROLE_LOOKUP_BATCH_SIZE) {
+        let ids = batch
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let response = match client
+            .get(format!("{}/gsp/roles?account_ids={ids}", target.base_url))
+            .header("Authorization", &target.password)
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) => {
+                return Err(RoleSyncError::ServerRequest(e));
+            }
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            let message = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "<no message>".to_owned());
+
+            return Err(RoleSyncError::ServerUpdate((status, message)));
+        }
+
+        let json = response.text().await.unwrap_or_default();
+        let response: ServerRolesResponse = match serde_json::from_str(&json) {
+            Ok(x) => x,
+            Err(err) => {
+                return Err(RoleSyncError::ServerMalformedResponse(err, json));
+            }
+        };
+
+        for user in response.users {
+            assignments.insert(user.account_id, user.roles);
+        }
+    }
+
+    Ok(assignments)
+}
+
+// splits the difference between the expected roles and the ones on the server into (missing, extra).
+// roles the bot doesn't manage are ignored, they are given out on Globed directly
+fn classify_drift(
+    expected: &[String],
+    actual: Option<&Vec<String>>,
+    managed: &HashSet<&str>,
+) -> (Vec<String>, Vec<String>) {
+    let mut actual: Vec<&str> = actual
+        .map(|roles| {
+            roles
+                .iter()
+                .map(String::as_str)
+                .filter(|x| managed.contains(x))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // the server may list a role more than once
+    actual.sort_unstable();
+    actual.dedup();
+
+    let missing = expected
+        .iter()
+        .filter(|x| !actual.contains(&x.as_str()))
+        .cloned()
+        .collect();
+
+    let extra = actual
+        .iter()
+        .filter(|x| !expected.iter().any(|k| k == *x))
+        .map(|x| (*x).to_owned())
+        .collect();
+
+    (missing, extra)
+}
+
+// the roles managed for a single user: the shared ones plus the roles given to the user by hand
+fn user_managed_roles<'a>(
+    managed: &HashSet<&'a str>,
+    user_roles: &'a [String],
+) -> HashSet<&'a str> {
+    let mut managed = managed.clone();
+    managed.extend(user_roles.iter().map(String::as_str));
+    managed
+}
+
+impl BotState {
+    /// Fetches the roles the server currently has assigned to the given accounts
+    pub async fn fetch_server_roles(
+        &self,
+        account_ids: &[i32],
+    ) -> Result<HashMap<i32, Vec<String>>, RoleSyncError> {
+        request_server_roles(&self.http_client, self.main_target(), account_ids).await
+    }
+
+    /// Compares the roles the server has with the ones computed from Discord roles, for every linked account
+    /// including alts. Only roles managed by the bot are taken into account.
+    pub async fn detect_drift(&self, http: &serenity::Http) -> Result<DriftReport, RoleSyncError> {
+        let linked_users = self.get_all_linked_users().await?;
+        let linked_roles = self.get_all_roles().await?;
+
+        // roles granted on Globed are the source of truth for discord, so they can't drift here
+        let managed_ids = self.managed_role_ids(&linked_roles, MAIN_TARGET);
+        let managed: HashSet<&str> = managed_ids.iter().map(String::as_str).collect();
+
+        let members = self.fetch_linked_members(http, &linked_users).await?;
+        let alts = self.get_all_alts().await?;
+
+        // every account of a user gets the same roles, so alts can drift too. main account first, as (id, display)
+        let accounts_of = |user: &LinkedUser| -> Vec<(i32, String)> {
+            std::iter::once((user.gd_account_id as i32, user.account_display()))
+                .chain(
+                    alts.get(&user.id)
+                        .into_iter()
+                        .flatten()
+                        .map(|alt| (alt.gd_account_id as i32, alt.account_display())),
+                )
+                .collect()
+        };
+
+        let account_ids: Vec<i32> = members
+            .users
+            .iter()
+            .flat_map(|(_, user)| accounts_of(user))
+            .map(|(id, _)| id)
+            .collect();
+
+        let server_roles = self.fetch_server_roles(&account_ids).await?;
+
+        let mut report = DriftReport {
+            users_checked: members.users.len(),
+            ..Default::default()
+        };
+
+        for (members, linked_user) in &members.users {
+            let expected = self.make_role_sync_request_with(members, linked_user, &linked_roles);
+
+            // active grants and overrides are sent along with the other roles, so they are managed for this user too
+            let user_roles: Vec<String> = self
+                .active_grants(linked_user.id)
+                .into_iter()
+                .map(|(id, _)| id)
+                .chain(
+                    self.active_overrides(linked_user.id)
+                        .into_iter()
+                        .map(|(id, _)| id),
+                )
+                .collect();
+
+            let user_managed = user_managed_roles(&managed, &user_roles);
+
+            for (account_id, account) in accounts_of(linked_user) {
+                let (missing, extra) =
+                    classify_drift(&expected.keep, server_roles.get(&account_id), &user_managed);
+
+                if missing.is_empty() && extra.is_empty() {
+                    continue;
+                }
+
+                report.drifted.push(DriftEntry {
+                    user_id: UserId::new(linked_user.id as u64),
+                    account_id,
+                    account,
+                    missing,
+                    extra,
+                    expected: RoleSyncRequest {
+                        account_id,
+                        ..expected.clone()
+                    },
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Sends the expected roles of every drifted account, returns the amount of accounts that were fixed
+    pub async fn fix_drift(&self, report: DriftReport) -> Result<usize, RoleSyncError> {
+        let mut fixed = 0;
+        let mut users: Vec<RoleSyncRequest> =
+            report.drifted.into_iter().map(|x| x.expected).collect();
+
+        while !users.is_empty() {
+            let rest = users.split_off(users.len().min(self.sync_chunk_size));
+            let data = RoleSyncRequestData { users };
+
+            self.send_sync_roles_req(&data).await?;
+            fixed += data.users.len();
+
+            users = rest;
+        }
+
+        Ok(fixed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{
+        extract::RawQuery,
+        http::{HeaderMap, StatusCode},
+        routing::get,
+        Router,
+    };
+
+    use super::*;
+
+    const PASSWORD: &str = "hunter2";
+
+    fn strings(roles: &[&str]) -> Vec<String> {
+        roles.iter().map(|x| (*x).to_owned()).collect()
+    }
+
+    // serves `/gsp/roles` like the Globed server does, returns the target and a counter of received requests
+    async fn mock_server(roles: HashMap<i32, Vec<String>>) -> (ServerTarget, Arc<AtomicUsize>) {
+        let roles = Arc::new(roles);
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        let handler = move |headers: HeaderMap, RawQuery(query): RawQuery| {
+            let roles = roles.clone();
+            counter.fetch_add(1, Ordering::Relaxed);
+
+            async move {
+                if headers.get("Authorization").and_then(|x| x.to_str().ok()) != Some(PASSWORD) {
+                    return (StatusCode::UNAUTHORIZED, "invalid password".to_owned());
+                }
+
+                let query = query.unwrap_or_default();
+                let users: Vec<_> = query
+                    .strip_prefix("account_ids=")
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter_map(|x| x.parse::<i32>().ok())
+                    .filter_map(|id| {
+                        roles
+                            .get(&id)
+                            .map(|r| serde_json::json!({ "account_id": id, "roles": r }))
+                    })
+                    .collect();
+
+                (
+                    StatusCode::OK,
+                    serde_json::json!({ "users": users }).to_string(),
+                )
+            }
+        };
+
+        let app = Router::new().route("/gsp/roles", get(handler));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let target = ServerTarget {
+            name: MAIN_TARGET.to_owned(),
+            base_url: format!("http://{addr}"),
+            password: PASSWORD.to_owned(),
+        };
+
+        (target, requests)
+    }
+
+    #[tokio::test]
+    async fn fetches_roles_of_known_accounts() {
+        let (target, _) = mock_server(HashMap::from([
+            (1, strings(&["mod", "vip"])),
+            (2, Vec::new()),
+        ]))
+        .await;
+
+        let roles = request_server_roles(&reqwest::Client::new(), &target, &[1, 2, 3])
+            .await
+            .unwrap_or_else(|e| panic!("{e}"));
+
+        assert_eq!(roles.len(), 2);
+        assert_eq!(roles[&1], strings(&["mod", "vip"]));
+        assert!(roles[&2].is_empty());
+        assert!(!roles.contains_key(&3));
+    }
+
+    #[tokio::test]
+    async fn splits_large_lookups_into_batches() {
+        let (target, requests) = mock_server(HashMap::from([(450, strings(&["vip"]))])).await;
+        let ids: Vec<i32> = (1..=450).collect();
+
+        let roles = request_server_roles(&reqwest::Client::new(), &target, &ids)
+            .await
+            .unwrap_or_else(|e| panic!("{e}"));
+
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+        assert_eq!(roles[&450], strings(&["vip"]));
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_requests() {
+        let (mut target, _) = mock_server(HashMap::new()).await;
+        target.password = "wrong".to_owned();
+
+        match request_server_roles(&reqwest::Client::new(), &target, &[1]).await {
+            Err(RoleSyncError::ServerUpdate((status, _))) => {
+                assert_eq!(status.as_u16(), 401);
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("request with a wrong password succeeded"),
+        }
+    }
+
+    #[tokio::test]
+    async fn classifies_drift_from_server_roles() {
+        let (target, _) = mock_server(HashMap::from([
+            // in sync, apart from a role the bot doesn't manage
+            (1, strings(&["vip", "event_winner"])),
+            // lost a role and got one they shouldn't have
+            (2, strings(&["booster"])),
+        ]))
+        .await;
+
+        let roles = request_server_roles(&reqwest::Client::new(), &target, &[1, 2, 3])
+            .await
+            .unwrap_or_else(|e| panic!("{e}"));
+
+        let managed = HashSet::from(["mod", "vip", "booster"]);
+        let expected = strings(&["vip"]);
+
+        let (missing, extra) = classify_drift(&expected, roles.get(&1), &managed);
+        assert!(missing.is_empty());
+        assert!(extra.is_empty());
+
+        let (missing, extra) = classify_drift(&expected, roles.get(&2), &managed);
+        assert_eq!(missing, strings(&["vip"]));
+        assert_eq!(extra, strings(&["booster"]));
+
+        // unknown to the server, so every expected role is missing
+        let (missing, extra) = classify_drift(&expected, roles.get(&3), &managed);
+        assert_eq!(missing, strings(&["vip"]));
+        assert!(extra.is_empty());
+    }
+
+    #[test]
+    fn reports_duplicate_server_roles_once() {
+        let managed = HashSet::from(["mod", "vip"]);
+        let actual = strings(&["vip", "mod", "vip"]);
+
+        let (missing, extra) = classify_drift(&strings(&["mod"]), Some(&actual), &managed);
+
+        assert!(missing.is_empty());
+        assert_eq!(extra, strings(&["vip"]));
+    }
+
+    #[test]
+    fn manages_granted_and_overridden_roles() {
+        let managed = HashSet::from(["vip"]);
+        // `event_winner` is granted, `beta` is forced to be kept and `booster` is forced to be removed
+        let user_roles = strings(&["event_winner", "beta", "booster"]);
+        let expected = strings(&["vip", "event_winner", "beta"]);
+        let actual = strings(&["vip", "event_winner", "beta", "booster"]);
+
+        let (missing, extra) = classify_drift(
+            &expected,
+            Some(&actual),
+            &user_managed_roles(&managed, &user_roles),
+        );
+
+        assert!(missing.is_empty());
+        assert_eq!(extra, strings(&["booster"]));
+
+        // once they are applied, the user is in sync
+        let (missing, extra) = classify_drift(
+            &expected,
+            Some(&strings(&["vip", "event_winner", "beta"])),
+            &user_managed_roles(&managed, &user_roles),
+        );
+
+        assert!(missing.is_empty());
+        assert!(extra.is_empty());
+    }
+
+    #[test]
+    fn ignores_roles_not_managed_by_the_bot() {
+        let managed = HashSet::from(["mod"]);
+        let actual = strings(&["owner", "custom"]);
+
+        let (missing, extra) = classify_drift(&strings(&["mod"]), Some(&actual), &managed);
+
+        assert_eq!(missing, strings(&["mod"]));
+        assert!(extra.is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+use serenity::all::Member;
+
+use super::{
+    alts::with_alt_requests,
+    synced::{synced_key, synced_role_list},
+    targets::target_request_data,
+    BotState, RoleSyncError, RoleSyncRequest, RoleSyncRequestData, SyncOptions,
+};
+use crate::serenity;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RoleChangeCount {
+    pub granted: usize,
+    pub revoked: usize,
+}
+
+/// Outcome of a sync that was only simulated, nothing is sent to the server
+#[derive(Default)]
+pub struct DryRunReport {
+    /// Requests that would be sent
+    pub payload: RoleSyncRequestData,
+    /// Linked users whose roles already match what the server has
+    pub users_unchanged: usize,
+    /// Users that would be sent, but whose roles on the server are unknown, so only granted roles are counted for them
+    pub users_unknown: usize,
+    /// Changes per Globed role, compared to the roles last synced to the server
+    pub roles: BTreeMap<String, RoleChangeCount>,
+    /// Requests that would be sent to each extra server
+    pub target_payloads: BTreeMap<String, RoleSyncRequestData>,
+}
+
+#[derive(Serialize)]
+struct PreviewPayload<'a> {
+    users: &'a [RoleSyncRequest],
+    targets: &'a BTreeMap<String, RoleSyncRequestData>,
+}
+
+impl DryRunReport {
+    /// Every request that would be sent, to the main server and to each extra server
+    pub fn payload_json(&self) -> Vec<u8> {
+        let payload = PreviewPayload {
+            users: &self.payload.users,
+            targets: &self.target_payloads,
+        };
+
+        // serializing can't fail, the requests are sent the same way during a sync
+        serde_json::to_vec_pretty(&payload).unwrap_or_default()
+    }
+
+    fn add(&mut self, req: RoleSyncRequest, synced: Option<&String>, options: SyncOptions) {
+        let previous: Option<Vec<String>> = synced.and_then(|x| serde_json::from_str(x).ok());
+
+        if !options.force && synced.is_some_and(|roles| *roles == synced_key(&req)) {
+            self.users_unchanged += 1;
+            return;
+        }
+
+        let keep = synced_role_list(&req);
+
+        match &previous {
+            Some(previous) => {
+                for role in keep.iter().filter(|x| !previous.contains(x)) {
+                    self.roles.entry(role.clone()).or_default().granted += 1;
+                }
+
+                for role in previous.iter().filter(|x| !keep.contains(x)) {
+                    self.roles.entry(role.clone()).or_default().revoked += 1;
+                }
+            }
+
+            None => {
+                self.users_unknown += 1;
+
+                for role in &keep {
+                    self.roles.entry(role.clone()).or_default().granted += 1;
+                }
+            }
+        }
+
+        self.payload.users.push(req);
+    }
+}
+
+impl BotState {
+    /// Builds the same requests as `sync_roles` would, without sending them
+    pub async fn preview_sync(
+        &self,
+        http: &serenity::Http,
+        user: &Member,
+        options: SyncOptions,
+    ) -> Result<DryRunReport, RoleSyncError> {
+        let reqs = self.make_role_sync_request(http, user).await?;
+
+        let mut report = DryRunReport::default();
+
+        for req in reqs {
+            let synced = self
+                .get_synced_roles(req.account_id)
+                .await?
+                .map(|x| x.roles);
+
+            report.add(req, synced.as_ref(), options);
+        }
+
+        self.add_target_payloads(&mut report);
+
+        Ok(report)
+    }
+
+    /// Builds the same requests as `sync_all_members` would, without sending them.
+    /// Doesn't touch the cursor of an interrupted sync.
+    pub async fn preview_sync_all(
+        &self,
+        http: &serenity::Http,
+        options: SyncOptions,
+    ) -> Result<DryRunReport, RoleSyncError> {
+        let linked_users = self.get_all_linked_users().await?;
+        let linked_roles = self.get_all_roles().await?;
+        let synced_roles: HashMap<i64, String> = self.get_all_synced_roles().await?;
+        let alts = self.get_all_alt_ids().await?;
+
+        let members = self.fetch_linked_members(http, &linked_users).await?;
+
+        let mut report = DryRunReport::default();
+
+        for (members, linked_user) in &members.users {
+            let req = self.make_role_sync_request_with(members, linked_user, &linked_roles);
+            let user_alts = alts
+                .get(&linked_user.id)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            for req in with_alt_requests(req, user_alts) {
+                let synced = synced_roles.get(&(req.account_id as i64));
+                report.add(req, synced, options);
+            }
+        }
+
+        self.add_target_payloads(&mut report);
+
+        Ok(report)
+    }
+
+    fn add_target_payloads(&self, report: &mut DryRunReport) {
+        for target in self.extra_targets() {
+            let data = target_request_data(&report.payload, &target.name);
+
+            if !data.users.is_empty() {
+                report.target_payloads.insert(target.name.clone(), data);
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use log::{info, warn};
+
+use super::{
+    alts::with_alt_requests, synced::synced_key, BotState, ReverseSyncReport, RoleSyncError,
+    RoleSyncRequest, RoleSyncRequestData, SyncOptions,
+};
+use crate::serenity::{self, all::UserId};
+
+// key in the `bot_state` table, holds the id of the last linked user whose chunk was sent
+const SYNC_CURSOR_KEY: &str = "sync_all_cursor";
+
+#[derive(Clone, Default, Debug)]
+pub struct SyncReport {
+    /// Whether the sync continued from where a previous, interrupted one stopped
+    pub resumed: bool,
+    pub pages_fetched: usize,
+    pub users_sent: usize,
+    /// Linked users whose roles already matched what the server has
+    pub users_unchanged: usize,
+    pub chunks_sent: usize,
+    /// Chunks that failed, but were queued for a retry
+    pub chunks_queued: usize,
+    /// Chunks the server rejected, their users weren't synced
+    pub chunks_failed: usize,
+    pub users_failed: usize,
+    /// Guild members that aren't linked to any GD account, counted once per guild
+    pub members_skipped: usize,
+    /// Linked users that weren't found in any guild
+    pub members_missing: usize,
+    /// Guilds whose members couldn't be fetched
+    pub guilds_failed: usize,
+    /// Linked users that weren't synced, because they are members of a guild that couldn't be fetched
+    pub users_incomplete: usize,
+    /// Discord roles changed to match roles granted on Globed
+    pub reverse: ReverseSyncReport,
+}
+
+impl Display for SyncReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} users synced in {} chunks ({} queued for a retry, {} with {} users rejected), {} unchanged, {} pages fetched, {} unlinked members skipped",
+            self.users_sent,
+            self.chunks_sent,
+            self.chunks_queued,
+            self.chunks_failed,
+            self.users_failed,
+            self.users_unchanged,
+            self.pages_fetched,
+            self.members_skipped
+        )?;
+
+        if self.members_missing > 0 {
+            write!(
+                f,
+                ", {} linked users missing from every guild",
+                self.members_missing
+            )?;
+        }
+
+        if self.guilds_failed > 0 {
+            write!(
+                f,
+                ", {} guilds couldn't be fetched and {} of their linked members were skipped",
+                self.guilds_failed, self.users_incomplete
+            )?;
+        }
+
+        if !self.reverse.is_empty() {
+            write!(
+                f,
+                ", {} discord roles added and {} removed from Globed ({} failed)",
+                self.reverse.roles_added, self.reverse.roles_removed, self.reverse.roles_failed
+            )?;
+        }
+
+        if self.resumed {
+            f.write_str(", resumed from a previous sync")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BotState {
+    /// Syncs roles of every linked member of the registered guilds, sending them in chunks.
+    /// If the sync gets interrupted, the next one continues after the last chunk that was sent.
+    pub async fn sync_all_members(
+        &self,
+        http: &serenity::Http,
+        options: SyncOptions,
+    ) -> Result<SyncReport, RoleSyncError> {
+        self.run_full_sync(http, options, false).await
+    }
+
+    // makes sure only one full sync runs at a time, and records the outcome for the status command
+    pub(super) async fn run_full_sync(
+        &self,
+        http: &serenity::Http,
+        options: SyncOptions,
+        periodic: bool,
+    ) -> Result<SyncReport, RoleSyncError> {
+        let Some(_guard) = self.begin_full_sync() else {
+            return Err(RoleSyncError::SyncInProgress);
+        };
+
+        let outcome = self.scan_and_sync_members(http, options).await;
+
+        self.record_full_sync(periodic, &outcome);
+
+        outcome
+    }
+
+    async fn scan_and_sync_members(
+        &self,
+        http: &serenity::Http,
+        options: SyncOptions,
+    ) -> Result<SyncReport, RoleSyncError> {
+        // get all linked users
+        let linked_users = self.get_all_linked_users().await?;
+
+        // get all linked roles
+        let linked_roles = self.get_all_roles().await?;
+
+        // roles the server already has, users whose roles didn't change can be skipped
+        let synced_roles = if options.force {
+            HashMap::new()
+        } else {
+            self.get_all_synced_roles().await?
+        };
+
+        // Perform quite a massive scan, every guild has to be seen before roles can be combined
+
+        let members = self.fetch_linked_members(http, &linked_users).await?;
+
+        let alts = self.get_all_alt_ids().await?;
+
+        let mut report = SyncReport {
+            pages_fetched: members.pages_fetched,
+            members_skipped: members.members_skipped,
+            guilds_failed: members.failed_guilds.len(),
+            users_incomplete: members.incomplete.len(),
+            ..Default::default()
+        };
+
+        self.store_memberships(&members).await?;
+
+        let cursor = self
+            .get_persistent_value(SYNC_CURSOR_KEY)
+            .await?
+            .and_then(|x| x.parse::<i64>().ok());
+
+        if let Some(cursor) = cursor {
+            info!("Resuming an interrupted sync after member {cursor}");
+            report.resumed = true;
+        }
+
+        let mut chunk = Vec::with_capacity(self.sync_chunk_size);
+
+        // once a chunk is rejected, the cursor stays before it, so resuming an interrupted sync sends it again
+        let mut keep_cursor = false;
+
+        // users are ordered by id, ones up to the cursor were already sent by the interrupted sync
+        for (members, linked_user) in members
+            .users
+            .iter()
+            .filter(|(_, user)| cursor.is_none_or(|c| user.id > c))
+        {
+            let req = self.make_role_sync_request_with(members, linked_user, &linked_roles);
+            let user_alts = alts
+                .get(&linked_user.id)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            // every linked account of the user gets its own request
+            for req in with_alt_requests(req, user_alts) {
+                if synced_roles
+                    .get(&(req.account_id as i64))
+                    .is_some_and(|roles| *roles == synced_key(&req))
+                {
+                    report.users_unchanged += 1;
+                    continue;
+                }
+
+                chunk.push(req);
+            }
+
+            if chunk.len() >= self.sync_chunk_size {
+                keep_cursor |= !self.send_sync_chunk(&mut chunk, &mut report).await;
+
+                if !keep_cursor {
+                    self.set_persistent_value(SYNC_CURSOR_KEY, &linked_user.id.to_string())
+                        .await?;
+                }
+            }
+        }
+
+        self.send_sync_chunk(&mut chunk, &mut report).await;
+
+        // pull roles that are granted on Globed back to discord, reusing the members found above
+        match self
+            .sync_reverse_roles(http, &members.users, &linked_roles)
+            .await
+        {
+            Ok(reverse) => report.reverse = reverse,
+            Err(err) => warn!("Failed to sync roles from Globed to Discord: {err}"),
+        }
+
+        // members that came back while the bot was offline keep their link
+        let seen: HashSet<u64> = members
+            .users
+            .iter()
+            .map(|(_, user)| user.id as u64)
+            .collect();
+
+        let seen_ids: Vec<u64> = seen.iter().copied().collect();
+        self.restore_departed_members(&seen_ids).await?;
+
+        // members in their grace period are expected to be gone
+        let departed = self.get_departed_ids().await?;
+
+        // members of guilds that couldn't be fetched weren't seen, but aren't gone either
+        let missing: Vec<UserId> = linked_users
+            .iter()
+            .map(|x| x.id as u64)
+            .filter(|id| {
+                !seen.contains(id) && !departed.contains(id) && !members.incomplete.contains(id)
+            })
+            .map(UserId::new)
+            .collect();
+
+        report.members_missing = missing.len();
+        self.handle_missing_members(&missing).await?;
+
+        self.remove_persistent_value(SYNC_CURSOR_KEY).await?;
+
+        Ok(report)
+    }
+
+    // returns false if the server rejected the chunk, failures that were queued for a retry count as sent
+    async fn send_sync_chunk(
+        &self,
+        chunk: &mut Vec<RoleSyncRequest>,
+        report: &mut SyncReport,
+    ) -> bool {
+        if chunk.is_empty() {
+            return true;
+        }
+
+        let data = RoleSyncRequestData {
+            users: std::mem::take(chunk),
+        };
+
+        report.chunks_sent += 1;
+
+        match self.send_sync_roles_req(&data).await {
+            Ok(()) => {
+                report.users_sent += data.users.len();
+                true
+            }
+
+            Err(err) if err.is_retryable() => {
+                report.chunks_queued += 1;
+                warn!(
+                    "Failed to sync a chunk of {} users, queued for a retry: {err}",
+                    data.users.len()
+                );
+                true
+            }
+
+            Err(err) => {
+                report.chunks_failed += 1;
+                report.users_failed += data.users.len();
+                warn!(
+                    "The server rejected a chunk of {} users, they were not synced: {err}",
+                    data.users.len()
+                );
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use log::{info, warn};
+use serenity::all::UserId;
+
+use super::{
+    expiry_after, now_timestamp, BotState, RoleSyncError, RoleSyncRequest, RoleSyncRequestData,
+};
+use crate::{
+    db::{LinkedUser, RoleGrant},
+    serenity,
+};
+
+// how often the expiry task checks for grants and overrides that ran out
+const EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+impl BotState {
+    // grants of the user that haven't expired yet, as (globed role, expiry timestamp)
+    pub(super) fn active_grants(&self, user_id: i64) -> Vec<(String, i64)> {
+        let now = now_timestamp();
+
+        self.role_grants
+            .read()
+            .get(&user_id)
+            .map(|grants| {
+                grants
+                    .iter()
+                    .filter(|(_, expires_at)| *expires_at > now)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Grants a Globed role to a linked user until the duration passes, returns the expiry timestamp.
+    /// Granting a role the user already has a grant for replaces its expiry.
+    pub async fn grant_role(
+        &self,
+        user_id: UserId,
+        globed_role_id: &str,
+        duration: Duration,
+        granted_by: UserId,
+    ) -> Result<i64, RoleSyncError> {
+        let user_id = user_id.get() as i64;
+        let granted_by = granted_by.get() as i64;
+        let expires_at = expiry_after(duration)
+            .ok_or(RoleSyncError::InternalError("grant duration is too long"))?;
+
+        let affected = sqlx::query!(
+            "INSERT INTO role_grants (user_id, globed_role_id, expires_at, granted_by)
+            SELECT id, ?, ?, ? FROM linked_users WHERE id = ?
+            ON CONFLICT(user_id, globed_role_id) DO UPDATE SET expires_at = excluded.expires_at, granted_by = excluded.granted_by",
+            globed_role_id,
+            expires_at,
+            granted_by,
+            user_id
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Err(RoleSyncError::NotLinked);
+        }
+
+        self.reload_grants().await?;
+
+        Ok(expires_at)
+    }
+
+    /// Removes a grant before it expires and takes the role away, returns whether the grant existed
+    pub async fn revoke_grant(
+        &self,
+        http: &serenity::Http,
+        user_id: UserId,
+        globed_role_id: &str,
+    ) -> Result<bool, RoleSyncError> {
+        let user_id_int = user_id.get() as i64;
+
+        let affected = sqlx::query!(
+            "DELETE FROM role_grants WHERE user_id = ? AND globed_role_id = ?",
+            user_id_int,
+            globed_role_id
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Ok(false);
+        }
+
+        self.reload_grants().await?;
+        self.resync_user_role(http, user_id_int, globed_role_id)
+            .await?;
+
+        Ok(true)
+    }
+
+    pub async fn get_user_grants(&self, user_id: UserId) -> Result<Vec<RoleGrant>, sqlx::Error> {
+        let user_id = user_id.get() as i64;
+
+        sqlx::query_as!(
+            RoleGrant,
+            "SELECT * FROM role_grants WHERE user_id = ? ORDER BY expires_at",
+            user_id
+        )
+        .fetch_all(&self.database)
+        .await
+    }
+
+    // caches all grants, so syncing doesn't have to query them
+    pub(super) async fn reload_grants(&self) -> Result<(), sqlx::Error> {
+        let mut grants: HashMap<i64, Vec<(String, i64)>> = HashMap::new();
+
+        for grant in sqlx::query_as!(RoleGrant, "SELECT * FROM role_grants")
+            .fetch_all(&self.database)
+            .await?
+        {
+            grants
+                .entry(grant.user_id)
+                .or_default()
+                .push((grant.globed_role_id, grant.expires_at));
+        }
+
+        *self.role_grants.write() = grants;
+
+        Ok(())
+    }
+
+    /// Background task that revokes grants and overrides once they expire
+    pub async fn run_expiry_task(self: Arc<Self>, http: Arc<serenity::Http>) {
+        loop {
+            if let Err(err) = self.expire_grants(&http).await {
+                warn!("Failed to revoke expired grants: {err}");
+            }
+
+            if let Err(err) = self.expire_overrides(&http).await {
+                warn!("Failed to remove expired overrides: {err}");
+            }
+
+            tokio::time::sleep(EXPIRY_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn expire_grants(&self, http: &serenity::Http) -> Result<(), RoleSyncError> {
+        let now = now_timestamp();
+
+        let expired = sqlx::query_as!(
+            RoleGrant,
+            "DELETE FROM role_grants WHERE expires_at <= ? RETURNING *",
+            now
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        self.reload_grants().await?;
+
+        for grant in expired {
+            info!(
+                "Temporary grant of `{}` to {} expired",
+                grant.globed_role_id, grant.user_id
+            );
+
+            // the grant is gone already, if this fails the outbox retries it
+            if let Err(err) = self
+                .resync_user_role(http, grant.user_id, &grant.globed_role_id)
+                .await
+            {
+                warn!(
+                    "Failed to remove expired grant `{}` from {}: {err}",
+                    grant.globed_role_id, grant.user_id
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // syncs the user after something that affected a role was removed, like a grant or an override.
+    // the role gets removed from the server unless something else grants it
+    pub(super) async fn resync_user_role(
+        &self,
+        http: &serenity::Http,
+        user_id: i64,
+        globed_role_id: &str,
+    ) -> Result<(), RoleSyncError> {
+        let Some(linked_user) = sqlx::query_as!(
+            LinkedUser,
+            "SELECT * FROM linked_users WHERE id = ?",
+            user_id
+        )
+        .fetch_optional(&self.database)
+        .await?
+        else {
+            return Ok(());
+        };
+
+        let members = self.fetch_memberships(http, &linked_user, None).await?;
+
+        let mut req = if members.is_empty() {
+            // not in any guild, only this role has to go
+            RoleSyncRequest {
+                account_id: linked_user.gd_account_id as i32,
+                keep: Vec::new(),
+                remove: Vec::new(),
+                targets: Vec::new(),
+            }
+        } else {
+            let roles = self.get_all_roles().await?;
+            self.make_role_sync_request_with(&members, &linked_user, &roles)
+        };
+
+        if !req.keep.iter().any(|x| x == globed_role_id)
+            && !req.remove.iter().any(|x| x == globed_role_id)
+        {
+            req.remove.push(globed_role_id.to_owned());
+        }
+
+        let users = self.with_alts(req, linked_user.id).await?;
+
+        self.send_sync_roles_req(&RoleSyncRequestData { users })
+            .await
+    }
+}
@@ -0,0 +1,252 @@
+use std::{collections::HashMap, fmt::Display};
+
+use super::BotState;
+use crate::db::{RoleGroup, RoleGroupMember};
+
+// group name and position of every grouped globed role of a server
+pub(super) type GroupTiers = HashMap<String, (String, i64)>;
+
+pub enum GroupError {
+    Database(sqlx::Error),
+    AlreadyExists,
+    NotFound,
+    RoleNotInGroup,
+    RoleInOtherGroup(String),
+}
+
+impl From<sqlx::Error> for GroupError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Database(value)
+    }
+}
+
+impl Display for GroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "Database error: {e}"),
+            Self::AlreadyExists => f.write_str("A group with this name already exists"),
+            Self::NotFound => f.write_str("No group with this name exists"),
+            Self::RoleNotInGroup => f.write_str("This Globed role is not in any group"),
+            Self::RoleInOtherGroup(group) => {
+                write!(f, "This Globed role is already in the group `{group}`")
+            }
+        }
+    }
+}
+
+impl BotState {
+    /// Returns every group with its roles, ordered from the highest tier
+    pub async fn get_all_groups(
+        &self,
+    ) -> Result<Vec<(RoleGroup, Vec<RoleGroupMember>)>, sqlx::Error> {
+        let groups = sqlx::query_as!(RoleGroup, "SELECT * FROM role_groups ORDER BY target, name")
+            .fetch_all(&self.database)
+            .await?;
+
+        let members = sqlx::query_as!(
+            RoleGroupMember,
+            "SELECT * FROM role_group_members ORDER BY group_name, position"
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        Ok(groups
+            .into_iter()
+            .map(|group| {
+                let in_group = members
+                    .iter()
+                    .filter(|x| x.group_name == group.name)
+                    .cloned()
+                    .collect();
+
+                (group, in_group)
+            })
+            .collect())
+    }
+
+    /// Creates a group for the roles of the given server
+    pub async fn create_group(&self, name: &str, target: &str) -> Result<(), GroupError> {
+        match sqlx::query!(
+            "INSERT INTO role_groups (name, target) VALUES (?, ?)",
+            name,
+            target
+        )
+        .execute(&self.database)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(sqlx::Error::Database(err))
+                if err.message().contains("UNIQUE constraint failed") =>
+            {
+                Err(GroupError::AlreadyExists)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn delete_group(&self, name: &str) -> Result<(), GroupError> {
+        let affected = sqlx::query!("DELETE FROM role_groups WHERE name = ?", name)
+            .execute(&self.database)
+            .await?
+            .rows_affected();
+
+        if affected == 0 {
+            return Err(GroupError::NotFound);
+        }
+
+        self.groups_changed().await?;
+
+        Ok(())
+    }
+
+    /// Puts a Globed role of the group's server into the group at the given position, or at the lowest tier
+    /// if none is given. Returns the position the role ended up at.
+    pub async fn add_role_to_group(
+        &self,
+        group: &str,
+        globed_role_id: &str,
+        position: Option<i64>,
+    ) -> Result<i64, GroupError> {
+        let mut tx = self.database.begin().await?;
+
+        let Some(target) =
+            sqlx::query_scalar!("SELECT target FROM role_groups WHERE name = ?", group)
+                .fetch_optional(&mut *tx)
+                .await?
+        else {
+            return Err(GroupError::NotFound);
+        };
+
+        if let Some(existing) = sqlx::query_scalar!(
+            "SELECT group_name FROM role_group_members WHERE target = ? AND globed_role_id = ?",
+            target,
+            globed_role_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        {
+            return Err(GroupError::RoleInOtherGroup(existing));
+        }
+
+        let size = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM role_group_members WHERE group_name = ?",
+            group
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let position = position.unwrap_or(size + 1).clamp(1, size + 1);
+
+        // make room for the new role
+        sqlx::query!(
+            "UPDATE role_group_members SET position = position + 1 WHERE group_name = ? AND position >= ?",
+            group,
+            position
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO role_group_members (globed_role_id, target, group_name, position) VALUES (?, ?, ?, ?)",
+            globed_role_id,
+            target,
+            group,
+            position
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.groups_changed().await?;
+
+        Ok(position)
+    }
+
+    pub async fn remove_role_from_group(
+        &self,
+        target: &str,
+        globed_role_id: &str,
+    ) -> Result<(), GroupError> {
+        let mut tx = self.database.begin().await?;
+
+        let Some(removed) = sqlx::query_as!(
+            RoleGroupMember,
+            "DELETE FROM role_group_members WHERE target = ? AND globed_role_id = ? RETURNING *",
+            target,
+            globed_role_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Err(GroupError::RoleNotInGroup);
+        };
+
+        // close the gap
+        sqlx::query!(
+            "UPDATE role_group_members SET position = position - 1 WHERE group_name = ? AND position > ?",
+            removed.group_name,
+            removed.position
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.groups_changed().await?;
+
+        Ok(())
+    }
+
+    async fn groups_changed(&self) -> Result<(), sqlx::Error> {
+        self.invalidate_synced_roles().await?;
+        self.reload_groups().await
+    }
+
+    // caches group membership of every globed role, so syncing doesn't have to query it
+    pub(super) async fn reload_groups(&self) -> Result<(), sqlx::Error> {
+        let mut groups: HashMap<String, GroupTiers> = HashMap::new();
+
+        for member in sqlx::query_as!(RoleGroupMember, "SELECT * FROM role_group_members")
+            .fetch_all(&self.database)
+            .await?
+        {
+            groups
+                .entry(member.target)
+                .or_default()
+                .insert(member.globed_role_id, (member.group_name, member.position));
+        }
+
+        *self.role_groups.write() = groups;
+
+        Ok(())
+    }
+
+    /// Leaves only the highest-tier role of each group of the server granted, all other roles of the group get removed
+    pub(super) fn apply_exclusive_groups(&self, target: &str, granted: &mut [(String, bool)]) {
+        let groups = self.role_groups.read();
+        let Some(groups) = groups.get(target) else {
+            return;
+        };
+
+        // group name -> (position, globed role) of the best role the user qualifies for
+        let mut best: HashMap<&str, (i64, &str)> = HashMap::new();
+
+        for (id, _) in granted.iter().filter(|(_, has)| *has) {
+            if let Some((group, position)) = groups.get(id) {
+                let entry = best.entry(group).or_insert((*position, id));
+                if *position < entry.0 {
+                    *entry = (*position, id);
+                }
+            }
+        }
+
+        let winners: Vec<String> = best.into_values().map(|(_, id)| id.to_owned()).collect();
+
+        for (id, has) in granted.iter_mut() {
+            if *has && groups.contains_key(id) && !winners.contains(id) {
+                *has = false;
+            }
+        }
+    }
+}
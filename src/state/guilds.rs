@@ -0,0 +1,504 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use log::{debug, warn};
+use serenity::all::{GuildId, Member, UserId};
+
+use super::{now_timestamp, BotState, RoleSyncError};
+use crate::{
+    db::{Guild, LinkedUser, Role},
+    serenity,
+};
+
+pub enum GuildError {
+    Database(sqlx::Error),
+    AlreadyRegistered,
+    NotFound,
+    HomeGuild,
+}
+
+impl From<sqlx::Error> for GuildError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Database(value)
+    }
+}
+
+impl Display for GuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "Database error: {e}"),
+            Self::AlreadyRegistered => f.write_str("This guild is already registered"),
+            Self::NotFound => f.write_str("This guild is not registered"),
+            Self::HomeGuild => f.write_str("The home guild of the bot can't be removed"),
+        }
+    }
+}
+
+/// Memberships of a single linked user, found without changing the recorded ones
+#[derive(Default)]
+pub(super) struct MembershipScan {
+    pub members: Vec<Member>,
+    /// Whether the membership passed by the caller isn't recorded yet
+    pub known_is_new: bool,
+    /// Recorded guilds the user isn't a member of anymore
+    pub left_guilds: Vec<GuildId>,
+}
+
+/// Linked members found while paging through every registered guild
+pub(super) struct LinkedMembers<'a> {
+    /// Memberships of every linked user that is in at least one guild, ordered by discord id
+    pub users: Vec<(Vec<Member>, &'a LinkedUser)>,
+    pub pages_fetched: usize,
+    /// Guild members that aren't linked to any GD account
+    pub members_skipped: usize,
+    /// Guilds whose members couldn't be fetched
+    pub failed_guilds: Vec<GuildId>,
+    /// Linked users recorded as members of a failed guild. They are left out of `users`, as their roles would be incomplete.
+    pub incomplete: HashSet<u64>,
+}
+
+fn is_unknown_member(err: &serenity::Error) -> bool {
+    matches!(err, serenity::Error::Http(e) if e.status_code().is_some_and(|x| x.as_u16() == 404))
+}
+
+pub(super) async fn max_accounts<'e, E>(executor: E, guild_id: GuildId) -> Result<i64, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    let guild_id = guild_id.get() as i64;
+
+    Ok(
+        sqlx::query_scalar!("SELECT max_accounts FROM guilds WHERE id = ?", guild_id)
+            .fetch_optional(executor)
+            .await?
+            .unwrap_or(1),
+    )
+}
+
+impl BotState {
+    /// Whether commands and events from this guild are handled
+    pub fn is_allowed_guild(&self, guild_id: GuildId) -> bool {
+        self.allowed_guilds.read().contains(&guild_id)
+    }
+
+    pub fn allowed_guilds(&self) -> Vec<GuildId> {
+        self.allowed_guilds.read().clone()
+    }
+
+    pub async fn get_all_guilds(&self) -> Result<Vec<Guild>, sqlx::Error> {
+        sqlx::query_as!(Guild, "SELECT * FROM guilds ORDER BY added_at")
+            .fetch_all(&self.database)
+            .await
+    }
+
+    // makes sure the home guild is registered, and moves data from before multi-guild support into it
+    pub(super) async fn register_home_guild(&self) -> Result<(), sqlx::Error> {
+        let guild_id = self.home_guild_id.get() as i64;
+        let now = now_timestamp();
+
+        let mut tx = self.database.begin().await?;
+
+        sqlx::query!(
+            "INSERT INTO guilds (id, added_at) VALUES (?, ?) ON CONFLICT(id) DO NOTHING",
+            guild_id,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("UPDATE roles SET guild_id = ? WHERE guild_id = 0", guild_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!(
+            "UPDATE OR IGNORE linked_user_guilds SET guild_id = ? WHERE guild_id = 0",
+            guild_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM linked_user_guilds WHERE guild_id = 0")
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Allows commands in the guild and starts syncing roles of its members
+    pub async fn add_guild(&self, guild_id: GuildId, name: &str) -> Result<(), GuildError> {
+        let guild_id = guild_id.get() as i64;
+        let now = now_timestamp();
+
+        match sqlx::query!(
+            "INSERT INTO guilds (id, name, added_at) VALUES (?, ?, ?)",
+            guild_id,
+            name,
+            now
+        )
+        .execute(&self.database)
+        .await
+        {
+            Ok(_) => {}
+            Err(sqlx::Error::Database(err))
+                if err.message().contains("UNIQUE constraint failed") =>
+            {
+                return Err(GuildError::AlreadyRegistered);
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        self.reload_guilds().await?;
+
+        Ok(())
+    }
+
+    /// How many GD accounts members of the guild can link, 1 if the guild isn't registered
+    pub async fn get_max_accounts(&self, guild_id: GuildId) -> Result<i64, sqlx::Error> {
+        max_accounts(&self.database, guild_id).await
+    }
+
+    /// Changes how many GD accounts members of the guild can link. Accounts above the new limit stay linked.
+    pub async fn set_max_accounts(
+        &self,
+        guild_id: GuildId,
+        max_accounts: i64,
+    ) -> Result<(), GuildError> {
+        let guild_id = guild_id.get() as i64;
+
+        let affected = sqlx::query!(
+            "UPDATE guilds SET max_accounts = ? WHERE id = ?",
+            max_accounts,
+            guild_id
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Err(GuildError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Unregisters the guild and deletes its role mappings. Globed roles that aren't mapped in any other guild
+    /// are taken away from its linked members. Returns the amount of deleted mappings, and the amount of accounts the roles were removed from.
+    pub async fn remove_guild(&self, guild_id: GuildId) -> Result<(usize, usize), GuildError> {
+        if guild_id == self.home_guild_id {
+            return Err(GuildError::HomeGuild);
+        }
+
+        let guild_id_int = guild_id.get() as i64;
+
+        // the memberships are deleted below, but the members still have the roles of the guild
+        let accounts = self.get_guild_accounts(guild_id).await?;
+
+        let mut tx = self.database.begin().await?;
+
+        let affected = sqlx::query!("DELETE FROM guilds WHERE id = ?", guild_id_int)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        if affected == 0 {
+            return Err(GuildError::NotFound);
+        }
+
+        let deleted = sqlx::query_as!(
+            Role,
+            "DELETE FROM roles WHERE guild_id = ? RETURNING *",
+            guild_id_int
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM linked_user_guilds WHERE guild_id = ?",
+            guild_id_int
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.invalidate_synced_roles().await?;
+        self.reload_guilds().await?;
+        self.reload_watched_roles().await?;
+
+        let remaining = self.get_all_roles().await?;
+
+        let report = self
+            .strip_unmapped_roles(&deleted, &remaining, &accounts)
+            .await?;
+
+        Ok((deleted.len(), report.users_stripped))
+    }
+
+    pub(super) async fn reload_guilds(&self) -> Result<(), sqlx::Error> {
+        let guilds = sqlx::query_scalar!("SELECT id FROM guilds ORDER BY added_at")
+            .fetch_all(&self.database)
+            .await?
+            .into_iter()
+            .map(|id| GuildId::new(id as u64))
+            .collect();
+
+        *self.allowed_guilds.write() = guilds;
+
+        Ok(())
+    }
+
+    /// Fetches every membership of a linked user in the registered guilds. `known` is a membership the caller
+    /// already has, it gets recorded if it's new. Memberships of guilds the user left in the meantime are forgotten.
+    pub(super) async fn fetch_memberships(
+        &self,
+        http: &serenity::Http,
+        linked_user: &LinkedUser,
+        known: Option<&Member>,
+    ) -> Result<Vec<Member>, RoleSyncError> {
+        let scan = self.scan_memberships(http, linked_user, known).await?;
+
+        if let Some(member) = known.filter(|_| scan.known_is_new) {
+            self.add_membership(linked_user.id, member.guild_id).await?;
+        }
+
+        for guild_id in scan.left_guilds {
+            debug!("{} is not in guild {guild_id} anymore", linked_user.id);
+            self.remove_membership(linked_user.id, guild_id).await?;
+        }
+
+        Ok(scan.members)
+    }
+
+    /// Same as `fetch_memberships`, but only reads, the recorded memberships are left as they are
+    pub(super) async fn scan_memberships(
+        &self,
+        http: &serenity::Http,
+        linked_user: &LinkedUser,
+        known: Option<&Member>,
+    ) -> Result<MembershipScan, RoleSyncError> {
+        let user_id = UserId::new(linked_user.id as u64);
+
+        let recorded = sqlx::query_scalar!(
+            "SELECT guild_id FROM linked_user_guilds WHERE user_id = ?",
+            linked_user.id
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        let mut scan = MembershipScan::default();
+
+        if let Some(member) = known {
+            scan.known_is_new = !recorded.contains(&(member.guild_id.get() as i64));
+            scan.members.push(member.clone());
+        }
+
+        for guild_id in recorded.into_iter().map(|x| GuildId::new(x as u64)) {
+            if known.is_some_and(|x| x.guild_id == guild_id) || !self.is_allowed_guild(guild_id) {
+                continue;
+            }
+
+            match http.get_member(guild_id, user_id).await {
+                Ok(member) => scan.members.push(member),
+                Err(err) if is_unknown_member(&err) => scan.left_guilds.push(guild_id),
+                Err(err) => return Err(RoleSyncError::MemberFetch(err)),
+            }
+        }
+
+        Ok(scan)
+    }
+
+    // every GD account, alts included, of linked users that are recorded as members of the guild
+    pub(super) async fn get_guild_accounts(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<Vec<i32>, sqlx::Error> {
+        let guild_id = guild_id.get() as i64;
+
+        Ok(sqlx::query_scalar!(
+            "SELECT u.gd_account_id FROM linked_users u JOIN linked_user_guilds g ON g.user_id = u.id WHERE g.guild_id = ?
+            UNION SELECT a.gd_account_id FROM linked_alts a JOIN linked_user_guilds g ON g.user_id = a.user_id WHERE g.guild_id = ?",
+            guild_id,
+            guild_id
+        )
+        .fetch_all(&self.database)
+        .await?
+        .into_iter()
+        .map(|x| x as i32)
+        .collect())
+    }
+
+    pub(super) async fn add_membership(
+        &self,
+        user_id: i64,
+        guild_id: GuildId,
+    ) -> Result<(), sqlx::Error> {
+        let guild_id = guild_id.get() as i64;
+
+        sqlx::query!(
+            "INSERT INTO linked_user_guilds (user_id, guild_id) SELECT id, ? FROM linked_users WHERE id = ?
+            ON CONFLICT(user_id, guild_id) DO NOTHING",
+            guild_id,
+            user_id
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok(())
+    }
+
+    pub(super) async fn remove_membership(
+        &self,
+        user_id: i64,
+        guild_id: GuildId,
+    ) -> Result<(), sqlx::Error> {
+        let guild_id = guild_id.get() as i64;
+
+        sqlx::query!(
+            "DELETE FROM linked_user_guilds WHERE user_id = ? AND guild_id = ?",
+            user_id,
+            guild_id
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok(())
+    }
+
+    // replaces all recorded memberships with the ones found during a scan of every guild
+    pub(super) async fn store_memberships(
+        &self,
+        members: &LinkedMembers<'_>,
+    ) -> Result<(), sqlx::Error> {
+        let pairs: Vec<(u64, u64)> = members
+            .users
+            .iter()
+            .flat_map(|(members, _)| members.iter().map(|x| (x.user.id.get(), x.guild_id.get())))
+            .collect();
+
+        let pairs = serde_json::to_string(&pairs).unwrap_or_default();
+
+        let failed: Vec<u64> = members.failed_guilds.iter().map(|x| x.get()).collect();
+        let failed = serde_json::to_string(&failed).unwrap_or_default();
+
+        let mut tx = self.database.begin().await?;
+
+        // guilds that couldn't be fetched keep what was recorded before
+        sqlx::query!(
+            "DELETE FROM linked_user_guilds WHERE guild_id NOT IN (SELECT value FROM json_each(?))",
+            failed
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // users could have been unlinked while the guilds were being scanned
+        sqlx::query!(
+            "INSERT INTO linked_user_guilds (user_id, guild_id)
+            SELECT json_extract(value, '$[0]'), json_extract(value, '$[1]') FROM json_each(?)
+            WHERE json_extract(value, '$[0]') IN (SELECT id FROM linked_users)
+            ON CONFLICT(user_id, guild_id) DO NOTHING",
+            pairs
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // pages through every registered guild and returns the memberships of users that are linked to a GD account.
+    // guilds that fail to be fetched are skipped, along with the users recorded as their members
+    pub(super) async fn fetch_linked_members<'a>(
+        &self,
+        http: &serenity::Http,
+        linked_users: &'a [LinkedUser],
+    ) -> Result<LinkedMembers<'a>, RoleSyncError> {
+        let linked: HashMap<u64, &LinkedUser> =
+            linked_users.iter().map(|x| (x.id as u64, x)).collect();
+
+        let mut found: HashMap<u64, Vec<Member>> = HashMap::with_capacity(linked_users.len());
+        let mut pages_fetched = 0;
+        let mut members_skipped = 0;
+        let mut failed_guilds = Vec::new();
+        let guilds = self.allowed_guilds();
+
+        'guilds: for guild_id in guilds.iter().copied() {
+            let mut after = None;
+
+            loop {
+                let members = match http.get_guild_members(guild_id, None, after).await {
+                    Ok(x) => x,
+                    // without any guild there's nothing to sync, and everyone would look missing
+                    Err(err) if failed_guilds.len() + 1 == guilds.len() => {
+                        return Err(RoleSyncError::MemberFetch(err));
+                    }
+                    Err(err) => {
+                        warn!("Failed to fetch members of guild {guild_id}, skipping it: {err}");
+                        failed_guilds.push(guild_id);
+                        continue 'guilds;
+                    }
+                };
+
+                pages_fetched += 1;
+
+                if members.is_empty() {
+                    break;
+                }
+
+                after = Some(members.last().unwrap().user.id.get());
+
+                for member in members {
+                    let member_id = member.user.id.get();
+
+                    if linked.contains_key(&member_id) {
+                        found.entry(member_id).or_default().push(member);
+                    } else {
+                        members_skipped += 1;
+                    }
+                }
+            }
+        }
+
+        let incomplete = self.get_guild_members(&failed_guilds).await?;
+
+        let mut users: Vec<(Vec<Member>, &LinkedUser)> = found
+            .into_iter()
+            .filter(|(id, _)| !incomplete.contains(id))
+            .map(|(id, members)| (members, linked[&id]))
+            .collect();
+
+        users.sort_by_key(|(_, user)| user.id);
+
+        Ok(LinkedMembers {
+            users,
+            pages_fetched,
+            members_skipped,
+            failed_guilds,
+            incomplete,
+        })
+    }
+
+    // linked users recorded as members of any of the guilds
+    async fn get_guild_members(&self, guild_ids: &[GuildId]) -> Result<HashSet<u64>, sqlx::Error> {
+        if guild_ids.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let ids: Vec<u64> = guild_ids.iter().map(|x| x.get()).collect();
+        let ids = serde_json::to_string(&ids).unwrap_or_default();
+
+        Ok(sqlx::query_scalar!(
+            "SELECT DISTINCT user_id FROM linked_user_guilds WHERE guild_id IN (SELECT value FROM json_each(?))",
+            ids
+        )
+        .fetch_all(&self.database)
+        .await?
+        .into_iter()
+        .map(|x| x as u64)
+        .collect())
+    }
+}
@@ -0,0 +1,285 @@
+use std::fmt::Display;
+
+use serenity::all::UserId;
+
+use super::{now_timestamp, BotState};
+use crate::{db::LinkEvent, serenity};
+
+// max amount of events shown in a timeline
+const HISTORY_LIMIT: i64 = 25;
+
+/// What happened to a link, stored in the link history
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkEventKind {
+    Link,
+    Unlink,
+    AdminLink,
+    AdminUnlink,
+    LeaveUnlink,
+    Transfer,
+    /// Linking got locked after too many failed attempts
+    Lockout,
+    /// A staff member lifted a lockout
+    LockoutClear,
+}
+
+impl LinkEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Link => "link",
+            Self::Unlink => "unlink",
+            Self::AdminLink => "admin_link",
+            Self::AdminUnlink => "admin_unlink",
+            Self::LeaveUnlink => "leave_unlink",
+            Self::Transfer => "transfer",
+            Self::Lockout => "lockout",
+            Self::LockoutClear => "lockout_clear",
+        }
+    }
+
+    fn from_db(kind: &str) -> Option<Self> {
+        match kind {
+            "link" => Some(Self::Link),
+            "unlink" => Some(Self::Unlink),
+            "admin_link" => Some(Self::AdminLink),
+            "admin_unlink" => Some(Self::AdminUnlink),
+            "leave_unlink" => Some(Self::LeaveUnlink),
+            "transfer" => Some(Self::Transfer),
+            "lockout" => Some(Self::Lockout),
+            "lockout_clear" => Some(Self::LockoutClear),
+            _ => None,
+        }
+    }
+}
+
+/// Who changed a link
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkActor {
+    /// The linked user themselves
+    User,
+    /// A staff member, with a command
+    Admin(UserId),
+    /// The bot, for example after the user left every guild
+    System,
+}
+
+impl LinkActor {
+    pub(super) fn link_kind(self) -> LinkEventKind {
+        match self {
+            Self::Admin(_) => LinkEventKind::AdminLink,
+            Self::User | Self::System => LinkEventKind::Link,
+        }
+    }
+
+    pub(super) fn unlink_kind(self) -> LinkEventKind {
+        match self {
+            Self::User => LinkEventKind::Unlink,
+            Self::Admin(_) => LinkEventKind::AdminUnlink,
+            Self::System => LinkEventKind::LeaveUnlink,
+        }
+    }
+
+    fn id(self, user_id: i64) -> Option<i64> {
+        match self {
+            Self::User => Some(user_id),
+            Self::Admin(id) => Some(id.get() as i64),
+            Self::System => None,
+        }
+    }
+}
+
+// one line of a timeline, e.g. "<t:..:f> <@1> was linked to GD account 2 by <@3>"
+impl Display for LinkEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<t:{}:f> ", self.created_at)?;
+
+        let actor = match self.actor_id {
+            Some(id) if Some(id) == self.user_id => "themselves".to_owned(),
+            Some(id) => format!("<@{id}>"),
+            None => "the bot".to_owned(),
+        };
+
+        // lockouts are about either a discord user or a GD username
+        let locked = match &self.gd_username {
+            Some(name) => format!("GD username `{name}`"),
+            None => format!("<@{}>", self.user_id.unwrap_or_default()),
+        };
+
+        let (user, account) = (
+            self.user_id.unwrap_or_default(),
+            self.gd_account_id.unwrap_or_default(),
+        );
+
+        match LinkEventKind::from_db(&self.kind) {
+            Some(LinkEventKind::Link | LinkEventKind::AdminLink) => {
+                write!(f, "<@{user}> was linked to GD account {account} by {actor}")
+            }
+            Some(LinkEventKind::Unlink | LinkEventKind::AdminUnlink) => {
+                write!(
+                    f,
+                    "<@{user}> was unlinked from GD account {account} by {actor}"
+                )
+            }
+            Some(LinkEventKind::LeaveUnlink) => write!(
+                f,
+                "<@{user}> was unlinked from GD account {account} after leaving"
+            ),
+            Some(LinkEventKind::Transfer) => match self.from_user_id {
+                Some(from) => write!(
+                    f,
+                    "GD account {account} was transferred from <@{from}> to <@{user}> by {actor}"
+                ),
+                None => write!(
+                    f,
+                    "GD account {account} was transferred to <@{user}> by {actor}"
+                ),
+            },
+            Some(LinkEventKind::Lockout) if self.gd_username.is_some() => write!(
+                f,
+                "linking was locked for {locked} after too many failed attempts, the last one by <@{user}>"
+            ),
+            Some(LinkEventKind::Lockout) => write!(
+                f,
+                "linking was locked for {locked} after too many failed attempts"
+            ),
+            Some(LinkEventKind::LockoutClear) => {
+                write!(f, "the lockout of {locked} was lifted by {actor}")
+            }
+            None => write!(
+                f,
+                "unknown event `{}` for <@{user}> and GD account {account}",
+                self.kind
+            ),
+        }
+    }
+}
+
+impl BotState {
+    pub(super) async fn record_link_event<'e, E>(
+        &self,
+        executor: E,
+        kind: LinkEventKind,
+        actor: LinkActor,
+        user_id: i64,
+        gd_account_id: i64,
+    ) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+    {
+        let kind = kind.as_str();
+        let actor_id = actor.id(user_id);
+        let now = now_timestamp();
+
+        sqlx::query!(
+            "INSERT INTO link_events (kind, actor_id, user_id, gd_account_id, created_at) VALUES (?, ?, ?, ?, ?)",
+            kind,
+            actor_id,
+            user_id,
+            gd_account_id,
+            now
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    pub(super) async fn record_transfer_event<'e, E>(
+        &self,
+        executor: E,
+        actor: LinkActor,
+        from_id: i64,
+        to_id: i64,
+        gd_account_id: i64,
+    ) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+    {
+        let kind = LinkEventKind::Transfer.as_str();
+        let actor_id = actor.id(to_id);
+        let now = now_timestamp();
+
+        sqlx::query!(
+            "INSERT INTO link_events (kind, actor_id, user_id, from_user_id, gd_account_id, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+            kind,
+            actor_id,
+            to_id,
+            from_id,
+            gd_account_id,
+            now
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    // lockouts have no account, `gd_username` is set if the username was locked rather than the discord user
+    pub(super) async fn record_lockout_event<'e, E>(
+        &self,
+        executor: E,
+        kind: LinkEventKind,
+        actor: LinkActor,
+        user_id: Option<i64>,
+        gd_username: Option<&str>,
+    ) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+    {
+        let kind = kind.as_str();
+        let actor_id = match actor {
+            LinkActor::Admin(id) => Some(id.get() as i64),
+            LinkActor::User | LinkActor::System => None,
+        };
+        let now = now_timestamp();
+
+        sqlx::query!(
+            "INSERT INTO link_events (kind, actor_id, user_id, gd_username, created_at) VALUES (?, ?, ?, ?, ?)",
+            kind,
+            actor_id,
+            user_id,
+            gd_username,
+            now
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Latest link events of a discord user, including accounts transferred away from them, oldest first
+    pub async fn get_user_history(&self, user_id: UserId) -> Result<Vec<LinkEvent>, sqlx::Error> {
+        let user_id = user_id.get() as i64;
+
+        let mut events = sqlx::query_as!(
+            LinkEvent,
+            "SELECT * FROM link_events WHERE user_id = ? OR from_user_id = ? ORDER BY id DESC LIMIT ?",
+            user_id,
+            user_id,
+            HISTORY_LIMIT
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        events.reverse();
+        Ok(events)
+    }
+
+    /// Latest link events of a GD account, oldest first
+    pub async fn get_account_history(
+        &self,
+        account_id: i32,
+    ) -> Result<Vec<LinkEvent>, sqlx::Error> {
+        let mut events = sqlx::query_as!(
+            LinkEvent,
+            "SELECT * FROM link_events WHERE gd_account_id = ? ORDER BY id DESC LIMIT ?",
+            account_id,
+            HISTORY_LIMIT
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        events.reverse();
+        Ok(events)
+    }
+}
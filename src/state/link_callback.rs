@@ -0,0 +1,495 @@
+use std::{fmt::Display, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serenity::all::{
+    ButtonStyle, ComponentInteraction, CreateActionRow, CreateButton, CreateInteractionResponse,
+    CreateInteractionResponseMessage, CreateMessage, EditInteractionResponse, GuildId, Member,
+    UserId,
+};
+
+use super::{
+    link_codes::LinkCodeError, now_timestamp, AttemptKind, BotState, LinkActor, LinkError,
+    SyncOptions,
+};
+use crate::serenity;
+
+// how long a user has to confirm a link started in-game, in seconds
+const LINK_CONFIRM_TIMEOUT: i64 = 10 * 60;
+// max amount of members fetched when looking up a discord username
+const MEMBER_SEARCH_LIMIT: u64 = 10;
+
+const CONFIRM_PREFIX: &str = "link_confirm:";
+const DENY_PREFIX: &str = "link_deny:";
+
+/// A link started in-game, waiting for the discord user to confirm it
+pub(super) struct PendingConfirmation {
+    user_id: UserId,
+    guild_id: GuildId,
+    account_id: i32,
+    gd_username: String,
+    expires_at: i64,
+}
+
+// body of the request the Globed server sends when a player starts linking in-game
+#[derive(Deserialize)]
+struct LinkCallback {
+    account_id: i32,
+    username: String,
+    discord_username: String,
+}
+
+// body of the request the Globed server sends when a player enters a code from `/linkstart`
+#[derive(Deserialize)]
+struct LinkCodeCallback {
+    code: String,
+    account_id: i32,
+    username: String,
+}
+
+pub enum LinkCallbackError {
+    Database(sqlx::Error),
+    MemberSearch(serenity::Error),
+    InvalidUsername,
+    UserNotFound,
+    AlreadyLinked,
+    AccountLinked,
+    DirectMessage(serenity::Error),
+    // seconds until another link can be started
+    RateLimited(i64),
+}
+
+impl From<sqlx::Error> for LinkCallbackError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Database(value)
+    }
+}
+
+impl Display for LinkCallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "Database error: {e}"),
+            Self::MemberSearch(e) => write!(f, "Failed to search for the Discord user: {e}"),
+            Self::InvalidUsername => f.write_str("Invalid GD username"),
+            Self::UserNotFound => f.write_str("No member with this Discord username was found"),
+            Self::AlreadyLinked => {
+                f.write_str("This Discord account can't link any more GD accounts")
+            }
+            Self::AccountLinked => f.write_str("This GD account is already linked"),
+            Self::DirectMessage(e) => write!(f, "Failed to send a direct message: {e}"),
+            Self::RateLimited(secs) => {
+                write!(f, "Too many link requests, try again in {secs} seconds")
+            }
+        }
+    }
+}
+
+impl LinkCallbackError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::Database(_) | Self::MemberSearch(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidUsername => StatusCode::BAD_REQUEST,
+            Self::UserNotFound => StatusCode::NOT_FOUND,
+            Self::AlreadyLinked | Self::AccountLinked => StatusCode::CONFLICT,
+            // most likely the user doesn't accept direct messages
+            Self::DirectMessage(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl LinkCodeError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::Database(_) | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Disabled | Self::InvalidCode => StatusCode::NOT_FOUND,
+            Self::AlreadyLinked | Self::AccountLinked => StatusCode::CONFLICT,
+        }
+    }
+}
+
+type ListenerState = (Arc<BotState>, Arc<serenity::Http>);
+
+// requests have to come from the Globed server, which knows the shared secret
+fn is_authorized(state: &BotState, headers: &HeaderMap) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .is_some_and(|x| constant_time_eq(x.as_bytes(), state.main_target().password.as_bytes()))
+}
+
+// compares every byte, so the time taken doesn't reveal how much of the secret was guessed
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn handle_callback(
+    State((state, http)): State<ListenerState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, String) {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized".to_owned());
+    }
+
+    let callback: LinkCallback = match serde_json::from_slice(&body) {
+        Ok(x) => x,
+        Err(err) => return (StatusCode::BAD_REQUEST, format!("Malformed request: {err}")),
+    };
+
+    match state
+        .start_ingame_link(
+            &http,
+            callback.account_id,
+            &callback.username,
+            &callback.discord_username,
+        )
+        .await
+    {
+        Ok(()) => (StatusCode::ACCEPTED, "Confirmation sent".to_owned()),
+        Err(err) => {
+            if err.status().is_server_error() {
+                warn!("Failed to start an in-game link: {err}");
+            }
+
+            (err.status(), err.to_string())
+        }
+    }
+}
+
+async fn handle_code_callback(
+    State((state, http)): State<ListenerState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, String) {
+    if !is_authorized(&state, &headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized".to_owned());
+    }
+
+    let callback: LinkCodeCallback = match serde_json::from_slice(&body) {
+        Ok(x) => x,
+        Err(err) => return (StatusCode::BAD_REQUEST, format!("Malformed request: {err}")),
+    };
+
+    match state
+        .redeem_link_code(
+            &http,
+            &callback.code,
+            callback.account_id,
+            &callback.username,
+        )
+        .await
+    {
+        Ok(username) => (StatusCode::OK, username),
+        Err(err) => {
+            if err.status().is_server_error() {
+                warn!("Failed to redeem a link code: {err}");
+            }
+
+            (err.status(), err.to_string())
+        }
+    }
+}
+
+impl BotState {
+    /// Background task that listens for links started in-game and link codes entered in-game, if `BOT_LINK_LISTEN_ADDR` is set
+    pub async fn run_link_listener(self: Arc<Self>, http: Arc<serenity::Http>) {
+        let Some(addr) = self.link_listen_addr else {
+            return;
+        };
+
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Failed to start the link listener on {addr}: {err}");
+                return;
+            }
+        };
+
+        info!("Listening for in-game links on {addr}");
+
+        let app = Router::new()
+            .route("/link", post(handle_callback))
+            .route("/link/code", post(handle_code_callback))
+            .with_state((self, http));
+
+        if let Err(err) = axum::serve(listener, app).await {
+            error!("Link listener stopped: {err}");
+        }
+    }
+
+    /// Looks up the discord user a player wants to link to, and asks them to confirm in a direct message
+    pub async fn start_ingame_link(
+        &self,
+        http: &serenity::Http,
+        account_id: i32,
+        gd_username: &str,
+        discord_username: &str,
+    ) -> Result<(), LinkCallbackError> {
+        if !gd_username.is_ascii() || gd_username.is_empty() || gd_username.len() > 16 {
+            return Err(LinkCallbackError::InvalidUsername);
+        }
+
+        if self.get_linked_discord_account(account_id).await?.is_some() {
+            return Err(LinkCallbackError::AccountLinked);
+        }
+
+        // every request searches the guilds and sends a direct message, so both sides are rate limited
+        self.ingame_link_cooldown(AttemptKind::Account, &account_id.to_string())
+            .await?;
+
+        let member = self
+            .find_member_by_username(http, discord_username)
+            .await?
+            .ok_or(LinkCallbackError::UserNotFound)?;
+
+        self.ingame_link_cooldown(AttemptKind::IngameUser, &member.user.id.to_string())
+            .await?;
+
+        if !self
+            .can_link_account(member.user.id, member.guild_id)
+            .await?
+        {
+            return Err(LinkCallbackError::AlreadyLinked);
+        }
+
+        let id: u64 = rand::random();
+        let now = now_timestamp();
+
+        {
+            let mut pending = self.link_confirmations.lock();
+
+            // only the latest request of a user can be confirmed
+            pending.retain(|_, x| x.expires_at > now && x.user_id != member.user.id);
+            pending.insert(
+                id,
+                PendingConfirmation {
+                    user_id: member.user.id,
+                    guild_id: member.guild_id,
+                    account_id,
+                    gd_username: gd_username.to_owned(),
+                    expires_at: now + LINK_CONFIRM_TIMEOUT,
+                },
+            );
+        }
+
+        let message = CreateMessage::new()
+            .content(format!(
+                "Someone wants to link the GD account **{gd_username}** ({account_id}) to your Discord account on Globed. If this was you, confirm it below. The request expires <t:{}:R>.",
+                now + LINK_CONFIRM_TIMEOUT
+            ))
+            .components(vec![CreateActionRow::Buttons(vec![
+                CreateButton::new(format!("{CONFIRM_PREFIX}{id}"))
+                    .label("Confirm")
+                    .style(ButtonStyle::Success),
+                CreateButton::new(format!("{DENY_PREFIX}{id}"))
+                    .label("Deny")
+                    .style(ButtonStyle::Danger),
+            ])]);
+
+        if let Err(err) = member.user.id.direct_message(http, message).await {
+            self.link_confirmations.lock().remove(&id);
+            return Err(LinkCallbackError::DirectMessage(err));
+        }
+
+        Ok(())
+    }
+
+    async fn ingame_link_cooldown(
+        &self,
+        kind: AttemptKind,
+        key: &str,
+    ) -> Result<(), LinkCallbackError> {
+        match self.start_ingame_link_cooldown(kind, key).await? {
+            Some(until) => Err(LinkCallbackError::RateLimited(
+                (until - now_timestamp()).max(1),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // first member of any registered guild with exactly this username
+    async fn find_member_by_username(
+        &self,
+        http: &serenity::Http,
+        username: &str,
+    ) -> Result<Option<Member>, LinkCallbackError> {
+        let username = username.trim().trim_start_matches('@');
+
+        for guild_id in self.allowed_guilds() {
+            let found = http
+                .search_guild_members(guild_id, username, Some(MEMBER_SEARCH_LIMIT))
+                .await
+                .map_err(LinkCallbackError::MemberSearch)?;
+
+            if let Some(member) = found
+                .into_iter()
+                .find(|x| x.user.name.eq_ignore_ascii_case(username))
+            {
+                return Ok(Some(member));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Handles the confirm and deny buttons of in-game links. Returns `false` if the interaction is not for a link.
+    pub async fn handle_link_interaction(
+        &self,
+        ctx: &serenity::Context,
+        interaction: &ComponentInteraction,
+    ) -> Result<bool, serenity::Error> {
+        let custom_id = &interaction.data.custom_id;
+
+        let (confirmed, id) = if let Some(id) = custom_id.strip_prefix(CONFIRM_PREFIX) {
+            (true, id)
+        } else if let Some(id) = custom_id.strip_prefix(DENY_PREFIX) {
+            (false, id)
+        } else {
+            return Ok(false);
+        };
+
+        let pending = id.parse::<u64>().ok().and_then(|id| {
+            let mut pending = self.link_confirmations.lock();
+
+            match pending.get(&id) {
+                Some(x) if x.user_id == interaction.user.id => pending.remove(&id),
+                _ => None,
+            }
+        });
+
+        let pending = match pending {
+            Some(x) if x.expires_at > now_timestamp() => x,
+            _ => {
+                respond(
+                    ctx,
+                    interaction,
+                    ":x: This link request has expired. Start linking again in-game.",
+                )
+                .await?;
+                return Ok(true);
+            }
+        };
+
+        if !confirmed {
+            respond(
+                ctx,
+                interaction,
+                "Link request denied, your account was not linked.",
+            )
+            .await?;
+            return Ok(true);
+        }
+
+        // linking and syncing can take longer than discord waits for a response
+        interaction.defer(&ctx.http).await?;
+
+        let message = self.confirm_ingame_link(ctx, &pending).await;
+
+        interaction
+            .edit_response(
+                &ctx.http,
+                EditInteractionResponse::new()
+                    .content(message)
+                    .components(Vec::new()),
+            )
+            .await?;
+
+        Ok(true)
+    }
+
+    // links the account and syncs roles, returns the message shown to the user
+    async fn confirm_ingame_link(
+        &self,
+        ctx: &serenity::Context,
+        pending: &PendingConfirmation,
+    ) -> String {
+        let member = match ctx.http.get_member(pending.guild_id, pending.user_id).await {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Failed to fetch a member confirming an in-game link: {err}");
+                return ":x: Failed to find you on the server. Make sure you are still a member and try again.".to_owned();
+            }
+        };
+
+        match self
+            .add_linked_user(
+                ctx,
+                pending.user_id,
+                pending.guild_id,
+                pending.account_id,
+                Some(&pending.gd_username),
+                LinkActor::User,
+            )
+            .await
+        {
+            Ok(()) => {}
+            Err(LinkError::AlreadyLinked) => {
+                return ":x: Already linked. Use the `/unlink` command to unlink your account."
+                    .to_owned();
+            }
+            Err(LinkError::LinkedToOther(ident)) => {
+                return format!(":x: This Geometry Dash account is already linked to another Discord account ({ident}). If this is not you, please contact the moderator team.");
+            }
+            Err(err) => {
+                if let LinkError::Database(err) = err {
+                    warn!("Failed to link an account confirmed in-game: {err}");
+                }
+
+                return ":x: Unknown database error has occurred.".to_owned();
+            }
+        }
+
+        info!(
+            "Linked {} to GD account {} ({}) from an in-game request",
+            member.user.name, pending.gd_username, pending.account_id
+        );
+
+        match self
+            .sync_roles(&ctx.http, &member, SyncOptions::default())
+            .await
+        {
+            Ok(roles) if roles.is_empty() => format!(
+                "✅ Linked to GD account {} ({})!",
+                pending.gd_username, pending.account_id
+            ),
+            Ok(roles) => format!(
+                "✅ Linked to GD account {} ({})!\n\n* Synced roles: {}\n* Reconnect to the server to see your new roles",
+                pending.gd_username,
+                pending.account_id,
+                roles.join(", ")
+            ),
+            Err(err) => {
+                warn!("Failed to sync roles: {err}");
+
+                format!(
+                    "Linked to GD account {} ({}) successfully, but role syncing failed. Try to execute the `/sync` command manually, or contact staff for assistance.",
+                    pending.gd_username, pending.account_id
+                )
+            }
+        }
+    }
+}
+
+async fn respond(
+    ctx: &serenity::Context,
+    interaction: &ComponentInteraction,
+    message: &str,
+) -> Result<(), serenity::Error> {
+    interaction
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content(message)
+                    .components(Vec::new()),
+            ),
+        )
+        .await
+}
@@ -0,0 +1,196 @@
+use std::fmt::Display;
+
+use log::{info, warn};
+use rand::Rng;
+use serenity::all::{CreateMessage, GuildId, UserId};
+
+use super::{now_timestamp, BotState, LinkActor, LinkError, SyncOptions};
+use crate::serenity;
+
+// how long a code from `/linkstart` can be used, in seconds
+const LINK_CODE_TIMEOUT: i64 = 10 * 60;
+const LINK_CODE_LENGTH: usize = 8;
+// no characters that are easy to mix up, like 0 and O
+const LINK_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+pub enum LinkCodeError {
+    Database(sqlx::Error),
+    Disabled,
+    InvalidCode,
+    AlreadyLinked,
+    AccountLinked,
+    Internal(&'static str),
+}
+
+impl From<sqlx::Error> for LinkCodeError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Database(value)
+    }
+}
+
+impl Display for LinkCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "Database error: {e}"),
+            Self::Disabled => f.write_str("Linking with codes from the bot is not enabled"),
+            Self::InvalidCode => f.write_str("The link code is invalid or has expired"),
+            Self::AlreadyLinked => {
+                f.write_str("This Discord account can't link any more GD accounts")
+            }
+            Self::AccountLinked => f.write_str("This GD account is already linked"),
+            Self::Internal(e) => write!(f, "Internal error: {e}"),
+        }
+    }
+}
+
+fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..LINK_CODE_LENGTH)
+        .map(|_| LINK_CODE_ALPHABET[rng.gen_range(0..LINK_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+// codes are shown as `ABCD-EFGH`, but the dash and case don't matter when entering them
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+pub fn format_code(code: &str) -> String {
+    let (a, b) = code.split_at(code.len() / 2);
+    format!("{a}-{b}")
+}
+
+impl BotState {
+    /// Whether `/linkstart` can be used, enabled with `BOT_LINK_CODES`. Codes made up by the game could then
+    /// be guessed through `/link`, so that command is disabled while this is enabled.
+    pub fn bot_link_codes_enabled(&self) -> bool {
+        self.bot_link_codes
+    }
+
+    /// Issues a new link code for the user, replacing their previous one. Returns the code and when it expires.
+    pub async fn create_link_code(
+        &self,
+        user_id: UserId,
+        guild_id: GuildId,
+    ) -> Result<(String, i64), LinkCodeError> {
+        if !self.bot_link_codes_enabled() {
+            return Err(LinkCodeError::Disabled);
+        }
+
+        if !self.can_link_account(user_id, guild_id).await? {
+            return Err(LinkCodeError::AlreadyLinked);
+        }
+
+        let user_id = user_id.get() as i64;
+        let guild_id = guild_id.get() as i64;
+        let now = now_timestamp();
+        let expires_at = now + LINK_CODE_TIMEOUT;
+        let code = generate_code();
+
+        sqlx::query!("DELETE FROM pending_links WHERE expires_at <= ?", now)
+            .execute(&self.database)
+            .await?;
+
+        sqlx::query!(
+            "INSERT INTO pending_links (code, user_id, guild_id, created_at, expires_at) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(user_id) DO UPDATE SET code = excluded.code, guild_id = excluded.guild_id,
+            created_at = excluded.created_at, expires_at = excluded.expires_at",
+            code,
+            user_id,
+            guild_id,
+            now,
+            expires_at
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok((code, expires_at))
+    }
+
+    /// Called by the Globed server once a player entered a link code in-game. Links the account of the user
+    /// the code was issued to and syncs their roles. Returns the discord username of the linked user.
+    pub async fn redeem_link_code(
+        &self,
+        http: &serenity::Http,
+        code: &str,
+        account_id: i32,
+        gd_username: &str,
+    ) -> Result<String, LinkCodeError> {
+        let code = normalize_code(code);
+        let now = now_timestamp();
+
+        // codes can only be used once, even if linking fails below
+        let Some(pending) = sqlx::query!(
+            "DELETE FROM pending_links WHERE code = ? AND expires_at > ? RETURNING user_id, guild_id",
+            code,
+            now
+        )
+        .fetch_optional(&self.database)
+        .await?
+        else {
+            return Err(LinkCodeError::InvalidCode);
+        };
+
+        let user_id = UserId::new(pending.user_id as u64);
+        let guild_id = GuildId::new(pending.guild_id as u64);
+
+        match self
+            .add_linked_user(
+                http,
+                user_id,
+                guild_id,
+                account_id,
+                Some(gd_username),
+                LinkActor::User,
+            )
+            .await
+        {
+            Ok(()) => {}
+            Err(LinkError::AlreadyLinked) => return Err(LinkCodeError::AlreadyLinked),
+            Err(LinkError::LinkedToOther(_)) => return Err(LinkCodeError::AccountLinked),
+            Err(LinkError::Database(e)) => return Err(LinkCodeError::Database(e)),
+            // only returned when looking up accounts on the server or syncing, neither happens here
+            Err(_) => return Err(LinkCodeError::Internal("unexpected error while linking")),
+        }
+
+        info!("Linked {user_id} to GD account {gd_username} ({account_id}) with a link code");
+
+        let mut message = format!("✅ Linked to GD account {gd_username} ({account_id})!");
+        let mut username = user_id.to_string();
+
+        // the link is done at this point, syncing can be retried with `/sync`
+        match http.get_member(guild_id, user_id).await {
+            Ok(member) => {
+                username = member.user.name.clone();
+
+                match self.sync_roles(http, &member, SyncOptions::default()).await {
+                    Ok(roles) if !roles.is_empty() => {
+                        message += &format!(
+                            "\n\n* Synced roles: {}\n* Reconnect to the server to see your new roles",
+                            roles.join(", ")
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("Failed to sync roles: {err}");
+                        message += "\n\nRole syncing failed. Try to execute the `/sync` command manually, or contact staff for assistance.";
+                    }
+                }
+            }
+            Err(err) => warn!("Failed to fetch {user_id} after linking them with a code: {err}"),
+        }
+
+        if let Err(err) = user_id
+            .direct_message(http, CreateMessage::new().content(message))
+            .await
+        {
+            warn!("Failed to tell {user_id} that their account was linked: {err}");
+        }
+
+        Ok(username)
+    }
+}
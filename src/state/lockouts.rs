@@ -0,0 +1,342 @@
+use std::fmt::Display;
+
+use log::warn;
+use serenity::all::UserId;
+use sqlx::SqliteConnection;
+
+use super::{history::LinkEventKind, now_timestamp, BotState, LinkActor};
+use crate::{db::LinkAttempts, serenity};
+
+// failed attempts before linking gets locked
+const MAX_FAILURES: i64 = 5;
+// failed attempts older than this are forgotten, in seconds
+const FAILURE_WINDOW: i64 = 60 * 60;
+// the first lockout lasts this long, every next one twice as long
+const BASE_LOCKOUT: i64 = 15 * 60;
+const MAX_LOCKOUT: i64 = 24 * 60 * 60;
+// lockouts are forgotten after this long without attempts or lockouts, so the next one starts short again
+const LOCKOUT_RESET_WINDOW: i64 = 7 * 24 * 60 * 60;
+// how long a discord user or a GD account has to wait between links started in-game, in seconds
+const INGAME_LINK_COOLDOWN: i64 = 2 * 60;
+
+/// What failed link attempts are counted for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AttemptKind {
+    /// The discord user running `/link`
+    User,
+    /// The GD username they tried to link
+    Username,
+    /// The discord user a link started in-game was sent to
+    IngameUser,
+    /// The GD account that started a link in-game
+    Account,
+}
+
+impl AttemptKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Username => "username",
+            Self::IngameUser => "ingame_user",
+            Self::Account => "account",
+        }
+    }
+}
+
+impl Display for LinkAttempts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind.as_str() {
+            "user" => write!(f, "<@{}>", self.key),
+            "username" => write!(f, "GD username `{}`", self.key),
+            "ingame_user" => write!(f, "<@{}> (in-game links)", self.key),
+            "account" => write!(f, "GD account `{}`", self.key),
+            _ => write!(f, "{} `{}`", self.kind, self.key),
+        }
+    }
+}
+
+fn lockout_duration(lockouts: i64) -> i64 {
+    let exp = (lockouts - 1).clamp(0, 16) as u32;
+    BASE_LOCKOUT.saturating_mul(2i64.pow(exp)).min(MAX_LOCKOUT)
+}
+
+// counts an attempt, returns the updated attempts if a lockout started
+async fn add_link_attempt(
+    conn: &mut SqliteConnection,
+    kind: AttemptKind,
+    key: &str,
+) -> Result<Option<LinkAttempts>, sqlx::Error> {
+    let kind_str = kind.as_str();
+    let now = now_timestamp();
+
+    let existing = sqlx::query_as!(
+        LinkAttempts,
+        "SELECT * FROM link_attempts WHERE kind = ? AND key = ?",
+        kind_str,
+        key
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let mut attempts = existing.unwrap_or_else(|| LinkAttempts {
+        kind: kind_str.to_owned(),
+        key: key.to_owned(),
+        failures: 0,
+        lockouts: 0,
+        locked_until: None,
+        last_attempt: now,
+    });
+
+    if now - attempts.last_attempt > FAILURE_WINDOW {
+        attempts.failures = 0;
+    }
+
+    let quiet_since = attempts
+        .locked_until
+        .unwrap_or_default()
+        .max(attempts.last_attempt);
+
+    if now - quiet_since > LOCKOUT_RESET_WINDOW {
+        attempts.lockouts = 0;
+    }
+
+    attempts.failures += 1;
+    attempts.last_attempt = now;
+
+    let locked = attempts.failures >= MAX_FAILURES;
+    if locked {
+        attempts.failures = 0;
+        attempts.lockouts += 1;
+        attempts.locked_until = Some(now + lockout_duration(attempts.lockouts));
+    }
+
+    sqlx::query!(
+        "INSERT INTO link_attempts (kind, key, failures, lockouts, locked_until, last_attempt) VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT(kind, key) DO UPDATE SET failures = excluded.failures, lockouts = excluded.lockouts,
+        locked_until = excluded.locked_until, last_attempt = excluded.last_attempt",
+        attempts.kind,
+        attempts.key,
+        attempts.failures,
+        attempts.lockouts,
+        attempts.locked_until,
+        attempts.last_attempt
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(locked.then_some(attempts))
+}
+
+impl BotState {
+    /// Counts a link attempt for both the user and the GD username, before the account is looked up on the server,
+    /// so parallel attempts can't get past the limit. Returns when linking gets unlocked instead, if the user or
+    /// the GD username are locked out. Attempts are forgotten once linking succeeds and given back if the lookup
+    /// failed for another reason than a wrong username or code, so only those add up.
+    pub(super) async fn begin_link_attempt(
+        &self,
+        http: &serenity::Http,
+        user_id: UserId,
+        gd_username: &str,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let user_key = user_id.to_string();
+        let username_key = gd_username.to_ascii_lowercase();
+        let now = now_timestamp();
+
+        // takes the write lock right away, so the check and the count can't interleave with another attempt
+        let mut tx = self.database.begin_with("BEGIN IMMEDIATE").await?;
+
+        let locked_until = sqlx::query_scalar!(
+            r#"SELECT MAX(locked_until) AS "locked_until: i64" FROM link_attempts
+            WHERE ((kind = 'user' AND key = ?) OR (kind = 'username' AND key = ?)) AND locked_until > ?"#,
+            user_key,
+            username_key,
+            now
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if locked_until.is_some() {
+            return Ok(locked_until);
+        }
+
+        let mut started = Vec::new();
+
+        for (kind, key) in [
+            (AttemptKind::User, &user_key),
+            (AttemptKind::Username, &username_key),
+        ] {
+            let Some(attempts) = add_link_attempt(&mut tx, kind, key).await? else {
+                continue;
+            };
+
+            let gd_username = (kind == AttemptKind::Username).then_some(gd_username);
+
+            self.record_lockout_event(
+                &mut *tx,
+                LinkEventKind::Lockout,
+                LinkActor::System,
+                Some(user_id.get() as i64),
+                gd_username,
+            )
+            .await?;
+
+            started.push(attempts);
+        }
+
+        tx.commit().await?;
+
+        // the attempt that started the lockout still goes through, only the next ones are refused
+        for attempts in started {
+            let message = format!(
+                ":warning: Linking is locked for {attempts} until <t:{}:f> after {MAX_FAILURES} failed attempts (lockout #{}), triggered by <@{user_id}> trying GD username `{gd_username}`.",
+                attempts.locked_until.unwrap_or_default(),
+                attempts.lockouts
+            );
+
+            warn!("{message}");
+            self.notify_admins(http, message).await;
+        }
+
+        Ok(None)
+    }
+
+    /// Starts the cooldown of links started in-game for the user or account. If it's still cooling down from
+    /// a previous link, returns when that cooldown ends instead.
+    pub(super) async fn start_ingame_link_cooldown(
+        &self,
+        kind: AttemptKind,
+        key: &str,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let kind = kind.as_str();
+        let now = now_timestamp();
+        let until = now + INGAME_LINK_COOLDOWN;
+
+        // a single statement, so parallel requests can't both pass the check
+        let started = sqlx::query!(
+            "INSERT INTO link_attempts (kind, key, failures, lockouts, locked_until, last_attempt) VALUES (?, ?, 1, 0, ?, ?)
+            ON CONFLICT(kind, key) DO UPDATE SET failures = failures + 1, locked_until = excluded.locked_until, last_attempt = excluded.last_attempt
+            WHERE locked_until IS NULL OR locked_until <= ?",
+            kind,
+            key,
+            until,
+            now,
+            now
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected()
+            > 0;
+
+        if started {
+            return Ok(None);
+        }
+
+        sqlx::query_scalar!(
+            "SELECT locked_until FROM link_attempts WHERE kind = ? AND key = ?",
+            kind,
+            key
+        )
+        .fetch_one(&self.database)
+        .await
+    }
+
+    // takes back an attempt counted by `begin_link_attempt`, when the link failed without the username or code being wrong
+    pub(super) async fn refund_link_attempt(
+        &self,
+        user_id: UserId,
+        gd_username: &str,
+    ) -> Result<(), sqlx::Error> {
+        let user_key = user_id.to_string();
+        let username_key = gd_username.to_ascii_lowercase();
+
+        sqlx::query!(
+            "UPDATE link_attempts SET failures = MAX(failures - 1, 0) WHERE (kind = 'user' AND key = ?) OR (kind = 'username' AND key = ?)",
+            user_key,
+            username_key
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok(())
+    }
+
+    // forgets the failed attempts once the user linked the account successfully
+    pub(super) async fn clear_link_failures(
+        &self,
+        user_id: UserId,
+        gd_username: &str,
+    ) -> Result<(), sqlx::Error> {
+        let user_key = user_id.to_string();
+        let username_key = gd_username.to_ascii_lowercase();
+
+        sqlx::query!(
+            "DELETE FROM link_attempts WHERE (kind = 'user' AND key = ?) OR (kind = 'username' AND key = ?)",
+            user_key,
+            username_key
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Every user and GD username that is currently locked out
+    pub async fn get_lockouts(&self) -> Result<Vec<LinkAttempts>, sqlx::Error> {
+        let now = now_timestamp();
+
+        sqlx::query_as!(
+            LinkAttempts,
+            "SELECT * FROM link_attempts WHERE kind IN ('user', 'username') AND locked_until > ? ORDER BY locked_until",
+            now
+        )
+        .fetch_all(&self.database)
+        .await
+    }
+
+    /// Lifts the lockout and forgets all failed attempts, returns `false` if there were none
+    pub async fn clear_lockout(
+        &self,
+        kind: AttemptKind,
+        key: &str,
+        actor: LinkActor,
+    ) -> Result<bool, sqlx::Error> {
+        let key = match kind {
+            AttemptKind::Username => key.to_ascii_lowercase(),
+            _ => key.to_owned(),
+        };
+        let kind_str = kind.as_str();
+
+        let mut tx = self.database.begin().await?;
+
+        let affected = sqlx::query!(
+            "DELETE FROM link_attempts WHERE kind = ? AND key = ?",
+            kind_str,
+            key
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Ok(false);
+        }
+
+        let (user_id, gd_username) = match kind {
+            AttemptKind::Username => (None, Some(key.as_str())),
+            _ => (key.parse::<i64>().ok(), None),
+        };
+
+        self.record_lockout_event(
+            &mut *tx,
+            LinkEventKind::LockoutClear,
+            actor,
+            user_id,
+            gd_username,
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+}
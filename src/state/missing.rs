@@ -0,0 +1,134 @@
+use std::env;
+
+use log::{info, warn};
+use serenity::all::UserId;
+
+use super::{now_timestamp, BotState, LinkActor, RoleSyncError};
+use crate::{db::MissingMember, serenity};
+
+/// What happens to linked users that aren't in any guild anymore, usually because they left while the bot was offline
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MissingMemberPolicy {
+    /// Unlink them and remove their roles, same as when they leave while the bot is running
+    Unlink,
+    /// Keep them linked, but list them in `/admin missing` so an admin can review them
+    Flag,
+}
+
+impl MissingMemberPolicy {
+    pub fn from_env() -> Self {
+        match env::var("BOT_MISSING_MEMBER_POLICY").ok().as_deref() {
+            None | Some("flag") => Self::Flag,
+            Some("unlink") => Self::Unlink,
+            Some(other) => {
+                panic!("BOT_MISSING_MEMBER_POLICY must be either 'flag' or 'unlink', got '{other}'")
+            }
+        }
+    }
+}
+
+impl BotState {
+    /// Applies the missing member policy to linked users that weren't found during a full scan of every guild
+    pub(super) async fn handle_missing_members(
+        &self,
+        missing: &[UserId],
+    ) -> Result<(), RoleSyncError> {
+        // members that showed up again lose their flag
+        self.flag_missing_members(missing).await?;
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        match self.missing_member_policy {
+            MissingMemberPolicy::Flag => {
+                info!(
+                    "{} linked users are no longer in any guild, flagged them for review",
+                    missing.len()
+                );
+            }
+
+            MissingMemberPolicy::Unlink => {
+                info!(
+                    "{} linked users are no longer in any guild, unlinking them",
+                    missing.len()
+                );
+
+                self.unlink_members(missing, LinkActor::System).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    // replaces the flagged members with the given ones, keeping the detection time of ones that were already flagged
+    async fn flag_missing_members(&self, missing: &[UserId]) -> Result<(), sqlx::Error> {
+        let now = now_timestamp();
+        let ids: Vec<u64> = missing.iter().map(|x| x.get()).collect();
+        let ids = serde_json::to_string(&ids).unwrap_or_default();
+
+        let mut tx = self.database.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM missing_members WHERE id NOT IN (SELECT value FROM json_each(?))",
+            ids
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO missing_members (id, detected_at)
+            SELECT id, ? FROM linked_users WHERE id IN (SELECT value FROM json_each(?))
+            ON CONFLICT(id) DO NOTHING",
+            now,
+            ids
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_missing_members(&self) -> Result<Vec<MissingMember>, sqlx::Error> {
+        sqlx::query_as!(
+            MissingMember,
+            "SELECT * FROM missing_members ORDER BY detected_at"
+        )
+        .fetch_all(&self.database)
+        .await
+    }
+
+    /// Unlinks every flagged member, returns the amount of users that were unlinked
+    pub async fn unlink_missing_members(&self, actor: LinkActor) -> Result<usize, sqlx::Error> {
+        let missing: Vec<UserId> = self
+            .get_missing_members()
+            .await?
+            .into_iter()
+            .map(|x| UserId::new(x.id as u64))
+            .collect();
+
+        Ok(self.unlink_members(&missing, actor).await)
+    }
+
+    // unlinks the users one by one, returns the amount of users that were unlinked
+    async fn unlink_members(&self, users: &[UserId], actor: LinkActor) -> usize {
+        let mut unlinked = 0;
+
+        for user_id in users {
+            match self.unlink_user(*user_id, actor).await {
+                Ok(()) => unlinked += 1,
+                Err(RoleSyncError::NotLinked) => {}
+                // the link is already gone at this point, only the role removal is waiting for a retry
+                Err(err) if err.is_retryable() => {
+                    unlinked += 1;
+                    warn!("Failed to remove roles of missing member {user_id}, queued for a retry: {err}");
+                }
+                Err(err) => warn!("Failed to unlink missing member {user_id}: {err}"),
+            }
+        }
+
+        unlinked
+    }
+}
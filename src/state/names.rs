@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::{BotState, RoleSyncError};
+use crate::db::LinkedUser;
+
+// max amount of account ids sent in a single name lookup
+const NAME_LOOKUP_BATCH_SIZE: usize = 200;
+
+#[derive(Deserialize)]
+struct ServerName {
+    account_id: i32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ServerNamesResponse {
+    users: Vec<ServerName>,
+}
+
+impl LinkedUser {
+    /// The GD username with the account ID, or only the account ID if the name isn't known
+    pub fn account_display(&self) -> String {
+        match &self.gd_username {
+            Some(name) => format!("{name} ({})", self.gd_account_id),
+            None => self.gd_account_id.to_string(),
+        }
+    }
+}
+
+impl BotState {
+    /// Fetches the current GD usernames of the given accounts from the server. Unknown accounts are left out.
+    pub async fn fetch_gd_usernames(
+        &self,
+        account_ids: &[i32],
+    ) -> Result<HashMap<i32, String>, RoleSyncError> {
+        let mut names = HashMap::with_capacity(account_ids.len());
+
+        for batch in account_ids.chunks(NAME_LOOKUP_BATCH_SIZE) {
+            let ids = batch
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+
+            let response = match self
+                .http_client
+                .get(format!(
+                    "{}/gsp/names?account_ids={ids}",
+                    self.main_target().base_url
+                ))
+                .header("Authorization", &self.main_target().password)
+                .send()
+                .await
+            {
+                Ok(resp) => resp,
+                Err(e) => {
+                    return Err(RoleSyncError::ServerRequest(e));
+                }
+            };
+
+            let status = response.status();
+            if !status.is_success() {
+                let message = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "<no message>".to_owned());
+
+                return Err(RoleSyncError::ServerUpdate((status, message)));
+            }
+
+            let json = response.text().await.unwrap_or_default();
+            let response: ServerNamesResponse = match serde_json::from_str(&json) {
+                Ok(x) => x,
+                Err(err) => {
+                    return Err(RoleSyncError::ServerMalformedResponse(err, json));
+                }
+            };
+
+            for user in response.users {
+                names.insert(user.account_id, user.name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Asks the server for the current names of every linked account, so renames are picked up.
+    /// Returns the amount of names that changed.
+    pub async fn refresh_gd_usernames(&self) -> Result<usize, RoleSyncError> {
+        // main accounts and alts, with their stored names
+        let accounts: Vec<(i64, Option<String>)> = sqlx::query!(
+            "SELECT gd_account_id, gd_username FROM linked_users
+            UNION ALL SELECT gd_account_id, gd_username FROM linked_alts"
+        )
+        .fetch_all(&self.database)
+        .await?
+        .into_iter()
+        .map(|x| (x.gd_account_id, x.gd_username))
+        .collect();
+
+        let account_ids: Vec<i32> = accounts.iter().map(|(id, _)| *id as i32).collect();
+
+        let names = self.fetch_gd_usernames(&account_ids).await?;
+
+        let mut changed = 0;
+
+        for (account_id, stored) in &accounts {
+            let Some(name) = names.get(&(*account_id as i32)) else {
+                continue;
+            };
+
+            if stored.as_ref() == Some(name) {
+                continue;
+            }
+
+            // the account is in only one of the tables
+            sqlx::query!(
+                "UPDATE linked_users SET gd_username = ? WHERE gd_account_id = ?",
+                name,
+                account_id
+            )
+            .execute(&self.database)
+            .await?;
+
+            sqlx::query!(
+                "UPDATE linked_alts SET gd_username = ? WHERE gd_account_id = ?",
+                name,
+                account_id
+            )
+            .execute(&self.database)
+            .await?;
+
+            changed += 1;
+        }
+
+        Ok(changed)
+    }
+}
@@ -0,0 +1,381 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{info, warn};
+use rand::Rng;
+
+use super::{
+    now_timestamp, BotState, RoleSyncError, RoleSyncRequest, RoleSyncRequestData, ServerTarget,
+    MAIN_TARGET,
+};
+use crate::db::OutboxEntry;
+
+// how often the worker checks for entries that are due for a retry
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// max amount of users sent in a single retry request
+const OUTBOX_BATCH_SIZE: i64 = 500;
+// backoff bounds, in seconds
+const OUTBOX_BASE_DELAY: i64 = 5;
+const OUTBOX_MAX_DELAY: i64 = 15 * 60;
+
+impl OutboxEntry {
+    fn to_request(&self) -> RoleSyncRequest {
+        let parse = |list: &str| {
+            serde_json::from_str::<Vec<String>>(list).unwrap_or_else(|err| {
+                warn!(
+                    "Malformed role list in outbox entry for account {} on server '{}': {err}",
+                    self.account_id, self.target
+                );
+                Vec::new()
+            })
+        };
+
+        RoleSyncRequest {
+            account_id: self.account_id as i32,
+            keep: parse(&self.keep),
+            remove: parse(&self.remove),
+            targets: Vec::new(),
+        }
+    }
+}
+
+// exponential backoff with jitter, returns the delay in seconds
+fn retry_delay(attempts: i64) -> i64 {
+    let exp = attempts.clamp(0, 16) as u32;
+    let backoff = OUTBOX_BASE_DELAY
+        .saturating_mul(1 << exp)
+        .min(OUTBOX_MAX_DELAY);
+
+    backoff / 2 + rand::thread_rng().gen_range(0..=backoff / 2)
+}
+
+impl BotState {
+    /// Stores failed sync requests to the given server in the outbox, so they can be retried later.
+    /// If there already is a pending entry for an account, it gets replaced with the newer request.
+    pub async fn enqueue_failed_sync(
+        &self,
+        target: &str,
+        data: &RoleSyncRequestData,
+        error: &RoleSyncError,
+    ) -> Result<(), sqlx::Error> {
+        let next_attempt = now_timestamp() + retry_delay(0);
+        let error = error.to_string();
+
+        let mut tx = self.database.begin().await?;
+
+        for req in &data.users {
+            // serializing a vec of strings can't fail
+            let keep = serde_json::to_string(&req.keep).unwrap_or_default();
+            let remove = serde_json::to_string(&req.remove).unwrap_or_default();
+
+            sqlx::query!(
+                "INSERT INTO sync_outbox (account_id, target, keep, remove, next_attempt, last_error) VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT(account_id, target) DO UPDATE SET keep = excluded.keep, remove = excluded.remove, version = version + 1, last_error = excluded.last_error",
+                req.account_id,
+                target,
+                keep,
+                remove,
+                next_attempt,
+                error
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            // the server may have any roles until the entry is sent, so the next sync can't be skipped
+            sqlx::query!(
+                "DELETE FROM synced_roles WHERE gd_account_id = ?",
+                req.account_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Queues requests that only take roles away. A pending entry for the same account is kept,
+    /// the roles are moved from its kept roles to the removed ones instead of replacing it.
+    pub(super) async fn enqueue_failed_strip(
+        &self,
+        target: &str,
+        data: &RoleSyncRequestData,
+        error: &RoleSyncError,
+    ) -> Result<(), sqlx::Error> {
+        let next_attempt = now_timestamp() + retry_delay(0);
+        let error = error.to_string();
+
+        let mut tx = self.database.begin().await?;
+
+        for req in &data.users {
+            let pending = sqlx::query_as!(
+                OutboxEntry,
+                "SELECT * FROM sync_outbox WHERE account_id = ? AND target = ?",
+                req.account_id,
+                target
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .map(|x| x.to_request());
+
+            let (mut keep, mut remove) = pending.map(|x| (x.keep, x.remove)).unwrap_or_default();
+
+            keep.retain(|x| !req.remove.contains(x));
+            for role in &req.remove {
+                if !remove.contains(role) {
+                    remove.push(role.clone());
+                }
+            }
+
+            let keep = serde_json::to_string(&keep).unwrap_or_default();
+            let remove = serde_json::to_string(&remove).unwrap_or_default();
+
+            sqlx::query!(
+                "INSERT INTO sync_outbox (account_id, target, keep, remove, next_attempt, last_error) VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT(account_id, target) DO UPDATE SET keep = excluded.keep, remove = excluded.remove, version = version + 1, last_error = excluded.last_error",
+                req.account_id,
+                target,
+                keep,
+                remove,
+                next_attempt,
+                error
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Removes outbox entries of the given users for the server, called after a newer state was successfully synced
+    pub(super) async fn clear_outbox_for(
+        &self,
+        target: &str,
+        data: &RoleSyncRequestData,
+    ) -> Result<(), sqlx::Error> {
+        let ids: Vec<i32> = data.users.iter().map(|x| x.account_id).collect();
+        let ids = serde_json::to_string(&ids).unwrap_or_default();
+
+        sqlx::query!(
+            "DELETE FROM sync_outbox WHERE target = ? AND account_id IN (SELECT value FROM json_each(?))",
+            target,
+            ids
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_outbox_size(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!("SELECT COUNT(*) FROM sync_outbox")
+            .fetch_one(&self.database)
+            .await
+    }
+
+    /// Background task that retries failed sync requests
+    pub async fn run_outbox_worker(self: Arc<Self>) {
+        loop {
+            // a successful sync elsewhere means the server is reachable again, so don't wait for backoff
+            let server_back = tokio::select! {
+                () = tokio::time::sleep(OUTBOX_POLL_INTERVAL) => false,
+                () = self.outbox_wakeup.notified() => true,
+            };
+
+            if let Err(err) = self.drop_unknown_target_entries().await {
+                warn!("Failed to remove queued role syncs of removed servers: {err}");
+            }
+
+            let mut drained = 0;
+
+            for target in &self.targets {
+                // the wakeup only means the main server is reachable again
+                let server_back = server_back && target.name == MAIN_TARGET;
+
+                match self.drain_outbox(target, server_back).await {
+                    Ok(count) => drained += count,
+                    Err(err) => warn!(
+                        "Failed to retry queued role syncs to server '{}': {err}",
+                        target.name
+                    ),
+                }
+            }
+
+            if drained > 0 {
+                info!("Retried {drained} queued role syncs successfully");
+            }
+        }
+    }
+
+    // servers can be removed from the config while syncs to them are still queued
+    async fn drop_unknown_target_entries(&self) -> Result<(), sqlx::Error> {
+        let names: Vec<&str> = self.targets.iter().map(|x| x.name.as_str()).collect();
+        let names = serde_json::to_string(&names).unwrap_or_default();
+
+        let dropped = sqlx::query!(
+            "DELETE FROM sync_outbox WHERE target NOT IN (SELECT value FROM json_each(?))",
+            names
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if dropped > 0 {
+            warn!("Dropped {dropped} queued role syncs to servers that aren't configured anymore");
+        }
+
+        Ok(())
+    }
+
+    // sends due entries of the server in the order they were queued, until none are left or the server can't be reached.
+    // entries the server rejects are dropped, retrying them would block the queue forever
+    async fn drain_outbox(
+        &self,
+        target: &ServerTarget,
+        server_back: bool,
+    ) -> Result<usize, RoleSyncError> {
+        let mut drained = 0;
+
+        loop {
+            // once a request went through, the server is back, so send everything left without waiting
+            let due_before = if server_back || drained > 0 {
+                i64::MAX
+            } else {
+                now_timestamp()
+            };
+
+            let entries = sqlx::query_as!(
+                OutboxEntry,
+                "SELECT * FROM sync_outbox WHERE target = ? AND next_attempt <= ? ORDER BY seq LIMIT ?",
+                target.name,
+                due_before,
+                OUTBOX_BATCH_SIZE
+            )
+            .fetch_all(&self.database)
+            .await?;
+
+            if entries.is_empty() {
+                return Ok(drained);
+            }
+
+            let data = RoleSyncRequestData {
+                users: entries.iter().map(OutboxEntry::to_request).collect(),
+            };
+
+            match self.post_sync_roles_req(target, &data).await {
+                Ok(()) => {
+                    self.finish_outbox_entries(&entries).await?;
+                    drained += entries.len();
+                }
+
+                Err(err) if err.is_retryable() => {
+                    self.reschedule_outbox_entries(&entries, &err).await?;
+                    return Err(err);
+                }
+
+                // one rejected entry fails the whole request, so they are sent one by one to only drop the bad ones
+                Err(_) => {
+                    drained += self
+                        .send_outbox_entries_separately(target, &entries)
+                        .await?
+                }
+            }
+        }
+    }
+
+    // returns the amount of entries that were sent successfully
+    async fn send_outbox_entries_separately(
+        &self,
+        target: &ServerTarget,
+        entries: &[OutboxEntry],
+    ) -> Result<usize, RoleSyncError> {
+        let mut sent = 0;
+
+        for (i, entry) in entries.iter().enumerate() {
+            let data = RoleSyncRequestData {
+                users: vec![entry.to_request()],
+            };
+
+            match self.post_sync_roles_req(target, &data).await {
+                Ok(()) => {
+                    self.finish_outbox_entries(std::slice::from_ref(entry))
+                        .await?;
+                    sent += 1;
+                }
+
+                Err(err) if err.is_retryable() => {
+                    self.reschedule_outbox_entries(&entries[i..], &err).await?;
+                    return Err(err);
+                }
+
+                Err(err) => {
+                    warn!(
+                        "Dropping queued role sync for account {} to server '{}' after {} attempts, the server rejected it: {err}",
+                        entry.account_id,
+                        target.name,
+                        entry.attempts + 1
+                    );
+
+                    self.remove_outbox_entry(entry).await?;
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+
+    // entries don't store the roles for extra servers, so they aren't marked as synced. the next sync of
+    // these users is sent in full and records the complete role set
+    async fn finish_outbox_entries(&self, entries: &[OutboxEntry]) -> Result<(), sqlx::Error> {
+        for entry in entries {
+            self.remove_outbox_entry(entry).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn remove_outbox_entry(&self, entry: &OutboxEntry) -> Result<(), sqlx::Error> {
+        // if a newer request got merged in while sending, keep it around
+        sqlx::query!(
+            "DELETE FROM sync_outbox WHERE seq = ? AND version = ?",
+            entry.seq,
+            entry.version
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn reschedule_outbox_entries(
+        &self,
+        entries: &[OutboxEntry],
+        error: &RoleSyncError,
+    ) -> Result<(), sqlx::Error> {
+        let now = now_timestamp();
+        let error = error.to_string();
+
+        let mut tx = self.database.begin().await?;
+
+        for entry in entries {
+            let attempts = entry.attempts + 1;
+            let next_attempt = now + retry_delay(attempts);
+
+            sqlx::query!(
+                "UPDATE sync_outbox SET attempts = ?, next_attempt = ?, last_error = ? WHERE seq = ?",
+                attempts,
+                next_attempt,
+                error,
+                entry.seq
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
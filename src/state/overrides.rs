@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use log::{info, warn};
+use serenity::all::UserId;
+
+use super::{now_timestamp, BotState, RoleSyncError};
+use crate::{db::RoleOverride, serenity};
+
+// cached override of a user, as (globed role, keep, expiry timestamp)
+pub(super) type CachedOverride = (String, bool, Option<i64>);
+
+/// What an override does with a Globed role, regardless of the user's discord roles
+#[derive(Clone, Copy, PartialEq, Eq, Debug, poise::ChoiceParameter)]
+pub enum OverrideMode {
+    #[name = "Always keep"]
+    Keep,
+    #[name = "Always remove"]
+    Remove,
+}
+
+impl OverrideMode {
+    pub fn from_keep(keep: bool) -> Self {
+        if keep {
+            Self::Keep
+        } else {
+            Self::Remove
+        }
+    }
+}
+
+impl BotState {
+    // overrides of the user that haven't expired yet, as (globed role, keep)
+    pub(super) fn active_overrides(&self, user_id: i64) -> Vec<(String, bool)> {
+        let now = now_timestamp();
+
+        self.role_overrides
+            .read()
+            .get(&user_id)
+            .map(|overrides| {
+                overrides
+                    .iter()
+                    .filter(|(_, _, expires_at)| expires_at.is_none_or(|x| x > now))
+                    .map(|(id, keep, _)| (id.clone(), *keep))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Forces roles of the user to be kept or removed, applied after everything else
+    pub(super) fn apply_overrides(&self, user_id: i64, granted: &mut Vec<(String, bool)>) {
+        for (id, keep) in self.active_overrides(user_id) {
+            match granted.iter_mut().find(|(x, _)| *x == id) {
+                Some((_, has)) => *has = keep,
+                None => granted.push((id, keep)),
+            }
+        }
+    }
+
+    /// Forces a Globed role to be kept or removed for a linked user, until `expires_at` if given.
+    /// Replaces any existing override of the same role.
+    pub async fn set_override(
+        &self,
+        user_id: UserId,
+        globed_role_id: &str,
+        keep: bool,
+        reason: &str,
+        created_by: UserId,
+        expires_at: Option<i64>,
+    ) -> Result<(), RoleSyncError> {
+        let user_id = user_id.get() as i64;
+        let created_by = created_by.get() as i64;
+        let now = now_timestamp();
+
+        let affected = sqlx::query!(
+            "INSERT INTO role_overrides (user_id, globed_role_id, keep, reason, created_by, created_at, expires_at)
+            SELECT id, ?, ?, ?, ?, ?, ? FROM linked_users WHERE id = ?
+            ON CONFLICT(user_id, globed_role_id) DO UPDATE SET keep = excluded.keep, reason = excluded.reason,
+                created_by = excluded.created_by, created_at = excluded.created_at, expires_at = excluded.expires_at",
+            globed_role_id,
+            keep,
+            reason,
+            created_by,
+            now,
+            expires_at,
+            user_id
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Err(RoleSyncError::NotLinked);
+        }
+
+        self.reload_overrides().await?;
+
+        Ok(())
+    }
+
+    /// Removes an override and syncs the user again, returns whether the override existed
+    pub async fn clear_override(
+        &self,
+        http: &serenity::Http,
+        user_id: UserId,
+        globed_role_id: &str,
+    ) -> Result<bool, RoleSyncError> {
+        let user_id = user_id.get() as i64;
+
+        let affected = sqlx::query!(
+            "DELETE FROM role_overrides WHERE user_id = ? AND globed_role_id = ?",
+            user_id,
+            globed_role_id
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Ok(false);
+        }
+
+        self.reload_overrides().await?;
+        self.resync_user_role(http, user_id, globed_role_id).await?;
+
+        Ok(true)
+    }
+
+    /// Returns overrides of the given user, or of every user if `None`
+    pub async fn get_overrides(
+        &self,
+        user_id: Option<UserId>,
+    ) -> Result<Vec<RoleOverride>, sqlx::Error> {
+        let user_id = user_id.map(|x| x.get() as i64);
+
+        sqlx::query_as!(
+            RoleOverride,
+            "SELECT * FROM role_overrides WHERE ? IS NULL OR user_id = ? ORDER BY user_id, globed_role_id",
+            user_id,
+            user_id
+        )
+        .fetch_all(&self.database)
+        .await
+    }
+
+    // caches all overrides, so syncing doesn't have to query them
+    pub(super) async fn reload_overrides(&self) -> Result<(), sqlx::Error> {
+        let mut overrides: HashMap<i64, Vec<CachedOverride>> = HashMap::new();
+
+        for x in self.get_overrides(None).await? {
+            overrides
+                .entry(x.user_id)
+                .or_default()
+                .push((x.globed_role_id, x.keep, x.expires_at));
+        }
+
+        *self.role_overrides.write() = overrides;
+
+        Ok(())
+    }
+
+    pub(super) async fn expire_overrides(
+        &self,
+        http: &serenity::Http,
+    ) -> Result<(), RoleSyncError> {
+        let now = now_timestamp();
+
+        let expired = sqlx::query_as!(
+            RoleOverride,
+            "DELETE FROM role_overrides WHERE expires_at <= ? RETURNING *",
+            now
+        )
+        .fetch_all(&self.database)
+        .await?;
+
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        self.reload_overrides().await?;
+
+        for x in expired {
+            info!(
+                "Override of `{}` for {} expired",
+                x.globed_role_id, x.user_id
+            );
+
+            if let Err(err) = self
+                .resync_user_role(http, x.user_id, &x.globed_role_id)
+                .await
+            {
+                warn!(
+                    "Failed to sync {} after their override of `{}` expired: {err}",
+                    x.user_id, x.globed_role_id
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use log::{debug, info, warn};
+
+use super::{now_timestamp, BotState, RoleSyncError, SyncOptions, SyncReport};
+use crate::serenity;
+
+// key in the `bot_state` table, set while the bot is paused for maintenance
+const MAINTENANCE_KEY: &str = "maintenance_paused";
+
+pub struct FullSyncStatus {
+    pub finished_at: i64,
+    /// Whether the sync was started by the periodic reconciliation task
+    pub periodic: bool,
+    pub outcome: Result<SyncReport, String>,
+}
+
+// resets the running flag once the sync finishes, even if it errors out
+pub(super) struct FullSyncGuard<'a>(&'a BotState);
+
+impl Drop for FullSyncGuard<'_> {
+    fn drop(&mut self) {
+        self.0.full_sync_running.store(false, Ordering::Release);
+    }
+}
+
+impl BotState {
+    pub(super) async fn load_maintenance_flag(&self) -> Result<(), sqlx::Error> {
+        let paused = self.get_persistent_value(MAINTENANCE_KEY).await?.is_some();
+        self.paused.store(paused, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Pauses or resumes periodic reconciliation, persisted across restarts
+    pub async fn set_paused(&self, paused: bool) -> Result<(), sqlx::Error> {
+        if paused {
+            self.set_persistent_value(MAINTENANCE_KEY, "1").await?;
+        } else {
+            self.remove_persistent_value(MAINTENANCE_KEY).await?;
+        }
+
+        self.paused.store(paused, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn is_full_sync_running(&self) -> bool {
+        self.full_sync_running.load(Ordering::Acquire)
+    }
+
+    // marks a full sync as running, returns `None` if one is already in progress
+    pub(super) fn begin_full_sync(&self) -> Option<FullSyncGuard<'_>> {
+        self.full_sync_running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| FullSyncGuard(self))
+    }
+
+    pub(super) fn record_full_sync(
+        &self,
+        periodic: bool,
+        outcome: &Result<SyncReport, RoleSyncError>,
+    ) {
+        *self.last_full_sync.lock() = Some(FullSyncStatus {
+            finished_at: now_timestamp(),
+            periodic,
+            outcome: match outcome {
+                Ok(report) => Ok(report.clone()),
+                Err(err) => Err(err.to_string()),
+            },
+        });
+    }
+
+    /// Returns the unix timestamp of the next periodic sync, or `None` if periodic syncing is disabled
+    pub fn next_reconciliation(&self) -> Option<i64> {
+        match self.next_reconcile_at.load(Ordering::Relaxed) {
+            0 => None,
+            x => Some(x),
+        }
+    }
+
+    /// Background task that periodically runs a full sync, to fix any roles that drifted since the last one
+    pub async fn run_reconciliation(self: Arc<Self>, http: Arc<serenity::Http>) {
+        let period = self.reconcile_interval;
+        if period.is_zero() {
+            info!("Periodic role reconciliation is disabled");
+            return;
+        }
+
+        loop {
+            // the interval is counted from the end of the previous run, so runs can never pile up
+            self.next_reconcile_at
+                .store(now_timestamp() + period.as_secs() as i64, Ordering::Relaxed);
+
+            tokio::time::sleep(period).await;
+
+            if self.is_paused() {
+                info!("Skipping periodic role reconciliation, the bot is paused for maintenance");
+                continue;
+            }
+
+            info!("Running periodic role reconciliation..");
+
+            // roles changed on the server don't show up in the synced role sets, so nobody can be skipped
+            match self
+                .run_full_sync(&http, SyncOptions { force: true }, true)
+                .await
+            {
+                Ok(report) => info!("Periodic reconciliation finished! {report}"),
+                Err(RoleSyncError::SyncInProgress) => {
+                    debug!("Skipping periodic reconciliation, another full sync is still running");
+                }
+                Err(err) => warn!("Periodic reconciliation failed: {err}"),
+            }
+
+            // picks up players that changed their name since the last run
+            match self.refresh_gd_usernames().await {
+                Ok(0) => {}
+                Ok(count) => info!("Updated {count} changed GD usernames"),
+                Err(err) => warn!("Failed to refresh GD usernames: {err}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use serenity::all::{GuildId, Member, RoleId, UserId};
+
+use super::{BotState, RoleSyncError, MAIN_TARGET};
+use crate::{
+    db::{LinkedUser, Role},
+    serenity,
+};
+
+// how long a role change made by the bot is remembered, in case the gateway event for it never arrives
+const OWN_CHANGE_TTL: Duration = Duration::from_secs(60);
+
+const REVERSE_SYNC_REASON: &str = "Role synced from Globed";
+
+#[derive(Clone, Default, Debug)]
+pub struct ReverseSyncReport {
+    pub roles_added: usize,
+    pub roles_removed: usize,
+    pub roles_failed: usize,
+}
+
+impl ReverseSyncReport {
+    pub fn is_empty(&self) -> bool {
+        self.roles_added == 0 && self.roles_removed == 0 && self.roles_failed == 0
+    }
+}
+
+impl BotState {
+    /// Adds or removes Discord roles of the given members, based on the roles they have on the Globed server.
+    /// Only mappings going from Globed to Discord are affected.
+    pub(super) async fn sync_reverse_roles(
+        &self,
+        http: &serenity::Http,
+        users: &[(Vec<Member>, &LinkedUser)],
+        all_roles: &[Role],
+    ) -> Result<ReverseSyncReport, RoleSyncError> {
+        let mut report = ReverseSyncReport::default();
+
+        let reverse_roles: Vec<&Role> = all_roles
+            .iter()
+            .filter(|role| role.is_reverse() && role.target == MAIN_TARGET)
+            .collect();
+
+        if reverse_roles.is_empty() || users.is_empty() {
+            return Ok(report);
+        }
+
+        let alts = self.get_all_alt_ids().await?;
+
+        // a role granted on Globed to any account of the user counts, alts included
+        let accounts_of = |user: &LinkedUser| -> Vec<i32> {
+            std::iter::once(user.gd_account_id)
+                .chain(alts.get(&user.id).into_iter().flatten().copied())
+                .map(|x| x as i32)
+                .collect()
+        };
+
+        let account_ids: Vec<i32> = users
+            .iter()
+            .flat_map(|(_, user)| accounts_of(user))
+            .collect();
+
+        let server_roles = self.fetch_server_roles(&account_ids).await?;
+
+        for (members, linked_user) in users {
+            let globed_roles: Vec<&String> = accounts_of(linked_user)
+                .iter()
+                .filter_map(|id| server_roles.get(id))
+                .flatten()
+                .collect();
+
+            // every guild only gets the roles mapped in it
+            for member in members {
+                // multiple globed roles can grant the same discord role, it's kept if the user has any of them
+                let mut wanted: HashMap<RoleId, bool> = HashMap::new();
+                for role in reverse_roles
+                    .iter()
+                    .filter(|x| x.guild_id == member.guild_id.get() as i64)
+                {
+                    let has_role = globed_roles.contains(&&role.id);
+                    *wanted
+                        .entry(RoleId::new(role.discord_id as u64))
+                        .or_default() |= has_role;
+                }
+
+                for (role_id, should_have) in wanted {
+                    if member.roles.contains(&role_id) == should_have {
+                        continue;
+                    }
+
+                    match self
+                        .change_member_role(
+                            http,
+                            member.guild_id,
+                            member.user.id,
+                            role_id,
+                            should_have,
+                        )
+                        .await
+                    {
+                        Ok(()) if should_have => report.roles_added += 1,
+                        Ok(()) => report.roles_removed += 1,
+                        Err(err) => {
+                            report.roles_failed += 1;
+                            warn!(
+                                "Failed to update role {role_id} of {} from Globed: {err}",
+                                member.user.id
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn change_member_role(
+        &self,
+        http: &serenity::Http,
+        guild_id: GuildId,
+        user_id: UserId,
+        role_id: RoleId,
+        add: bool,
+    ) -> Result<(), serenity::Error> {
+        self.own_role_changes
+            .lock()
+            .insert((user_id, role_id), Instant::now());
+
+        let result = if add {
+            http.add_member_role(guild_id, user_id, role_id, Some(REVERSE_SYNC_REASON))
+                .await
+        } else {
+            http.remove_member_role(guild_id, user_id, role_id, Some(REVERSE_SYNC_REASON))
+                .await
+        };
+
+        if result.is_err() {
+            self.own_role_changes.lock().remove(&(user_id, role_id));
+        }
+
+        result
+    }
+
+    /// Drops role changes that the bot made itself during a reverse sync, so they don't get synced back to the server
+    pub fn filter_own_role_changes(&self, user_id: UserId, changed: Vec<RoleId>) -> Vec<RoleId> {
+        let mut own = self.own_role_changes.lock();
+        own.retain(|_, at| at.elapsed() < OWN_CHANGE_TTL);
+
+        changed
+            .into_iter()
+            .filter(|role| own.remove(&(user_id, *role)).is_none())
+            .collect()
+    }
+}
@@ -0,0 +1,182 @@
+use log::warn;
+use serenity::all::{GuildId, Role as DiscordRole, RoleId};
+
+use super::{
+    target_role_ids, BotState, RoleRemoveError, RoleSyncError, RoleSyncRequest,
+    RoleSyncRequestData, TargetRequestKind, TargetRoles, MAIN_TARGET,
+};
+use crate::{db::Role, serenity};
+
+pub struct DeletedRoleReport {
+    /// Last known name of the deleted discord role
+    pub name: Option<String>,
+    /// Globed roles that were mapped to the deleted role
+    pub globed_roles: Vec<String>,
+    pub stripped: StripReport,
+}
+
+#[derive(Default)]
+pub struct StripReport {
+    /// Linked accounts the Globed roles were removed from
+    pub users_stripped: usize,
+    /// Chunks that failed, but were queued for a retry
+    pub chunks_queued: usize,
+    /// Chunks the server rejected, their accounts keep the roles
+    pub chunks_failed: usize,
+}
+
+impl From<RoleRemoveError> for RoleSyncError {
+    fn from(value: RoleRemoveError) -> Self {
+        match value {
+            RoleRemoveError::Database(e) => Self::Database(e),
+            RoleRemoveError::NotFound => Self::InternalError("role mapping disappeared"),
+        }
+    }
+}
+
+impl BotState {
+    /// Called when a discord role gets deleted. Removes its mappings, and takes the mapped Globed roles away from linked members
+    /// of the guild. Returns `None` if the role wasn't mapped to anything.
+    pub async fn handle_role_deleted(
+        &self,
+        guild_id: GuildId,
+        role_id: RoleId,
+    ) -> Result<Option<DeletedRoleReport>, RoleSyncError> {
+        let (deleted, remaining): (Vec<Role>, Vec<Role>) = self
+            .get_all_roles()
+            .await?
+            .into_iter()
+            .partition(|x| x.discord_id == role_id.get() as i64);
+
+        if deleted.is_empty() {
+            return Ok(None);
+        }
+
+        // only members of the guild the role was in could have had it
+        let accounts = self.get_guild_accounts(guild_id).await?;
+
+        self.remove_role(role_id.get() as i64).await?;
+
+        let stripped = self
+            .strip_unmapped_roles(&deleted, &remaining, &accounts)
+            .await?;
+
+        Ok(Some(DeletedRoleReport {
+            name: deleted.iter().find_map(|x| x.discord_name.clone()),
+            globed_roles: deleted.iter().map(|x| x.id.clone()).collect(),
+            stripped,
+        }))
+    }
+
+    /// Takes the Globed roles of the deleted mappings away from the given accounts, unless one of the remaining mappings
+    /// of the same server grants them. Only the removal is sent, the other roles of the accounts are left as they are.
+    pub(super) async fn strip_unmapped_roles(
+        &self,
+        deleted: &[Role],
+        remaining: &[Role],
+        accounts: &[i32],
+    ) -> Result<StripReport, sqlx::Error> {
+        let mut report = StripReport::default();
+
+        // roles granted on Globed stay there, only discord can't show them anymore.
+        // roles still mapped elsewhere are handled like any other role, so they are never in both lists
+        let managed = self.managed_role_ids(remaining, MAIN_TARGET);
+        let stripped: Vec<String> = target_role_ids(deleted, MAIN_TARGET)
+            .into_iter()
+            .filter(|x| !managed.contains(x))
+            .collect();
+
+        let target_stripped: Vec<TargetRoles> = self
+            .extra_targets()
+            .iter()
+            .map(|target| {
+                let managed = self.managed_role_ids(remaining, &target.name);
+
+                TargetRoles {
+                    target: target.name.clone(),
+                    keep: Vec::new(),
+                    remove: target_role_ids(deleted, &target.name)
+                        .into_iter()
+                        .filter(|x| !managed.contains(x))
+                        .collect(),
+                }
+            })
+            .filter(|x| !x.remove.is_empty())
+            .collect();
+
+        if stripped.is_empty() && target_stripped.is_empty() {
+            return Ok(report);
+        }
+
+        let mut users: Vec<RoleSyncRequest> = accounts
+            .iter()
+            .map(|account_id| RoleSyncRequest {
+                account_id: *account_id,
+                keep: Vec::new(),
+                remove: stripped.clone(),
+                targets: target_stripped.clone(),
+            })
+            .collect();
+
+        while !users.is_empty() {
+            let rest = users.split_off(users.len().min(self.sync_chunk_size));
+            let data = RoleSyncRequestData { users };
+
+            self.send_to_extra_targets(&data, TargetRequestKind::Strip)
+                .await;
+
+            // not a full sync, so it can't be recorded as synced or replace queued retries
+            match self.post_sync_roles_req(self.main_target(), &data).await {
+                Ok(()) => report.users_stripped += data.users.len(),
+
+                Err(err) if err.is_retryable() => {
+                    report.chunks_queued += 1;
+                    warn!(
+                        "Failed to remove unmapped roles from a chunk of {} users, queued for a retry: {err}",
+                        data.users.len()
+                    );
+
+                    self.enqueue_failed_strip(MAIN_TARGET, &data, &err).await?;
+                }
+
+                Err(err) => {
+                    report.chunks_failed += 1;
+                    warn!(
+                        "The server rejected removing unmapped roles from a chunk of {} users: {err}",
+                        data.users.len()
+                    );
+                }
+            }
+
+            users = rest;
+        }
+
+        Ok(report)
+    }
+
+    /// Called when a discord role gets updated, keeps the stored name of mapped roles up to date
+    pub async fn handle_role_updated(&self, role: &DiscordRole) -> Result<(), sqlx::Error> {
+        let role_id = role.id.get() as i64;
+
+        sqlx::query!(
+            "UPDATE roles SET discord_name = ? WHERE discord_id = ?",
+            role.name,
+            role_id
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Posts a message in the admin channel, if one is configured
+    pub async fn notify_admins(&self, http: &serenity::Http, message: impl Into<String>) {
+        let Some(channel) = self.admin_channel else {
+            return;
+        };
+
+        if let Err(err) = channel.say(http, message).await {
+            warn!("Failed to post a notice in the admin channel: {err}");
+        }
+    }
+}
@@ -0,0 +1,590 @@
+use std::{collections::HashMap, fmt::Display};
+
+use log::warn;
+use serenity::all::RoleId;
+
+use super::BotState;
+use crate::{db::RoleRule, serenity};
+
+/// Boolean expression over discord roles, for example `<@&1> OR <@&2>` or `<@&3> AND NOT <@&4>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoleExpr {
+    Role(RoleId),
+    Not(Box<RoleExpr>),
+    And(Box<RoleExpr>, Box<RoleExpr>),
+    Or(Box<RoleExpr>, Box<RoleExpr>),
+}
+
+// how deep parentheses and `NOT`s can be nested, the parser and evaluation recurse this deep
+const MAX_RULE_DEPTH: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RuleParseError {
+    Empty,
+    UnexpectedEnd,
+    UnexpectedToken(String),
+    InvalidRole(String),
+    TooDeep,
+}
+
+impl Display for RuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => f.write_str("Rule is empty"),
+            Self::UnexpectedEnd => f.write_str("Rule ends unexpectedly"),
+            Self::UnexpectedToken(t) => write!(f, "Unexpected `{t}` in the rule"),
+            Self::InvalidRole(r) => write!(
+                f,
+                "`{r}` is not a role, mention the role or use its ID instead"
+            ),
+            Self::TooDeep => write!(
+                f,
+                "Rule is nested too deeply, at most {MAX_RULE_DEPTH} levels of parentheses and `NOT` are allowed"
+            ),
+        }
+    }
+}
+
+pub enum RuleError {
+    Parse(RuleParseError),
+    Database(sqlx::Error),
+    AlreadyExists,
+    NotFound,
+}
+
+impl From<sqlx::Error> for RuleError {
+    fn from(value: sqlx::Error) -> Self {
+        match value {
+            sqlx::Error::RowNotFound => Self::NotFound,
+            e => Self::Database(e),
+        }
+    }
+}
+
+impl From<RuleParseError> for RuleError {
+    fn from(value: RuleParseError) -> Self {
+        Self::Parse(value)
+    }
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "Invalid rule: {e}"),
+            Self::Database(e) => write!(f, "Database error: {e}"),
+            Self::AlreadyExists => f.write_str("A rule for this Globed role already exists"),
+            Self::NotFound => f.write_str("No rule exists for this Globed role"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Role(RoleId),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, RuleParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '!' => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            '&' | '|' => {
+                // both `&` and `&&` are accepted
+                chars.next();
+                if chars.peek().is_some_and(|(_, next)| *next == c) {
+                    chars.next();
+                }
+
+                tokens.push(if c == '&' { Token::And } else { Token::Or });
+            }
+            _ => {
+                // a word, either a role or a keyword
+                let mut end = input.len();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '!' | '|') {
+                        end = i;
+                        break;
+                    }
+
+                    // `&` is a part of role mentions, but an operator everywhere else
+                    if c == '&' && !input[start..i].ends_with("<@") {
+                        end = i;
+                        break;
+                    }
+
+                    chars.next();
+                }
+
+                let word = &input[start..end];
+
+                tokens.push(match word.to_ascii_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Role(parse_role(word)?),
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_role(word: &str) -> Result<RoleId, RuleParseError> {
+    let id = word
+        .strip_prefix("<@&")
+        .and_then(|x| x.strip_suffix('>'))
+        .unwrap_or(word);
+
+    match id.parse::<u64>() {
+        Ok(id) if id != 0 => Ok(RoleId::new(id)),
+        _ => Err(RuleParseError::InvalidRole(word.to_owned())),
+    }
+}
+
+// recursive descent parser, `NOT` binds tighter than `AND`, which binds tighter than `OR`
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<RoleExpr, RuleParseError> {
+        let mut expr = self.parse_and()?;
+
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = RoleExpr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<RoleExpr, RuleParseError> {
+        let mut expr = self.parse_not()?;
+
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = RoleExpr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<RoleExpr, RuleParseError> {
+        match self.next() {
+            Some(Token::Not) => Ok(RoleExpr::Not(Box::new(self.nested(Self::parse_not)?))),
+            Some(Token::Role(id)) => Ok(RoleExpr::Role(id)),
+            Some(Token::Open) => {
+                let expr = self.nested(Self::parse_or)?;
+
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    Some(t) => Err(RuleParseError::UnexpectedToken(t.to_string())),
+                    None => Err(RuleParseError::UnexpectedEnd),
+                }
+            }
+            Some(t) => Err(RuleParseError::UnexpectedToken(t.to_string())),
+            None => Err(RuleParseError::UnexpectedEnd),
+        }
+    }
+
+    // parses one level deeper, so nesting can't overflow the stack
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<RoleExpr, RuleParseError>,
+    ) -> Result<RoleExpr, RuleParseError> {
+        if self.depth >= MAX_RULE_DEPTH {
+            return Err(RuleParseError::TooDeep);
+        }
+
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+
+        expr
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Role(id) => write!(f, "<@&{id}>"),
+            Self::And => f.write_str("AND"),
+            Self::Or => f.write_str("OR"),
+            Self::Not => f.write_str("NOT"),
+            Self::Open => f.write_str("("),
+            Self::Close => f.write_str(")"),
+        }
+    }
+}
+
+impl RoleExpr {
+    pub fn parse(input: &str) -> Result<Self, RuleParseError> {
+        let tokens = tokenize(input)?;
+        if tokens.is_empty() {
+            return Err(RuleParseError::Empty);
+        }
+
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.parse_or()?;
+
+        match parser.next() {
+            None => Ok(expr),
+            Some(t) => Err(RuleParseError::UnexpectedToken(t.to_string())),
+        }
+    }
+
+    /// Whether a member with the given roles matches the rule
+    pub fn eval(&self, roles: &[RoleId]) -> bool {
+        match self {
+            Self::Role(id) => roles.contains(id),
+            Self::Not(x) => !x.eval(roles),
+            Self::And(a, b) => a.eval(roles) && b.eval(roles),
+            Self::Or(a, b) => a.eval(roles) || b.eval(roles),
+        }
+    }
+
+    /// All roles the rule depends on
+    pub fn roles(&self) -> Vec<RoleId> {
+        let mut out = Vec::new();
+        self.collect_roles(&mut out);
+        out
+    }
+
+    fn collect_roles(&self, out: &mut Vec<RoleId>) {
+        match self {
+            Self::Role(id) => out.push(*id),
+            Self::Not(x) => x.collect_roles(out),
+            Self::And(a, b) | Self::Or(a, b) => {
+                a.collect_roles(out);
+                b.collect_roles(out);
+            }
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Or(..) => 0,
+            Self::And(..) => 1,
+            Self::Not(_) | Self::Role(_) => 2,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut std::fmt::Formatter<'_>, min: u8) -> std::fmt::Result {
+        if self.precedence() < min {
+            write!(f, "({self})")
+        } else {
+            write!(f, "{self}")
+        }
+    }
+}
+
+// canonical form of the rule, this is what gets stored in the database
+impl Display for RoleExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Role(id) => write!(f, "<@&{id}>"),
+            Self::Not(x) => {
+                f.write_str("NOT ")?;
+                x.fmt_operand(f, 2)
+            }
+            Self::And(a, b) => {
+                a.fmt_operand(f, 1)?;
+                f.write_str(" AND ")?;
+                b.fmt_operand(f, 2)
+            }
+            Self::Or(a, b) => {
+                a.fmt_operand(f, 0)?;
+                f.write_str(" OR ")?;
+                b.fmt_operand(f, 1)
+            }
+        }
+    }
+}
+
+impl BotState {
+    pub async fn get_all_rules(&self) -> Result<Vec<RoleRule>, sqlx::Error> {
+        sqlx::query_as!(RoleRule, "SELECT * FROM role_rules ORDER BY target, id")
+            .fetch_all(&self.database)
+            .await
+    }
+
+    /// Adds a rule that grants the Globed role on the server to members matching the expression, returns the parsed rule
+    pub async fn add_rule(
+        &self,
+        target: &str,
+        globed_role_id: &str,
+        expression: &str,
+    ) -> Result<RoleExpr, RuleError> {
+        let expr = RoleExpr::parse(expression)?;
+        let canonical = expr.to_string();
+
+        match sqlx::query!(
+            "INSERT INTO role_rules (id, target, expression) VALUES (?, ?, ?)",
+            globed_role_id,
+            target,
+            canonical
+        )
+        .execute(&self.database)
+        .await
+        {
+            Ok(_) => {}
+            Err(sqlx::Error::Database(err))
+                if err.message().contains("UNIQUE constraint failed") =>
+            {
+                return Err(RuleError::AlreadyExists);
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        self.rules_changed().await?;
+
+        Ok(expr)
+    }
+
+    /// Replaces the expression of an existing rule, returns the parsed rule
+    pub async fn edit_rule(
+        &self,
+        target: &str,
+        globed_role_id: &str,
+        expression: &str,
+    ) -> Result<RoleExpr, RuleError> {
+        let expr = RoleExpr::parse(expression)?;
+        let canonical = expr.to_string();
+
+        let affected = sqlx::query!(
+            "UPDATE role_rules SET expression = ? WHERE target = ? AND id = ?",
+            canonical,
+            target,
+            globed_role_id
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Err(RuleError::NotFound);
+        }
+
+        self.rules_changed().await?;
+
+        Ok(expr)
+    }
+
+    pub async fn remove_rule(&self, target: &str, globed_role_id: &str) -> Result<(), RuleError> {
+        let affected = sqlx::query!(
+            "DELETE FROM role_rules WHERE target = ? AND id = ?",
+            target,
+            globed_role_id
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Err(RuleError::NotFound);
+        }
+
+        self.rules_changed().await?;
+
+        Ok(())
+    }
+
+    async fn rules_changed(&self) -> Result<(), sqlx::Error> {
+        self.invalidate_synced_roles().await?;
+        self.reload_rules().await?;
+        self.reload_watched_roles().await
+    }
+
+    // parses all rules from the database and caches them, so syncing doesn't have to
+    pub(super) async fn reload_rules(&self) -> Result<(), sqlx::Error> {
+        let mut rules: HashMap<String, Vec<(String, RoleExpr)>> = HashMap::new();
+
+        for rule in self.get_all_rules().await? {
+            match RoleExpr::parse(&rule.expression) {
+                Ok(expr) => rules.entry(rule.target).or_default().push((rule.id, expr)),
+                Err(err) => {
+                    warn!("Ignoring malformed rule for Globed role {}: {err}", rule.id);
+                }
+            }
+        }
+
+        *self.role_rules.write() = rules;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn role(id: u64) -> RoleExpr {
+        RoleExpr::Role(RoleId::new(id))
+    }
+
+    fn not(x: RoleExpr) -> RoleExpr {
+        RoleExpr::Not(Box::new(x))
+    }
+
+    fn and(a: RoleExpr, b: RoleExpr) -> RoleExpr {
+        RoleExpr::And(Box::new(a), Box::new(b))
+    }
+
+    fn or(a: RoleExpr, b: RoleExpr) -> RoleExpr {
+        RoleExpr::Or(Box::new(a), Box::new(b))
+    }
+
+    fn parse(input: &str) -> RoleExpr {
+        RoleExpr::parse(input).unwrap_or_else(|e| panic!("failed to parse `{input}`: {e}"))
+    }
+
+    #[test]
+    fn tokenizes_mentions_ids_and_operators() {
+        let tokens = tokenize("<@&1> && 2|<@&3>||!(4 and not 5) Or 6 & 7").unwrap();
+
+        let r = |id| Token::Role(RoleId::new(id));
+        assert_eq!(
+            tokens,
+            vec![
+                r(1),
+                Token::And,
+                r(2),
+                Token::Or,
+                r(3),
+                Token::Or,
+                Token::Not,
+                Token::Open,
+                r(4),
+                Token::And,
+                Token::Not,
+                r(5),
+                Token::Close,
+                Token::Or,
+                r(6),
+                Token::And,
+                r(7),
+            ]
+        );
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_and_and_tighter_than_or() {
+        assert_eq!(parse("1 OR 2 AND 3"), or(role(1), and(role(2), role(3))));
+        assert_eq!(parse("1 AND 2 OR 3"), or(and(role(1), role(2)), role(3)));
+        assert_eq!(parse("NOT 1 AND 2"), and(not(role(1)), role(2)));
+        assert_eq!(parse("NOT (1 AND 2)"), not(and(role(1), role(2))));
+        assert_eq!(parse("(1 OR 2) AND 3"), and(or(role(1), role(2)), role(3)));
+        assert_eq!(parse("1 AND 2 AND 3"), and(and(role(1), role(2)), role(3)));
+    }
+
+    #[test]
+    fn evaluates_against_member_roles() {
+        let expr = parse("<@&1> AND NOT <@&2> OR <@&3>");
+        let roles = |ids: &[u64]| ids.iter().map(|x| RoleId::new(*x)).collect::<Vec<_>>();
+
+        assert!(expr.eval(&roles(&[1])));
+        assert!(!expr.eval(&roles(&[1, 2])));
+        assert!(expr.eval(&roles(&[2, 3])));
+        assert!(!expr.eval(&roles(&[])));
+        assert_eq!(expr.roles(), roles(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn canonical_form_round_trips() {
+        let cases = [
+            ("1 || 2 && 3", "<@&1> OR <@&2> AND <@&3>"),
+            ("(1 | 2) & 3", "(<@&1> OR <@&2>) AND <@&3>"),
+            ("!(1 and 2)", "NOT (<@&1> AND <@&2>)"),
+            ("not not 1", "NOT NOT <@&1>"),
+            ("1 and (2 and 3)", "<@&1> AND (<@&2> AND <@&3>)"),
+            ("((1)) or (2 or 3)", "<@&1> OR (<@&2> OR <@&3>)"),
+        ];
+
+        for (input, canonical) in cases {
+            let expr = parse(input);
+            assert_eq!(expr.to_string(), canonical, "canonical form of `{input}`");
+            assert_eq!(parse(canonical), expr, "round trip of `{input}`");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        let err = |input: &str| RoleExpr::parse(input).unwrap_err();
+
+        assert_eq!(err(""), RuleParseError::Empty);
+        assert_eq!(err("   "), RuleParseError::Empty);
+        assert_eq!(err("1 AND"), RuleParseError::UnexpectedEnd);
+        assert_eq!(err("(1 OR 2"), RuleParseError::UnexpectedEnd);
+        assert_eq!(err("1 2"), RuleParseError::UnexpectedToken("<@&2>".into()));
+        assert_eq!(err("1 OR )"), RuleParseError::UnexpectedToken(")".into()));
+        assert_eq!(err("AND 1"), RuleParseError::UnexpectedToken("AND".into()));
+        assert_eq!(
+            err("@moderators"),
+            RuleParseError::InvalidRole("@moderators".into())
+        );
+        assert_eq!(err("<@&0>"), RuleParseError::InvalidRole("<@&0>".into()));
+        assert_eq!(err("<@1>"), RuleParseError::InvalidRole("<@1>".into()));
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        let negated = |depth: usize| format!("{}1", "NOT ".repeat(depth));
+
+        assert!(RoleExpr::parse(&nested(MAX_RULE_DEPTH)).is_ok());
+        assert!(RoleExpr::parse(&negated(MAX_RULE_DEPTH)).is_ok());
+
+        assert_eq!(
+            RoleExpr::parse(&nested(MAX_RULE_DEPTH + 1)).unwrap_err(),
+            RuleParseError::TooDeep
+        );
+        assert_eq!(
+            RoleExpr::parse(&negated(MAX_RULE_DEPTH + 1)).unwrap_err(),
+            RuleParseError::TooDeep
+        );
+
+        // deep enough to overflow the stack without the limit
+        assert_eq!(
+            RoleExpr::parse(&nested(100_000)).unwrap_err(),
+            RuleParseError::TooDeep
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::{collections::HashMap, mem, sync::Arc};
+
+use log::{debug, warn};
+
+use super::{BotState, RoleSyncError, RoleSyncRequestData};
+use crate::{
+    db::LinkedUser,
+    serenity::{self, all::Member},
+};
+
+impl BotState {
+    /// Marks the member as needing a role sync. Syncs are batched and sent once the debounce window passes,
+    /// always using the most recent state of the member.
+    pub fn schedule_sync(&self, member: Member) {
+        self.pending_syncs.lock().insert(member.user.id, member);
+        self.sync_wakeup.notify_one();
+    }
+
+    /// Background task that flushes scheduled syncs
+    pub async fn run_sync_scheduler(self: Arc<Self>, http: Arc<serenity::Http>) {
+        loop {
+            self.sync_wakeup.notified().await;
+
+            // give other changes a chance to pile up
+            tokio::time::sleep(self.sync_debounce).await;
+
+            let pending = mem::take(&mut *self.pending_syncs.lock());
+            if pending.is_empty() {
+                continue;
+            }
+
+            let members: Vec<Member> = pending.into_values().collect();
+
+            match self.sync_members(&http, &members).await {
+                Ok(count) => debug!(
+                    "Flushed scheduled syncs: {} members changed, {count} linked users synced",
+                    members.len()
+                ),
+                Err(err) if err.is_retryable() => {
+                    warn!("Failed to sync scheduled members, queued for a retry: {err}");
+                }
+                Err(err) => warn!("Failed to sync scheduled members: {err}"),
+            }
+        }
+    }
+
+    /// Syncs roles of multiple members in a single request, returns the amount of linked accounts that were synced
+    pub async fn sync_members(
+        &self,
+        http: &serenity::Http,
+        members: &[Member],
+    ) -> Result<usize, RoleSyncError> {
+        let ids: Vec<u64> = members.iter().map(|m| m.user.id.get()).collect();
+        let ids = serde_json::to_string(&ids).unwrap_or_default();
+
+        let linked_users: HashMap<i64, LinkedUser> = sqlx::query_as!(
+            LinkedUser,
+            "SELECT * FROM linked_users WHERE id IN (SELECT value FROM json_each(?))",
+            ids
+        )
+        .fetch_all(&self.database)
+        .await?
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect();
+
+        if linked_users.is_empty() {
+            return Ok(0);
+        }
+
+        let linked_roles = self.get_all_roles().await?;
+
+        let mut users = Vec::new();
+
+        for member in members {
+            let Some(linked) = linked_users.get(&(member.user.id.get() as i64)) else {
+                continue;
+            };
+
+            // memberships in the other guilds count too
+            let memberships = self.fetch_memberships(http, linked, Some(member)).await?;
+            let req = self.make_role_sync_request_with(&memberships, linked, &linked_roles);
+
+            for req in self.with_alts(req, linked.id).await? {
+                // nothing changed for roles we care about
+                if self.is_up_to_date(&req).await? {
+                    continue;
+                }
+
+                users.push(req);
+            }
+        }
+
+        let count = users.len();
+        if count == 0 {
+            return Ok(0);
+        }
+
+        self.send_sync_roles_req(&RoleSyncRequestData { users })
+            .await
+            .map(|()| count)
+    }
+}
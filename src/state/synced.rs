@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use super::{now_timestamp, BotState, RoleSyncRequest, RoleSyncRequestData};
+use crate::db::SyncedRoles;
+
+// canonical form of a role set, so it can be compared against the stored one
+pub(super) fn role_set_key(roles: &[String]) -> String {
+    let mut roles = roles.to_vec();
+    roles.sort();
+    roles.dedup();
+
+    serde_json::to_string(&roles).unwrap_or_default()
+}
+
+// roles a request keeps on every server, roles on extra servers are prefixed with the server name
+pub(super) fn synced_role_list(req: &RoleSyncRequest) -> Vec<String> {
+    let mut roles = req.keep.clone();
+
+    for target in &req.targets {
+        roles.extend(target.keep.iter().map(|x| format!("{}/{x}", target.target)));
+    }
+
+    roles
+}
+
+pub(super) fn synced_key(req: &RoleSyncRequest) -> String {
+    role_set_key(&synced_role_list(req))
+}
+
+impl SyncedRoles {
+    pub fn role_list(&self) -> Vec<String> {
+        serde_json::from_str(&self.roles).unwrap_or_default()
+    }
+}
+
+impl BotState {
+    /// Records the roles in the request as confirmed by the server
+    pub(super) async fn mark_synced(&self, data: &RoleSyncRequestData) -> Result<(), sqlx::Error> {
+        let now = now_timestamp();
+
+        let mut tx = self.database.begin().await?;
+
+        for req in &data.users {
+            let roles = synced_key(req);
+
+            // accounts that were just unlinked are skipped, their roles don't matter anymore
+            sqlx::query!(
+                "INSERT INTO synced_roles (gd_account_id, roles, synced_at)
+                SELECT gd_account_id, ?, ? FROM linked_users WHERE gd_account_id = ?
+                UNION SELECT gd_account_id, ?, ? FROM linked_alts WHERE gd_account_id = ?
+                ON CONFLICT(gd_account_id) DO UPDATE SET roles = excluded.roles, synced_at = excluded.synced_at",
+                roles,
+                now,
+                req.account_id,
+                roles,
+                now,
+                req.account_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_synced_roles(
+        &self,
+        account_id: i32,
+    ) -> Result<Option<SyncedRoles>, sqlx::Error> {
+        sqlx::query_as!(
+            SyncedRoles,
+            "SELECT * FROM synced_roles WHERE gd_account_id = ?",
+            account_id
+        )
+        .fetch_optional(&self.database)
+        .await
+    }
+
+    // returns the stored role set of every synced account
+    pub(super) async fn get_all_synced_roles(&self) -> Result<HashMap<i64, String>, sqlx::Error> {
+        Ok(sqlx::query_as!(SyncedRoles, "SELECT * FROM synced_roles")
+            .fetch_all(&self.database)
+            .await?
+            .into_iter()
+            .map(|x| (x.gd_account_id, x.roles))
+            .collect())
+    }
+
+    /// Whether the server already has exactly the roles this request would keep
+    pub(super) async fn is_up_to_date(&self, req: &RoleSyncRequest) -> Result<bool, sqlx::Error> {
+        Ok(self
+            .get_synced_roles(req.account_id)
+            .await?
+            .is_some_and(|synced| synced.roles == synced_key(req)))
+    }
+
+    /// Forgets all confirmed role sets, so the next sync sends every user again.
+    /// Called whenever the role mappings change, as the list of roles to remove changes too.
+    pub(super) async fn invalidate_synced_roles(&self) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM synced_roles")
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::HashSet, env};
+
+use log::warn;
+
+use super::{now_timestamp, BotState, RoleSyncRequest, RoleSyncRequestData};
+
+/// How a request to an extra server is queued for a retry when it fails
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum TargetRequestKind {
+    /// The complete roles of the users, replaces anything queued for them
+    Full,
+    /// Only takes roles away, merged into anything queued for them
+    Strip,
+}
+
+/// Name of the server from `BOT_BASE_URL`, mappings without an explicit target belong to it
+pub const MAIN_TARGET: &str = "main";
+
+/// A Globed central server that roles get synced to
+pub struct ServerTarget {
+    pub name: String,
+    pub base_url: String,
+    pub password: String,
+}
+
+impl ServerTarget {
+    fn new(name: &str, mut base_url: String, password: String) -> Self {
+        if base_url.ends_with('/') {
+            base_url.pop();
+        }
+
+        Self {
+            name: name.to_owned(),
+            base_url,
+            password,
+        }
+    }
+
+    /// The main server from `BOT_BASE_URL`, followed by every server listed in `BOT_EXTRA_SERVERS`.
+    /// Extra servers are configured with `BOT_SERVER_<NAME>_URL` and `BOT_SERVER_<NAME>_PASSWORD`.
+    pub fn all_from_env() -> Vec<Self> {
+        let mut targets = vec![Self::new(
+            MAIN_TARGET,
+            env::var("BOT_BASE_URL").expect("'BOT_BASE_URL' env variable not passed"),
+            env::var("BOT_SERVER_PASSWORD").expect("'BOT_SERVER_PASSWORD' env variable not passed"),
+        )];
+
+        let extra = env::var("BOT_EXTRA_SERVERS").unwrap_or_default();
+
+        for name in extra.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let name = name.to_ascii_lowercase();
+
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                panic!("Server name '{name}' in BOT_EXTRA_SERVERS can only contain letters, digits and underscores");
+            }
+
+            if targets.iter().any(|x| x.name == name) {
+                panic!("Server '{name}' is listed more than once in BOT_EXTRA_SERVERS");
+            }
+
+            let prefix = format!("BOT_SERVER_{}", name.to_ascii_uppercase());
+
+            targets.push(Self::new(
+                &name,
+                env::var(format!("{prefix}_URL"))
+                    .unwrap_or_else(|_| panic!("'{prefix}_URL' env variable not passed")),
+                env::var(format!("{prefix}_PASSWORD"))
+                    .unwrap_or_else(|_| panic!("'{prefix}_PASSWORD' env variable not passed")),
+            ));
+        }
+
+        targets
+    }
+}
+
+/// Roles of a user on a server other than the main one
+#[derive(Clone, Debug)]
+pub struct TargetRoles {
+    pub target: String,
+    pub keep: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+/// The part of the requests meant for one extra server
+pub(super) fn target_request_data(data: &RoleSyncRequestData, target: &str) -> RoleSyncRequestData {
+    let users = data
+        .users
+        .iter()
+        .filter_map(|req| {
+            req.targets
+                .iter()
+                .find(|x| x.target == target)
+                .map(|roles| RoleSyncRequest {
+                    account_id: req.account_id,
+                    keep: roles.keep.clone(),
+                    remove: roles.remove.clone(),
+                    targets: Vec::new(),
+                })
+        })
+        .collect();
+
+    RoleSyncRequestData { users }
+}
+
+impl BotState {
+    pub fn main_target(&self) -> &ServerTarget {
+        &self.targets[0]
+    }
+
+    pub fn extra_targets(&self) -> &[ServerTarget] {
+        &self.targets[1..]
+    }
+
+    pub fn has_target(&self, name: &str) -> bool {
+        self.targets.iter().any(|x| x.name == name)
+    }
+
+    /// Last failed sync of every extra server, as (server, timestamp, error). Cleared once a sync to the server succeeds.
+    pub fn target_errors(&self) -> Vec<(String, i64, String)> {
+        let mut errors: Vec<_> = self
+            .target_errors
+            .lock()
+            .iter()
+            .map(|(name, (at, err))| (name.clone(), *at, err.clone()))
+            .collect();
+
+        errors.sort();
+        errors
+    }
+
+    /// Sends the roles for every extra server, each one separately so a failing server doesn't affect the others.
+    /// Requests that failed for a temporary reason are queued in the outbox of the server.
+    /// Returns the accounts that failed to sync to at least one server.
+    pub(super) async fn send_to_extra_targets(
+        &self,
+        data: &RoleSyncRequestData,
+        kind: TargetRequestKind,
+    ) -> HashSet<i32> {
+        let mut failed = HashSet::new();
+
+        for target in self.extra_targets() {
+            let target_data = target_request_data(data, &target.name);
+
+            if target_data.users.is_empty() {
+                continue;
+            }
+
+            match self.post_sync_roles_req(target, &target_data).await {
+                Ok(()) => {
+                    self.target_errors.lock().remove(&target.name);
+
+                    // anything still queued for these users is outdated now
+                    if kind == TargetRequestKind::Full {
+                        if let Err(err) = self.clear_outbox_for(&target.name, &target_data).await {
+                            warn!("Failed to clear outdated outbox entries: {err}");
+                        }
+                    }
+                }
+
+                Err(err) => {
+                    warn!(
+                        "Failed to sync {} users to server '{}': {err}",
+                        target_data.users.len(),
+                        target.name
+                    );
+
+                    failed.extend(target_data.users.iter().map(|x| x.account_id));
+
+                    if err.is_retryable() {
+                        let queued = match kind {
+                            TargetRequestKind::Full => {
+                                self.enqueue_failed_sync(&target.name, &target_data, &err)
+                                    .await
+                            }
+                            TargetRequestKind::Strip => {
+                                self.enqueue_failed_strip(&target.name, &target_data, &err)
+                                    .await
+                            }
+                        };
+
+                        if let Err(db_err) = queued {
+                            warn!(
+                                "Failed to queue role sync to server '{}' for a retry: {db_err}",
+                                target.name
+                            );
+                        }
+                    }
+
+                    self.target_errors
+                        .lock()
+                        .insert(target.name.clone(), (now_timestamp(), err.to_string()));
+                }
+            }
+        }
+
+        failed
+    }
+
+    // makes the next sync of these accounts send their roles again, even if nothing changed
+    pub(super) async fn forget_synced(
+        &self,
+        account_ids: &HashSet<i32>,
+    ) -> Result<(), sqlx::Error> {
+        let ids: Vec<i32> = account_ids.iter().copied().collect();
+        let ids = serde_json::to_string(&ids).unwrap_or_default();
+
+        sqlx::query!(
+            "DELETE FROM synced_roles WHERE gd_account_id IN (SELECT value FROM json_each(?))",
+            ids
+        )
+        .execute(&self.database)
+        .await?;
+
+        Ok(())
+    }
+}
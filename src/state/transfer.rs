@@ -0,0 +1,159 @@
+use std::fmt::Display;
+
+use serenity::all::{Member, UserId};
+
+use super::{BotState, LinkActor, RoleSyncError, SyncOptions};
+use crate::{db::LinkedUser, serenity};
+
+pub enum TransferError {
+    Database(sqlx::Error),
+    NotLinked,
+    AlreadyLinked,
+    SameUser,
+    // the account was transferred, but syncing the roles of the new user failed
+    RoleSync(RoleSyncError, LinkedUser),
+}
+
+impl From<sqlx::Error> for TransferError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Database(value)
+    }
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "Database error: {e}"),
+            Self::NotLinked => f.write_str("The user is not linked to a GD account"),
+            Self::AlreadyLinked => f.write_str("The new user is already linked to a GD account"),
+            Self::SameUser => f.write_str("Can't transfer an account to the same user"),
+            Self::RoleSync(e, _) => write!(f, "Failed to sync roles: {e}"),
+        }
+    }
+}
+
+impl BotState {
+    /// Moves the GD accounts linked to `from` over to `to`, along with their grants and overrides, then syncs
+    /// the roles of `to`. The roles of `from` don't need to be removed, the sync replaces them.
+    pub async fn transfer_link(
+        &self,
+        http: &serenity::Http,
+        from: UserId,
+        to: &Member,
+        actor: LinkActor,
+    ) -> Result<(LinkedUser, Vec<String>), TransferError> {
+        if from == to.user.id {
+            return Err(TransferError::SameUser);
+        }
+
+        let from_id = from.get() as i64;
+        let to_id = to.user.id.get() as i64;
+
+        let mut tx = self.database.begin().await?;
+
+        let Some(mut linked_user) = sqlx::query_as!(
+            LinkedUser,
+            "SELECT * FROM linked_users WHERE id = ?",
+            from_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Err(TransferError::NotLinked);
+        };
+
+        let target_linked = sqlx::query_scalar!("SELECT id FROM linked_users WHERE id = ?", to_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        if target_linked.is_some() {
+            return Err(TransferError::AlreadyLinked);
+        }
+
+        // the link and the rows referencing it change one after another, so they are only checked on commit
+        sqlx::query("PRAGMA defer_foreign_keys = ON")
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!(
+            "UPDATE linked_users SET id = ? WHERE id = ?",
+            to_id,
+            from_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE linked_alts SET user_id = ? WHERE user_id = ?",
+            to_id,
+            from_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE role_grants SET user_id = ? WHERE user_id = ?",
+            to_id,
+            from_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE role_overrides SET user_id = ? WHERE user_id = ?",
+            to_id,
+            from_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // these describe the old discord account, the new one gets its own during the sync below
+        sqlx::query!("DELETE FROM linked_user_guilds WHERE user_id = ?", from_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM missing_members WHERE id = ?", from_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM departed_members WHERE id = ?", from_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let alt_ids = sqlx::query_scalar!(
+            "SELECT gd_account_id FROM linked_alts WHERE user_id = ?",
+            to_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for account_id in std::iter::once(linked_user.gd_account_id).chain(alt_ids) {
+            self.record_transfer_event(&mut *tx, actor, from_id, to_id, account_id)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        linked_user.id = to_id;
+
+        {
+            let mut grants = self.role_grants.write();
+            if let Some(x) = grants.remove(&from_id) {
+                grants.insert(to_id, x);
+            }
+        }
+
+        {
+            let mut overrides = self.role_overrides.write();
+            if let Some(x) = overrides.remove(&from_id) {
+                overrides.insert(to_id, x);
+            }
+        }
+
+        // the server may still have the roles of the old user, so the sync can't be skipped
+        match self.sync_roles(http, to, SyncOptions { force: true }).await {
+            Ok(roles) => Ok((linked_user, roles)),
+            Err(e) => Err(TransferError::RoleSync(e, linked_user)),
+        }
+    }
+}
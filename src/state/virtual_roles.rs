@@ -0,0 +1,167 @@
+use std::{collections::HashMap, fmt::Display};
+
+use log::warn;
+use serenity::all::Member;
+
+use super::{now_timestamp, BotState};
+use crate::{db::VirtualRole, serenity};
+
+/// Member state that can grant a Globed role, without being a regular discord role
+#[derive(Clone, Copy, PartialEq, Eq, Debug, poise::ChoiceParameter)]
+pub enum VirtualSource {
+    #[name = "Server booster"]
+    Booster,
+    #[name = "Pending membership screening"]
+    Pending,
+    #[name = "Timed out"]
+    TimedOut,
+}
+
+impl VirtualSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Booster => "booster",
+            Self::Pending => "pending",
+            Self::TimedOut => "timed_out",
+        }
+    }
+
+    fn from_db(source: &str) -> Option<Self> {
+        match source {
+            "booster" => Some(Self::Booster),
+            "pending" => Some(Self::Pending),
+            "timed_out" => Some(Self::TimedOut),
+            _ => None,
+        }
+    }
+}
+
+impl Display for VirtualRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match VirtualSource::from_db(&self.source) {
+            Some(VirtualSource::Booster) if self.min_boost_days > 0 => {
+                write!(f, "boosting for {}+ days", self.min_boost_days)
+            }
+            Some(VirtualSource::Booster) => f.write_str("boosting"),
+            Some(VirtualSource::Pending) => f.write_str("pending membership screening"),
+            Some(VirtualSource::TimedOut) => f.write_str("timed out"),
+            None => write!(f, "unknown ({})", self.source),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) struct VirtualCondition {
+    source: VirtualSource,
+    min_boost_days: i64,
+}
+
+impl VirtualCondition {
+    /// Whether the member currently matches the condition. Boost durations and timeouts run out
+    /// without any event, so those are only picked up by the next full sync.
+    pub fn eval(&self, member: &Member) -> bool {
+        match self.source {
+            VirtualSource::Booster => member.premium_since.is_some_and(|since| {
+                now_timestamp() - since.unix_timestamp() >= self.min_boost_days * 24 * 60 * 60
+            }),
+            VirtualSource::Pending => member.pending,
+            VirtualSource::TimedOut => member
+                .communication_disabled_until
+                .is_some_and(|until| until.unix_timestamp() > now_timestamp()),
+        }
+    }
+}
+
+impl BotState {
+    pub async fn get_all_virtual_roles(&self) -> Result<Vec<VirtualRole>, sqlx::Error> {
+        sqlx::query_as!(
+            VirtualRole,
+            "SELECT * FROM virtual_roles ORDER BY target, id"
+        )
+        .fetch_all(&self.database)
+        .await
+    }
+
+    pub async fn add_virtual_role(
+        &self,
+        target: &str,
+        globed_role_id: &str,
+        source: VirtualSource,
+        min_boost_days: u32,
+    ) -> Result<(), sqlx::Error> {
+        let source = source.as_str();
+
+        sqlx::query!(
+            "INSERT INTO virtual_roles (id, target, source, min_boost_days) VALUES (?, ?, ?, ?)",
+            globed_role_id,
+            target,
+            source,
+            min_boost_days
+        )
+        .execute(&self.database)
+        .await?;
+
+        self.invalidate_synced_roles().await?;
+        self.reload_virtual_roles().await
+    }
+
+    /// Returns whether a virtual role with this id existed
+    pub async fn remove_virtual_role(
+        &self,
+        target: &str,
+        globed_role_id: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let affected = sqlx::query!(
+            "DELETE FROM virtual_roles WHERE target = ? AND id = ?",
+            target,
+            globed_role_id
+        )
+        .execute(&self.database)
+        .await?
+        .rows_affected();
+
+        if affected == 0 {
+            return Ok(false);
+        }
+
+        self.invalidate_synced_roles().await?;
+        self.reload_virtual_roles().await?;
+
+        Ok(true)
+    }
+
+    // caches all virtual roles, so syncing doesn't have to query them
+    pub(super) async fn reload_virtual_roles(&self) -> Result<(), sqlx::Error> {
+        let mut roles: HashMap<String, Vec<(String, VirtualCondition)>> = HashMap::new();
+
+        for role in self.get_all_virtual_roles().await? {
+            match VirtualSource::from_db(&role.source) {
+                Some(source) => roles.entry(role.target).or_default().push((
+                    role.id,
+                    VirtualCondition {
+                        source,
+                        min_boost_days: role.min_boost_days,
+                    },
+                )),
+                None => {
+                    warn!(
+                        "Ignoring virtual role {} with unknown source '{}'",
+                        role.id, role.source
+                    );
+                }
+            }
+        }
+
+        *self.virtual_roles.write() = roles;
+
+        Ok(())
+    }
+
+    /// Whether a change between the two states of a member can affect virtual roles
+    pub fn virtual_state_changed(&self, old: &Member, new: &Member) -> bool {
+        !self.virtual_roles.read().is_empty()
+            && (old.premium_since != new.premium_since
+                || old.pending != new.pending
+                || old.communication_disabled_until != new.communication_disabled_until)
+    }
+}